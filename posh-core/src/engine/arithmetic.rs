@@ -0,0 +1,864 @@
+use std::fmt;
+use std::io::Write;
+
+use super::{Engine, ExitStatus};
+use crate::{Error, Result};
+
+/// How deep variables may refer to other variables, since their values are
+/// evaluated as expressions of their own.
+const MAX_DEPTH: usize = 64;
+
+/// Where the variables of an expression are read from and assigned to.
+pub trait Scope {
+    fn get(&self, name: &str) -> Option<String>;
+    fn set(&mut self, name: &str, value: String) -> Result<()>;
+}
+
+impl<W: Write> Scope for Engine<W> {
+    fn get(&self, name: &str) -> Option<String> {
+        self.get_param(name)
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<()> {
+        self.set_var(name, value)
+    }
+}
+
+/// The scope of `math`, which only knows of a few constants. Shell variables
+/// are used through parameter expansion instead.
+struct Constants;
+
+impl Scope for Constants {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "pi" => Some(std::f64::consts::PI.to_string()),
+            "tau" => Some(std::f64::consts::TAU.to_string()),
+            "e" => Some(std::f64::consts::E.to_string()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, name: &str, _: String) -> Result<()> {
+        Err(Error::Arithmetic(format!("cannot assign to '{name}'")))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn as_float(self) -> f64 {
+        match self {
+            Self::Int(n) => n as f64,
+            Self::Float(n) => n,
+        }
+    }
+
+    fn is_true(self) -> bool {
+        self.as_float() != 0.0
+    }
+}
+
+impl From<bool> for Number {
+    fn from(b: bool) -> Self {
+        Self::Int(b as i64)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Float(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+/// Evaluates `expression` with integers, like `$((...))`. Variables that are
+/// unset or empty count as zero.
+pub fn evaluate(expression: &str, scope: &mut impl Scope) -> Result<i64> {
+    let mut evaluator = Evaluator {
+        scope,
+        float: false,
+        depth: 0,
+    };
+
+    match evaluator.evaluate(expression)? {
+        Number::Int(n) => Ok(n),
+        Number::Float(_) => unreachable!("integer expressions never contain floats"),
+    }
+}
+
+/// Evaluates `expression` with floats, like `math`, where `^` is exponentiation
+/// and functions like `sqrt(x)` are available.
+pub fn math(expression: &str) -> Result<f64> {
+    let mut evaluator = Evaluator {
+        scope: &mut Constants,
+        float: true,
+        depth: 0,
+    };
+
+    evaluator.evaluate(expression).map(Number::as_float)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Number),
+    Name(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Sorted so that the longest operators are tried first.
+const OPERATORS: [&str; 36] = [
+    "<<=", ">>=", "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=", "/=",
+    "%=", "&=", "^=", "|=", "++", "--", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "?", ":", "=",
+];
+
+fn tokenize(s: &str, float: bool) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() || (float && c == '.') {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..len], float)?));
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+
+            // Like in fish, `x` can be used for multiplication, since `*` is a glob.
+            match &rest[..len] {
+                "x" if float => tokens.push(Token::Op("*")),
+                name => tokens.push(Token::Name(name.to_string())),
+            }
+            len
+        } else if let Some(op) = OPERATORS.into_iter().find(|op| rest.starts_with(op)) {
+            // `++` and `--` only increment or decrement a variable right next
+            // to them, and are otherwise two signs, like in `1--1`.
+            let after_name = matches!(tokens.last(), Some(Token::Name(_)));
+            let before_name = rest[op.len()..]
+                .trim_start()
+                .starts_with(|c: char| c.is_alphabetic() || c == '_');
+
+            let op = match op {
+                "++" | "--" if !after_name && !before_name => &op[..1],
+                op => op,
+            };
+            tokens.push(Token::Op(op));
+            op.len()
+        } else {
+            tokens.push(match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                ',' => Token::Comma,
+                c => return Err(Error::Arithmetic(format!("unexpected character '{c}'"))),
+            });
+            1
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a decimal, hexadecimal (`0x1f`) or octal (`017`) integer, or a
+/// float if `float` is set.
+fn parse_number(s: &str, float: bool) -> Result<Number> {
+    let invalid = || Error::Arithmetic(format!("invalid number '{s}'"));
+
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"));
+
+    let n = match hex {
+        Some(hex) => i64::from_str_radix(hex, 16).map(Number::Int).ok(),
+        None if float => s.parse().map(Number::Float).ok(),
+        None if s.len() > 1 && s.starts_with('0') => {
+            i64::from_str_radix(&s[1..], 8).map(Number::Int).ok()
+        }
+        None => s.parse().map(Number::Int).ok(),
+    };
+
+    n.ok_or_else(invalid)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(Number),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+
+    /// `name = value`, or `name op= value` if there is an operator.
+    Assign(String, Option<&'static str>, Box<Expr>),
+
+    /// `name++` or `name--`, whose value is that from before `op` was applied.
+    Postfix(String, &'static str),
+
+    /// `condition ? then : otherwise`
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+
+    Call(String, Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    float: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => Err(unexpected(token.as_ref())),
+        }
+    }
+
+    /// The precedence of a binary operator, and whether it is right associative.
+    fn precedence(&self, op: &str) -> Option<(u8, bool)> {
+        let precedence = match op {
+            "=" | "+=" | "-=" | "*=" | "/=" | "%=" | "<<=" | ">>=" | "&=" | "^=" | "|=" => {
+                (1, true)
+            }
+            "?" => (2, true),
+            "||" => (3, false),
+            "&&" => (4, false),
+            "|" => (5, false),
+            "^" if self.float => (14, true),
+            "^" => (6, false),
+            "&" => (7, false),
+            "==" | "!=" => (8, false),
+            "<" | "<=" | ">" | ">=" => (9, false),
+            "<<" | ">>" => (10, false),
+            "+" | "-" => (11, false),
+            "*" | "/" | "%" => (12, false),
+            "**" => (14, true),
+            _ => return None,
+        };
+
+        Some(precedence)
+    }
+
+    /// Parses expressions separated by commas, which are all evaluated, with
+    /// the value of the last one being the value of them all.
+    fn parse_sequence(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_expr(0)?;
+
+        while self.peek() == Some(&Token::Comma) {
+            self.next();
+            let rhs = self.parse_expr(0)?;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(rhs));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.parse_operand()?;
+
+        while let Some(&Token::Op(op)) = self.peek() {
+            let Some((precedence, right)) = self.precedence(op) else {
+                break;
+            };
+
+            if precedence < min_precedence {
+                break;
+            }
+
+            self.next();
+            let next_precedence = if right { precedence } else { precedence + 1 };
+
+            lhs = match op {
+                "?" => {
+                    let then = self.parse_sequence()?;
+                    self.expect(Token::Op(":"))?;
+                    let otherwise = self.parse_expr(next_precedence)?;
+                    Expr::Conditional(Box::new(lhs), Box::new(then), Box::new(otherwise))
+                }
+
+                _ if precedence == 1 => {
+                    let Expr::Var(name) = lhs else {
+                        return Err(Error::Arithmetic(format!(
+                            "cannot assign with '{op}' to something that is not a variable"
+                        )));
+                    };
+
+                    let op = op.strip_suffix('=').filter(|op| !op.is_empty());
+                    let op = OPERATORS.into_iter().find(|o| Some(*o) == op);
+                    let rhs = self.parse_expr(next_precedence)?;
+                    Expr::Assign(name, op, Box::new(rhs))
+                }
+
+                "^" if self.float => {
+                    let rhs = self.parse_expr(next_precedence)?;
+                    Expr::Binary("**", Box::new(lhs), Box::new(rhs))
+                }
+
+                _ => {
+                    let rhs = self.parse_expr(next_precedence)?;
+                    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+                }
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+
+            Some(Token::Name(name)) if self.float && self.peek() == Some(&Token::LParen) => {
+                self.next();
+
+                let mut args = Vec::new();
+                if self.peek() != Some(&Token::RParen) {
+                    args.push(self.parse_expr(0)?);
+                    while self.peek() == Some(&Token::Comma) {
+                        self.next();
+                        args.push(self.parse_expr(0)?);
+                    }
+                }
+
+                self.expect(Token::RParen)?;
+                Ok(Expr::Call(name, args))
+            }
+
+            Some(Token::Name(name)) => match self.peek() {
+                Some(&Token::Op(op @ ("++" | "--"))) => {
+                    self.next();
+                    Ok(Expr::Postfix(name, &op[..1]))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+
+            // `++name` and `--name` are the same as `name += 1` and `name -= 1`
+            Some(Token::Op(op @ ("++" | "--"))) => match self.next() {
+                Some(Token::Name(name)) => Ok(Expr::Assign(
+                    name,
+                    Some(&op[..1]),
+                    Box::new(Expr::Number(Number::Int(1))),
+                )),
+                token => Err(unexpected(token.as_ref())),
+            },
+
+            Some(Token::LParen) => {
+                let expr = self.parse_sequence()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+
+            Some(Token::Op(op @ ("+" | "-" | "!" | "~"))) => {
+                Ok(Expr::Unary(op, Box::new(self.parse_expr(13)?)))
+            }
+
+            token => Err(unexpected(token.as_ref())),
+        }
+    }
+}
+
+fn unexpected(token: Option<&Token>) -> Error {
+    let message = match token {
+        Some(Token::Number(n)) => format!("unexpected number '{n}'"),
+        Some(Token::Name(name)) => format!("unexpected name '{name}'"),
+        Some(Token::Op(op)) => format!("unexpected '{op}'"),
+        Some(Token::LParen) => "unexpected '('".to_string(),
+        Some(Token::RParen) => "unexpected ')'".to_string(),
+        Some(Token::Comma) => "unexpected ','".to_string(),
+        None => "unexpected end of expression".to_string(),
+    };
+
+    Error::Arithmetic(message)
+}
+
+struct Evaluator<'a, S: Scope> {
+    scope: &'a mut S,
+    float: bool,
+    depth: usize,
+}
+
+impl<S: Scope> Evaluator<'_, S> {
+    fn evaluate(&mut self, expression: &str) -> Result<Number> {
+        let mut parser = Parser {
+            tokens: tokenize(expression, self.float)?,
+            pos: 0,
+            float: self.float,
+        };
+
+        // An empty expression is zero, like `$(())`
+        if parser.tokens.is_empty() {
+            return Ok(Number::Int(0));
+        }
+
+        let expr = parser.parse_sequence()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(unexpected(parser.peek()));
+        }
+
+        self.eval(&expr)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Number> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+
+            Expr::Var(name) => self.var(name),
+
+            Expr::Unary(op, expr) => {
+                let n = self.eval(expr)?;
+                match (*op, n) {
+                    ("+", n) => Ok(n),
+                    ("-", Number::Int(n)) => Ok(Number::Int(n.wrapping_neg())),
+                    ("-", Number::Float(n)) => Ok(Number::Float(-n)),
+                    ("!", n) => Ok((!n.is_true()).into()),
+                    ("~", Number::Int(n)) => Ok(Number::Int(!n)),
+                    (op, _) => Err(Error::Arithmetic(format!("'{op}' needs integers"))),
+                }
+            }
+
+            Expr::Binary("&&", lhs, rhs) => {
+                Ok((self.eval(lhs)?.is_true() && self.eval(rhs)?.is_true()).into())
+            }
+
+            Expr::Binary("||", lhs, rhs) => {
+                Ok((self.eval(lhs)?.is_true() || self.eval(rhs)?.is_true()).into())
+            }
+
+            Expr::Binary(",", lhs, rhs) => {
+                self.eval(lhs)?;
+                self.eval(rhs)
+            }
+
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                apply(op, lhs, rhs)
+            }
+
+            Expr::Assign(name, op, rhs) => {
+                let rhs = self.eval(rhs)?;
+                let value = match op {
+                    Some(op) => apply(op, self.var(name)?, rhs)?,
+                    None => rhs,
+                };
+
+                self.scope.set(name, value.to_string())?;
+                Ok(value)
+            }
+
+            Expr::Postfix(name, op) => {
+                let value = self.var(name)?;
+                self.scope
+                    .set(name, apply(op, value, Number::Int(1))?.to_string())?;
+                Ok(value)
+            }
+
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)?.is_true() {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg).map(Number::as_float))
+                    .collect::<Result<Vec<_>>>()?;
+                call(name, &args).map(Number::Float)
+            }
+        }
+    }
+
+    /// The value of a variable, which may itself be an expression.
+    fn var(&mut self, name: &str) -> Result<Number> {
+        let Some(value) = self.scope.get(name) else {
+            return if self.float {
+                Err(Error::Arithmetic(format!("unknown variable '{name}'")))
+            } else {
+                Ok(Number::Int(0))
+            };
+        };
+
+        // A value that is just an integer is read as one, since the smallest
+        // one would otherwise be the negation of a number that is too large
+        if let (false, Ok(n)) = (self.float, value.trim().parse::<i64>()) {
+            return Ok(Number::Int(n));
+        }
+
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::Arithmetic(format!(
+                "'{name}' refers to itself too many times"
+            )));
+        }
+
+        self.depth += 1;
+        let value = self.evaluate(&value);
+        self.depth -= 1;
+        value
+    }
+}
+
+/// Applies the binary operator `op`. Like in other shells, integers wrap around
+/// when they overflow, even for `**`, but shifting by a negative number or by
+/// at least the 64 bits of an integer is an error.
+fn apply(op: &str, lhs: Number, rhs: Number) -> Result<Number> {
+    use Number::{Float, Int};
+
+    let needs_integers = || Error::Arithmetic(format!("'{op}' needs integers"));
+    let division_by_zero = || Error::Arithmetic("division by zero".to_string());
+
+    let (a, b) = match (lhs, rhs) {
+        (Int(a), Int(b)) => (a, b),
+
+        (lhs, rhs) => {
+            let (a, b) = (lhs.as_float(), rhs.as_float());
+            return Ok(match op {
+                "+" => Float(a + b),
+                "-" => Float(a - b),
+                "*" => Float(a * b),
+                "/" => Float(a / b),
+                "%" => Float(a % b),
+                "**" => Float(a.powf(b)),
+                "<" => (a < b).into(),
+                "<=" => (a <= b).into(),
+                ">" => (a > b).into(),
+                ">=" => (a >= b).into(),
+                "==" => (a == b).into(),
+                "!=" => (a != b).into(),
+                _ => return Err(needs_integers()),
+            });
+        }
+    };
+
+    let n = match op {
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" if b == 0 => return Err(division_by_zero()),
+        "/" => a.wrapping_div(b),
+        "%" if b == 0 => return Err(division_by_zero()),
+        "%" => a.wrapping_rem(b),
+        "**" if b < 0 => {
+            return Err(Error::Arithmetic("negative exponent".to_string()));
+        }
+        "**" => wrapping_pow(a, b),
+        "<<" | ">>" if !(0..64).contains(&b) => {
+            return Err(Error::Arithmetic(format!(
+                "cannot shift by {b}, which is not from 0 to 63"
+            )));
+        }
+        "<<" => a << b,
+        ">>" => a >> b,
+        "&" => a & b,
+        "|" => a | b,
+        "^" => a ^ b,
+        "<" => (a < b) as i64,
+        "<=" => (a <= b) as i64,
+        ">" => (a > b) as i64,
+        ">=" => (a >= b) as i64,
+        "==" => (a == b) as i64,
+        "!=" => (a != b) as i64,
+        op => unreachable!("'{op}' is not a binary operator"),
+    };
+
+    Ok(Int(n))
+}
+
+/// `base` to the power of `exponent`, wrapping around like multiplication does.
+fn wrapping_pow(mut base: i64, mut exponent: i64) -> i64 {
+    let mut n = 1_i64;
+
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            n = n.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+
+    n
+}
+
+fn call(name: &str, args: &[f64]) -> Result<f64> {
+    let f: fn(f64) -> f64 = match (name, args) {
+        ("pow", &[a, b]) => return Ok(a.powf(b)),
+        ("min", [_, ..]) => return Ok(args.iter().copied().fold(f64::INFINITY, f64::min)),
+        ("max", [_, ..]) => return Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+
+        ("abs", [_]) => f64::abs,
+        ("ceil", [_]) => f64::ceil,
+        ("floor", [_]) => f64::floor,
+        ("round", [_]) => f64::round,
+        ("sqrt", [_]) => f64::sqrt,
+        ("exp", [_]) => f64::exp,
+        ("ln", [_]) => f64::ln,
+        ("log", [_]) => f64::log10,
+        ("log2", [_]) => f64::log2,
+        ("sin", [_]) => f64::sin,
+        ("cos", [_]) => f64::cos,
+        ("tan", [_]) => f64::tan,
+        ("asin", [_]) => f64::asin,
+        ("acos", [_]) => f64::acos,
+        ("atan", [_]) => f64::atan,
+
+        (name, args) => {
+            return Err(Error::Arithmetic(format!(
+                "unknown function '{name}' with {} arguments",
+                args.len()
+            )))
+        }
+    };
+
+    Ok(f(args[0]))
+}
+
+/// The most decimals that `math` prints, beyond which an `f64` has no precision left.
+const MAX_SCALE: usize = 17;
+
+/// Formats the result of `math` with at most `scale` decimals, without any
+/// trailing zeros.
+fn format_float(n: f64, scale: usize) -> String {
+    if scale == 0 {
+        return format!("{}", n.trunc());
+    }
+
+    format!("{n:.scale$}")
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+impl<W: Write> Engine<W> {
+    /// Runs `math`, which evaluates its arguments as one expression and prints
+    /// the result. `-s N` or `--scale=N` sets the number of decimals.
+    pub(super) fn math(&mut self, args: &[String]) -> Result<ExitStatus> {
+        let (scale, args) = match args {
+            [flag] if flag == "-s" || flag == "--scale" => {
+                writeln!(self.writer, "math: expected a scale after '{flag}'")?;
+                return Ok(ExitStatus::from(2));
+            }
+            [flag, scale, rest @ ..] if flag == "-s" || flag == "--scale" => {
+                (Some(scale.as_str()), rest)
+            }
+            [flag, rest @ ..] if flag.len() > 2 && flag.starts_with("-s") => {
+                (Some(&flag[2..]), rest)
+            }
+            [flag, rest @ ..] if flag.starts_with("--scale=") => (Some(&flag[8..]), rest),
+            _ => (None, args),
+        };
+
+        let scale = match scale.map(|s| s.parse::<usize>()) {
+            Some(Ok(scale)) if scale > MAX_SCALE => Err(Error::Arithmetic(format!(
+                "scale {scale} is more than {MAX_SCALE} decimals"
+            ))),
+            Some(Ok(scale)) => Ok(scale),
+            Some(Err(_)) => {
+                writeln!(self.writer, "math: invalid scale")?;
+                return Ok(ExitStatus::from(2));
+            }
+            None => Ok(6),
+        };
+
+        if args.is_empty() {
+            writeln!(self.writer, "math: expected an expression")?;
+            return Ok(ExitStatus::from(2));
+        }
+
+        match scale.and_then(|scale| Ok((math(&args.join(" "))?, scale))) {
+            Ok((n, scale)) if n.is_finite() => {
+                writeln!(self.writer, "{}", format_float(n, scale))?;
+                Ok(ExitStatus::from(0))
+            }
+
+            Ok(_) => {
+                writeln!(self.writer, "math: result is not a finite number")?;
+                Ok(ExitStatus::from(1))
+            }
+
+            Err(Error::Arithmetic(message)) => {
+                writeln!(self.writer, "math: {message}")?;
+                Ok(ExitStatus::from(1))
+            }
+
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    impl Scope for HashMap<String, String> {
+        fn get(&self, name: &str) -> Option<String> {
+            HashMap::get(self, name).cloned()
+        }
+
+        fn set(&mut self, name: &str, value: String) -> Result<()> {
+            self.insert(name.to_string(), value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        let mut vars = HashMap::new();
+        let mut eval = |s: &str| evaluate(s, &mut vars).unwrap();
+
+        assert_eq!(7, eval("1 + 2 * 3"));
+        assert_eq!(9, eval("(1 + 2) * 3"));
+        assert_eq!(-1, eval("7 / -4"));
+        assert_eq!(3, eval("-7 % 4 + 6"));
+        assert_eq!(1024, eval("2 ** 10"));
+        assert_eq!(-4, eval("-2 ** 2"));
+        assert_eq!(1, eval("3 > 2 && 2 >= 2 || 0"));
+        assert_eq!(0, eval("!5"));
+        assert_eq!(14, eval("0x1f & 016"));
+        assert_eq!(12, eval("3 << 2 | 4 ^ 4"));
+        assert_eq!(20, eval("1 ? 2 ? 20 : 30 : 40"));
+        assert_eq!(0, eval(""));
+
+        // Integers wrap around when they overflow
+        assert_eq!(i64::MIN, eval("9223372036854775807 + 1"));
+        assert_eq!(0, eval("2 ** 64"));
+        assert_eq!(-6187776510509375487, eval("3 ** 100000000000"));
+        assert_eq!(i64::MIN, eval("1 << 63"));
+        assert_eq!(-1, eval("-8 >> 63"));
+    }
+
+    #[test]
+    fn variables_and_assignments() {
+        let mut vars = HashMap::new();
+        vars.insert("x".to_string(), "4".to_string());
+        vars.insert("y".to_string(), "x * 2".to_string());
+
+        assert_eq!(13, evaluate("x + y + z + 1", &mut vars).unwrap());
+        assert_eq!(6, evaluate("x += 2", &mut vars).unwrap());
+        assert_eq!(7, evaluate("z = x + 1", &mut vars).unwrap());
+        assert_eq!(Some("6"), vars.get("x").map(String::as_str));
+        assert_eq!(Some("7"), vars.get("z").map(String::as_str));
+
+        vars.insert("a".to_string(), "a + 1".to_string());
+        assert!(evaluate("a", &mut vars).is_err());
+
+        assert_eq!(6, evaluate("x++", &mut vars).unwrap());
+        assert_eq!(8, evaluate("++x", &mut vars).unwrap());
+        assert_eq!(8, evaluate("x--", &mut vars).unwrap());
+        assert_eq!(6, evaluate("--x", &mut vars).unwrap());
+        assert_eq!(12, evaluate("x=5, x++ + ++x", &mut vars).unwrap());
+        assert_eq!(10, evaluate("x=1, y=x--, y*10+x", &mut vars).unwrap());
+        assert_eq!(2, evaluate("(x=1, x+1)", &mut vars).unwrap());
+        assert_eq!(2, evaluate("1--1", &mut vars).unwrap());
+        assert_eq!(2, evaluate("1++1", &mut vars).unwrap());
+        assert_eq!(Some("1"), vars.get("x").map(String::as_str));
+
+        // The smallest integer can be read back from a variable
+        let min = evaluate("m = -9223372036854775807 - 1", &mut vars).unwrap();
+        assert_eq!(i64::MIN, min);
+        assert_eq!(i64::MIN, evaluate("m / -1", &mut vars).unwrap());
+        assert_eq!(i64::MAX, evaluate("m - 1", &mut vars).unwrap());
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        let mut vars = HashMap::new();
+        let mut error = |s: &str| match evaluate(s, &mut vars) {
+            Err(Error::Arithmetic(message)) => message,
+            result => panic!("expected an error, got {result:?}"),
+        };
+
+        assert_eq!("division by zero", error("1 / 0"));
+        assert_eq!("unexpected end of expression", error("1 +"));
+        assert_eq!("unexpected ')'", error("1 + 2)"));
+        assert_eq!("unexpected number '1'", error("x++ 1"));
+        assert_eq!("unexpected ','", error("1 +, 2"));
+        assert_eq!(
+            "cannot shift by 70, which is not from 0 to 63",
+            error("1 << 70")
+        );
+        assert_eq!(
+            "cannot shift by -1, which is not from 0 to 63",
+            error("1 >> -1")
+        );
+        assert_eq!("invalid number '1.5'", error("1.5 * 2"));
+        assert_eq!("invalid number '09'", error("09"));
+    }
+
+    #[test]
+    fn arithmetic_expansion() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "2 6 4 4\n",
+            engine.output("i=1; i=$((i + 1)); echo $i $(($i * 3)) \"$((i += 2))\" $i")
+        );
+        assert_eq!("2.5\n3\n", engine.output("math 5 / 2; math -s0 10 / 3"));
+        assert!(engine.execute_line("echo $((1 / 0))").is_err());
+        assert_eq!(
+            "0 1 2 3\n",
+            engine.output("i=0; while [ $i -lt 3 ]; do printf '%s ' $((i++)); done; echo $i")
+        );
+        assert_eq!("1 2\n", engine.output("echo $((a = 1, b = 2, a)) $b"));
+    }
+
+    #[test]
+    fn math_scale() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!("0.33333333333333331\n", engine.output("math -s17 1/3"));
+        assert_eq!("0.333\n", engine.output("math --scale=3 1/3"));
+        assert_eq!(
+            "math: scale 999999999999 is more than 17 decimals\n1\n",
+            engine.output("math -s999999999999 1/3; echo $?")
+        );
+        assert_eq!(
+            "math: expected a scale after '-s'\n2\n",
+            engine.output("math -s; echo $?")
+        );
+        assert_eq!(
+            "math: invalid scale\n2\n",
+            engine.output("math -s x 1; echo $?")
+        );
+    }
+
+    #[test]
+    fn float_math() {
+        assert_eq!(2.5, math("5 / 2").unwrap());
+        assert_eq!(512.0, math("2 ^ 3 ^ 2").unwrap());
+        assert_eq!(12.0, math("3 x 4").unwrap());
+        assert_eq!(3.0, math("sqrt(9)").unwrap());
+        assert_eq!(2.0, math("max(1, 2, -3)").unwrap());
+        assert_eq!(std::f64::consts::PI, math("pi").unwrap());
+        assert!(math("x").is_err());
+
+        assert_eq!("0.333333", format_float(1.0 / 3.0, 6));
+        assert_eq!("2.5", format_float(2.5, 6));
+        assert_eq!("2", format_float(2.5, 0));
+        assert_eq!("-3", format_float(-3.0, 6));
+    }
+}
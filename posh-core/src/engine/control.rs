@@ -0,0 +1,311 @@
+use std::io::Write;
+
+use super::parser::ast::{
+    expand_pattern, expand_word_joined, expand_words, CaseArm, SyntaxTree, Word,
+};
+use super::{glob, Engine, ExitStatus};
+use crate::Result;
+
+/// A change in control flow that is waiting to take effect, which stops the
+/// commands being run until it reaches whatever it applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Control {
+    /// `break n`, which stops the `n` innermost loops.
+    Break(usize),
+
+    /// `continue n`, which stops the `n - 1` innermost loops and goes on with
+    /// the next iteration of the one around them.
+    Continue(usize),
+
+    /// `return n`, which stops the function being called with the status `n`.
+    Return(i32),
+}
+
+impl<W: Write> Engine<W> {
+    /// Runs `while condition; do body; done`, or `until` if `until` is set.
+    pub(super) fn run_while(
+        &mut self,
+        condition: SyntaxTree,
+        body: SyntaxTree,
+        until: bool,
+    ) -> Result<Vec<ExitStatus>> {
+        self.run_loop(|engine| {
+            let statuses = engine.walk_ast(condition.clone())?;
+            if engine.control.is_some() {
+                return Ok(Some(statuses));
+            }
+
+            if ExitStatus::succeeded(&statuses) == until {
+                return Ok(None);
+            }
+
+            engine.walk_ast(body.clone()).map(Some)
+        })
+    }
+
+    /// Runs `for name in words; do body; done`, or goes over the positional
+    /// parameters if there are no `words`.
+    pub(super) fn run_for(
+        &mut self,
+        name: &str,
+        words: Option<Vec<Word>>,
+        body: SyntaxTree,
+    ) -> Result<Vec<ExitStatus>> {
+        let fields = match words {
+            Some(words) => expand_words(self, words)?,
+            None => self.args[1..].to_vec(),
+        };
+
+        let mut fields = fields.into_iter();
+        self.run_loop(|engine| {
+            let Some(field) = fields.next() else {
+                return Ok(None);
+            };
+
+            engine.set_var(name, field)?;
+            engine.walk_ast(body.clone()).map(Some)
+        })
+    }
+
+    /// Runs `case word in ... esac`, starting with the first arm that has a
+    /// pattern matching `word` and going on for as long as the arms fall
+    /// through. Nothing matching counts as a success.
+    pub(super) fn run_case(&mut self, word: Word, arms: Vec<CaseArm>) -> Result<Vec<ExitStatus>> {
+        let word = expand_word_joined(self, &[], word)?.name;
+
+        let mut statuses = vec![ExitStatus::from(0)];
+        let mut matched = false;
+
+        for arm in arms {
+            if !matched {
+                for pattern in arm.patterns {
+                    if glob::matches(&expand_pattern(self, &[], pattern)?, &word) {
+                        matched = true;
+                        break;
+                    }
+                }
+            }
+
+            if !matched {
+                continue;
+            }
+
+            statuses = self.walk_ast(arm.body)?;
+            if statuses.is_empty() {
+                statuses.push(ExitStatus::from(0));
+            }
+
+            if !arm.fallthrough || self.control.is_some() {
+                break;
+            }
+        }
+
+        Ok(statuses)
+    }
+
+    /// Runs `iteration` until it returns `None` or the loop is broken out of.
+    /// The statuses of the loop are those of the last iteration that ran, or
+    /// a success if there were none.
+    pub(super) fn run_loop(
+        &mut self,
+        mut iteration: impl FnMut(&mut Self) -> Result<Option<Vec<ExitStatus>>>,
+    ) -> Result<Vec<ExitStatus>> {
+        self.loop_depth += 1;
+
+        let mut statuses = vec![ExitStatus::from(0)];
+        let result = loop {
+            match iteration(self) {
+                Ok(Some(s)) => statuses = s,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+
+            if self.should_stop_loop() {
+                break Ok(());
+            }
+        };
+
+        self.loop_depth -= 1;
+        result.map(|()| statuses)
+    }
+
+    /// Handles a `break` or `continue` at the end of an iteration, returning
+    /// whether the loop should stop.
+    fn should_stop_loop(&mut self) -> bool {
+        match self.control {
+            None => false,
+
+            Some(Control::Break(1)) => {
+                self.control = None;
+                true
+            }
+
+            Some(Control::Continue(1)) => {
+                self.control = None;
+                false
+            }
+
+            Some(Control::Break(n)) => {
+                self.control = Some(Control::Break(n - 1));
+                true
+            }
+
+            Some(Control::Continue(n)) => {
+                self.control = Some(Control::Continue(n - 1));
+                true
+            }
+
+            Some(Control::Return(_)) => true,
+        }
+    }
+
+    /// Runs `break` or `continue`, which take the number of enclosing loops
+    /// they apply to. Like in bash, invalid arguments are reported, but the
+    /// innermost loop is still left or continued, so that it cannot run forever.
+    pub(super) fn loop_control(&mut self, builtin: &str, args: &[String]) -> Result<ExitStatus> {
+        let (n, status) = match args {
+            [] => (1, 0),
+
+            [n] => match n.parse::<usize>() {
+                Ok(n) if n > 0 => (n, 0),
+                _ => {
+                    writeln!(self.writer, "{builtin}: '{n}' is not a positive number.")?;
+                    (1, 1)
+                }
+            },
+
+            _ => {
+                writeln!(self.writer, "{builtin}: too many arguments")?;
+                (1, 1)
+            }
+        };
+
+        if self.loop_depth == 0 {
+            writeln!(self.writer, "{builtin}: not inside of a loop")?;
+            return Ok(ExitStatus::from(1));
+        }
+
+        let n = n.min(self.loop_depth);
+        self.control = Some(if builtin == "break" {
+            Control::Break(n)
+        } else {
+            Control::Continue(n)
+        });
+
+        Ok(ExitStatus::from(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loops() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "1\n2\n3\n",
+            engine.output("i=0; while [ $i -lt 3 ]; do i=$((i + 1)); echo $i; done")
+        );
+        assert_eq!(
+            "0\n",
+            engine.output("until true; do echo never; done; echo $?")
+        );
+        assert_eq!(
+            "1\n3\n",
+            engine.output("i=0; while true; do i=$((i + 1)); [ $i = 2 ] && continue; [ $i -gt 3 ] && break; echo $i; done")
+        );
+        assert_eq!(
+            "out\n",
+            engine
+                .output("while true; do while true; do break 2; done; echo never; done; echo out")
+        );
+        assert_eq!(
+            "2\n",
+            engine.output("i=0; until [ $i = 2 ]; do i=$((i + 1)); while true; do continue 2; done; done; echo $i")
+        );
+        assert_eq!(
+            "break: not inside of a loop\n1\n",
+            engine.output("break; echo $?")
+        );
+        assert_eq!(
+            "a1\na2\nb c\nb c\n",
+            engine.output("for x in a{1,2} \"$(echo b c)\"; do echo $x; done; echo $x")
+        );
+        assert_eq!(
+            "d\n",
+            engine.output("set -- d e; for x; do [ $x = e ] && break; echo $x; done")
+        );
+    }
+
+    #[test]
+    fn loop_control() {
+        let mut engine = Engine::with_writer(Vec::new());
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        // `break n` applies to at most as many loops as there are
+        engine.loop_depth = 2;
+        engine.loop_control("break", &args(&["5"])).unwrap();
+        assert_eq!(Some(Control::Break(2)), engine.control);
+
+        assert!(engine.should_stop_loop());
+        assert_eq!(Some(Control::Break(1)), engine.control);
+        assert!(engine.should_stop_loop());
+        assert_eq!(None, engine.control);
+
+        engine.loop_control("continue", &[]).unwrap();
+        assert!(!engine.should_stop_loop());
+        assert_eq!(None, engine.control);
+
+        engine.control = Some(Control::Return(3));
+        assert!(engine.should_stop_loop());
+        assert_eq!(Some(Control::Return(3)), engine.control);
+        engine.control = None;
+
+        // Invalid arguments are errors, but still apply to the innermost loop
+        let status = engine.loop_control("continue", &args(&["0"])).unwrap();
+        assert_eq!(1, status.code);
+        assert_eq!(Some(Control::Continue(1)), engine.control);
+        let status = engine.loop_control("break", &args(&["0"])).unwrap();
+        assert_eq!(1, status.code);
+        assert_eq!(Some(Control::Break(1)), engine.control);
+        let status = engine.loop_control("break", &args(&["1", "2"])).unwrap();
+        assert_eq!(1, status.code);
+        assert_eq!(Some(Control::Break(1)), engine.control);
+        assert_eq!(
+            "continue: '0' is not a positive number.\nbreak: '0' is not a positive number.\nbreak: too many arguments\n",
+            String::from_utf8_lossy(&engine.writer)
+        );
+
+        let mut engine = Engine::with_writer(Vec::new());
+        assert_eq!(
+            "break: 'x' is not a positive number.\n1\n",
+            engine.output("while true; do break x; done; echo $?")
+        );
+    }
+
+    #[test]
+    fn case_conditionals() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "code\n",
+            engine.output("case foo.rs in *.txt) echo text;; *.rs | *.c) echo code;; esac")
+        );
+        assert_eq!(
+            "b\nc\n",
+            engine.output("case b in a) echo a;; b) echo b;& c) echo c;; d) echo d;; esac")
+        );
+        assert_eq!(
+            "glob\n",
+            engine.output("x=*; case abc in \"$x\") echo quoted;; $x) echo glob;; esac")
+        );
+        assert_eq!(
+            "star\nx\n",
+            engine.output(r"case '*' in \*) echo star;; esac; case x in \*) ;; *) echo x;; esac")
+        );
+        assert_eq!("0\n", engine.output("false; case a in b) ;; esac; echo $?"));
+        assert_eq!("1\n", engine.output("case a in a) false;; esac; echo $?"));
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::Write;
+use std::mem;
+
+use super::control::Control;
+use super::parser::ast::Command;
+use super::variables::{is_valid_name, Variable};
+use super::{Engine, ExitStatus};
+use crate::{path, Error, Result};
+
+impl<W: Write> Engine<W> {
+    pub fn has_function(&self, name: impl AsRef<str>) -> bool {
+        self.functions.contains_key(name.as_ref())
+    }
+
+    /// Calls the function that `cmd` names, with the arguments of `cmd` as
+    /// the positional parameters for as long as it runs.
+    pub(super) fn call_function(&mut self, cmd: Command) -> Result<Vec<ExitStatus>> {
+        let body = self.functions[cmd.cmd_name()].clone();
+
+        let mut args = vec![self.args[0].clone()];
+        args.extend(cmd.args());
+        let args = mem::replace(&mut self.args, args);
+
+        // Loops around the call cannot be broken out of from inside of it
+        let loop_depth = mem::take(&mut self.loop_depth);
+        self.locals.push(Vec::new());
+
+        let result = self.walk_ast(body);
+
+        for (name, var) in self.locals.pop().unwrap_or_default().into_iter().rev() {
+            self.replace_var(&name, var);
+        }
+        self.loop_depth = loop_depth;
+        self.args = args;
+
+        let returned = match self.control {
+            Some(Control::Return(code)) => {
+                self.control = None;
+                Some(code)
+            }
+            _ => None,
+        };
+
+        let statuses = result?;
+        Ok(match returned {
+            Some(code) => vec![ExitStatus::from(code)],
+            None if statuses.is_empty() => vec![ExitStatus::from(0)],
+            None => statuses,
+        })
+    }
+
+    /// Replaces the variable `name` no matter if it is read-only, or removes it
+    /// if `var` is `None`. This is how local variables come and go.
+    fn replace_var(&mut self, name: &str, var: Option<Variable>) -> Option<Variable> {
+        let old = self.vars.replace(name, var);
+
+        if name == "PATH" {
+            self.commands = path::get_cmds_in(self.vars.get("PATH").unwrap_or_default());
+        }
+
+        old
+    }
+
+    /// Runs `local`, which makes variables local to the function being called,
+    /// optionally assigning them at the same time. They are still visible to
+    /// the functions it calls, and are restored once it returns.
+    pub(super) fn local(&mut self, args: &[String]) -> Result<ExitStatus> {
+        if self.locals.is_empty() {
+            writeln!(self.writer, "local: not inside of a function")?;
+            return Ok(ExitStatus::from(1));
+        }
+
+        let mut status = ExitStatus::from(0);
+
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !is_valid_name(name) {
+                writeln!(self.writer, "local: '{name}' is not a valid variable name.")?;
+                status = ExitStatus::from(1);
+                continue;
+            }
+
+            let old = self.vars.get_var(name).cloned();
+            if old.as_ref().is_some_and(|var| var.readonly) {
+                writeln!(self.writer, "local: {}", Error::ReadOnly(name.to_string()))?;
+                status = ExitStatus::from(1);
+                continue;
+            }
+
+            // The variable keeps being exported, so that commands still see it
+            let var = value.map(|value| Variable {
+                value: value.to_string(),
+                exported: old.as_ref().is_some_and(|var| var.exported),
+                readonly: false,
+            });
+
+            let frame = self.locals.last().unwrap();
+            if frame.iter().any(|(local, _)| local == name) {
+                self.replace_var(name, var);
+            } else {
+                let old = self.replace_var(name, var);
+                self.locals
+                    .last_mut()
+                    .unwrap()
+                    .push((name.to_string(), old));
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Runs `return`, which stops the function being called with the given
+    /// status, or that of the latest command.
+    pub(super) fn return_from_function(&mut self, args: &[String]) -> Result<ExitStatus> {
+        if self.locals.is_empty() {
+            writeln!(self.writer, "return: not inside of a function")?;
+            return Ok(ExitStatus::from(1));
+        }
+
+        let code = match args {
+            [] => self.last_status,
+
+            [code] => match code.parse::<i32>() {
+                Ok(code) => code,
+                Err(_) => {
+                    writeln!(self.writer, "return: '{code}' is not a number.")?;
+                    2
+                }
+            },
+
+            _ => {
+                writeln!(self.writer, "return: too many arguments")?;
+                2
+            }
+        };
+
+        self.control = Some(Control::Return(code));
+        Ok(ExitStatus::from(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "hi you 2\na\n",
+            engine.output("set -- a; greet() { echo hi $1 $#; }; greet you x; echo $1")
+        );
+        assert_eq!(
+            "hi 0\nhi 0\n",
+            engine.output("function twice\n $1; $1\nend; twice greet")
+        );
+        assert_eq!(
+            "2\n1\n",
+            engine.output("x=1; show() { echo $x; }; f() { local x=2; show; }; f; show")
+        );
+        assert_eq!(
+            "4\n",
+            engine.output("r() { for i in 1 2; do return 4; done; echo no; }; r; echo $?")
+        );
+        assert_eq!(
+            "return: not inside of a function\n",
+            engine.output("return")
+        );
+    }
+
+    #[test]
+    fn locals_are_restored() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "3 4\n1 2\n",
+            engine.output("x=1; export y=2; f() { local x=3 y; y=4; echo $x $y; }; f; echo $x $y")
+        );
+        assert!(engine.vars.get_var("y").unwrap().exported);
+        assert!(engine.locals.is_empty());
+
+        // A variable that did not exist before is removed again
+        assert_eq!(
+            "[new]\n[]\n",
+            engine.output("g() { local z=new; echo \"[$z]\"; }; g; echo \"[$z]\"")
+        );
+        assert_eq!(None, engine.vars.get("z"));
+    }
+
+    #[test]
+    fn local_errors() {
+        let mut engine = Engine::with_writer(Vec::new());
+        engine.locals.push(Vec::new());
+
+        let status = engine.local(&["1x".to_string()]).unwrap();
+        assert_eq!(1, status.code);
+
+        engine.vars.set("RO", "ro").unwrap();
+        engine.vars.set_readonly("RO");
+        let status = engine.local(&["RO=x".to_string()]).unwrap();
+        assert_eq!(1, status.code);
+        assert_eq!(Some("ro"), engine.vars.get("RO"));
+
+        assert_eq!(
+            "local: '1x' is not a valid variable name.\nlocal: 'RO' is read-only\n",
+            String::from_utf8_lossy(&engine.writer)
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use std::fs;
+
+/// Whether `s` contains any unescaped characters that make it a pattern.
+pub fn is_pattern(s: &str) -> bool {
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if match_bracket(&chars, i, '\0').is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+
+    false
+}
+
+/// Whether all of `s` matches `pattern`, where `*` matches any number of
+/// characters, `?` matches any single character, and `[...]` matches any of
+/// the characters (or ranges, like `a-z`) inside of it, or any character but
+/// them if it starts with `!` or `^`. A backslash makes the next character
+/// match literally.
+pub fn matches(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+
+    let (mut p, mut i) = (0, 0);
+
+    // Where to resume matching if the current attempt fails, which is right
+    // after the latest `*`, with it having swallowed one more character.
+    let mut backtrack = None;
+
+    while i < s.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, i));
+                continue;
+            }
+
+            Some('?') => Some(p + 1),
+
+            Some('[') => match match_bracket(&pattern, p, s[i]) {
+                Some((matched, end)) => matched.then_some(end),
+                None => (s[i] == '[').then_some(p + 1),
+            },
+
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+
+            Some(&c) => (c == s[i]).then_some(p + 1),
+
+            None => None,
+        };
+
+        match (next, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+
+            (None, Some((star, start))) => {
+                backtrack = Some((star, start + 1));
+                p = star;
+                i = start + 1;
+            }
+
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Escapes the characters of `s` that would otherwise make it a pattern, so
+/// that it only matches itself.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Matches `c` against the bracket expression starting at `start` in `pattern`.
+/// Returns whether it matched along with the index right after the expression,
+/// or `None` if the bracket is never closed.
+fn match_bracket(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+
+    // A `]` right at the start is part of the set, rather than closing it.
+    let first = i;
+
+    loop {
+        let &lo = pattern.get(i)?;
+
+        if lo == ']' && i != first {
+            break;
+        }
+
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&hi)) if hi != ']' => {
+                matched |= (lo..=hi).contains(&c);
+                i += 3;
+            }
+
+            _ => {
+                matched |= lo == c;
+                i += 1;
+            }
+        }
+    }
+
+    Some((matched != negated, i + 1))
+}
+
+/// Finds the paths on the filesystem that match `pattern`, sorted. Each part
+/// of the path is matched on its own, except for `**` which matches any number
+/// of directories (or everything beneath them, if it is the last part). Files
+/// starting with `.` are only matched by parts that also start with `.`.
+pub fn expand(pattern: &str) -> Vec<String> {
+    let only_dirs = pattern.ends_with('/');
+
+    // `**.rs` is short for `**/*.rs`
+    let mut components = Vec::new();
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        match component.strip_prefix("**") {
+            Some(rest) if !rest.is_empty() => {
+                components.push("**".to_string());
+                components.push(format!("*{rest}"));
+            }
+            _ => components.push(component.to_string()),
+        }
+    }
+
+    let root = if pattern.starts_with('/') { "/" } else { "" };
+    let mut paths = vec![root.to_string()];
+
+    for (i, component) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+
+        paths = if component == "**" {
+            let mut found = Vec::new();
+            for path in &paths {
+                // Matching no directories at all only makes sense if there is
+                // something after the `**` to match against.
+                if !is_last {
+                    found.push(path.clone());
+                }
+                walk(path, is_last && !only_dirs, &mut found);
+            }
+            found
+        } else if !is_pattern(component) {
+            paths.iter().map(|path| join(path, component)).collect()
+        } else {
+            let mut found = Vec::new();
+            for path in &paths {
+                read_matching(path, component, !is_last || only_dirs, &mut found);
+            }
+            found
+        };
+    }
+
+    let mut paths = paths
+        .into_iter()
+        .filter(|path| path != root && fs::symlink_metadata(path).is_ok())
+        .filter(|path| !only_dirs || fs::metadata(path).is_ok_and(|m| m.is_dir()))
+        .map(|path| if only_dirs { path + "/" } else { path })
+        .collect::<Vec<_>>();
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() || dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Lists the entries of `dir`, where an empty string means the current directory.
+fn entries(dir: &str) -> impl Iterator<Item = fs::DirEntry> {
+    let dir = if dir.is_empty() { "." } else { dir };
+    fs::read_dir(dir).into_iter().flatten().flatten()
+}
+
+/// Adds the entries of `dir` that match `pattern` to `found`.
+fn read_matching(dir: &str, pattern: &str, only_dirs: bool, found: &mut Vec<String>) {
+    for entry in entries(dir) {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if name.starts_with('.') && !pattern.starts_with('.') {
+            continue;
+        }
+
+        if only_dirs && !entry.path().is_dir() {
+            continue;
+        }
+
+        if matches(pattern, &name) {
+            found.push(join(dir, &name));
+        }
+    }
+}
+
+/// Adds every directory beneath `dir` to `found`, and the files too if
+/// `include_files` is set. Hidden entries and symlinks are not descended into.
+fn walk(dir: &str, include_files: bool, found: &mut Vec<String>) {
+    for entry in entries(dir) {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let path = join(dir, &name);
+
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            found.push(path.clone());
+            walk(&path, include_files, found);
+        } else if include_files {
+            found.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_matching() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("?", "x"));
+        assert!(!matches("?", ""));
+        assert!(matches("[abc]x", "bx"));
+        assert!(!matches("[!abc]x", "bx"));
+        assert!(matches("[^abc]x", "dx"));
+        assert!(matches("file[0-9]", "file7"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[", "["));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(matches("*", ""));
+        assert!(matches("a/*", "a/b/c"));
+    }
+
+    #[test]
+    fn pattern_detection() {
+        assert!(is_pattern("*.rs"));
+        assert!(is_pattern("file?"));
+        assert!(is_pattern("[ab]"));
+        assert!(!is_pattern("["));
+        assert!(!is_pattern("\\*"));
+        assert!(!is_pattern("foo"));
+    }
+
+    #[test]
+    fn pattern_escaping() {
+        assert_eq!("\\*.rs", escape("*.rs"));
+        assert!(matches(&escape("[a]?\\"), "[a]?\\"));
+        assert!(!matches(&escape("a*"), "ab"));
+    }
+
+    #[test]
+    fn filesystem_expansion() {
+        let dir = std::env::temp_dir().join(format!("posh-glob-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        for path in ["a/b/c.rs", "a/d.rs", "a/.e.rs", "f.rs", "g.txt", ".h.rs"] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let dir = dir.to_str().unwrap();
+        let expand = |pattern: &str| {
+            expand(&format!("{dir}/{pattern}"))
+                .into_iter()
+                .map(|path| path[dir.len() + 1..].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["f.rs"], expand("*.rs"));
+        assert_eq!(vec![".h.rs"], expand(".*.rs"));
+        assert_eq!(vec!["a/d.rs"], expand("?/*.rs"));
+        assert_eq!(vec!["f.rs", "g.txt"], expand("[e-g].*"));
+        assert_eq!(vec!["a/b/c.rs", "a/d.rs", "f.rs"], expand("**/*.rs"));
+        assert_eq!(vec!["a/b/c.rs", "a/d.rs", "f.rs"], expand("**.rs"));
+        assert_eq!(vec!["a/", "a/b/"], expand("**/"));
+        assert_eq!(
+            vec!["a", "a/b", "a/b/c.rs", "a/d.rs", "f.rs", "g.txt"],
+            expand("**")
+        );
+        assert_eq!(Vec::<String>::new(), expand("*.md"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
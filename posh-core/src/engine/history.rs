@@ -35,6 +35,7 @@ impl FileHistory {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
 
         let mut buffer = String::new();
@@ -85,6 +86,7 @@ impl History for FileHistory {
         let mut file = fs::OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&self.path)?;
         file.write_all(self.lines.join("\n").as_bytes())?;
         file.write_all(b"\n")?;
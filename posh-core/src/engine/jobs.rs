@@ -0,0 +1,676 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process;
+
+use libc::pid_t;
+
+use super::parser::ast::CommandType;
+use super::{Engine, ExitStatus};
+use crate::Result;
+
+/// The signals an interactive shell ignores, which must be reset for its children.
+const JOB_CONTROL_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+
+    /// Stopped by the contained signal.
+    Stopped(i32),
+
+    /// Finished with the contained exit code.
+    Done(i32),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub pid: pid_t,
+    pub state: ProcessState,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: pid_t,
+    pub processes: Vec<Process>,
+    pub command: String,
+}
+
+impl Job {
+    pub fn is_done(&self) -> bool {
+        self.processes
+            .iter()
+            .all(|p| matches!(p.state, ProcessState::Done(_)))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|p| matches!(p.state, ProcessState::Stopped(_)))
+    }
+
+    /// The statuses of every process in the job, where processes that are
+    /// stopped are reported as 128 + the signal that stopped them.
+    pub fn statuses(&self) -> Vec<ExitStatus> {
+        self.processes
+            .iter()
+            .map(|p| match p.state {
+                ProcessState::Done(code) => ExitStatus::from(code),
+                ProcessState::Stopped(signal) => ExitStatus::from(128 + signal),
+                ProcessState::Running => ExitStatus::from(0),
+            })
+            .collect()
+    }
+
+    fn set_running(&mut self) {
+        for process in &mut self.processes {
+            if let ProcessState::Stopped(_) = process.state {
+                process.state = ProcessState::Running;
+            }
+        }
+    }
+
+    /// Polls every unfinished process in the job for changes, without blocking.
+    fn update(&mut self) -> Result<()> {
+        for process in &mut self.processes {
+            if let ProcessState::Done(_) = process.state {
+                continue;
+            }
+
+            let options = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            if let Some(state) = wait_pid(process.pid, options)? {
+                process.state = state;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.is_done() {
+            match self.statuses().last() {
+                Some(status) if status.code != 0 => format!("Exit {}", status.code),
+                _ => "Done".to_string(),
+            }
+        } else if self.is_stopped() {
+            "Stopped".to_string()
+        } else {
+            "Running".to_string()
+        };
+
+        write!(f, "[{}]  {:<10}{}", self.id, state, self.command)
+    }
+}
+
+/// Waits for `pid` to change state. Returns `None` if `options` contains
+/// `WNOHANG` and the process has not changed state.
+pub(super) fn wait_pid(pid: pid_t, options: libc::c_int) -> Result<Option<ProcessState>> {
+    let mut status = 0;
+
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, options) } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+            0 => return Ok(None),
+            _ => break,
+        }
+    }
+
+    let state = if libc::WIFSTOPPED(status) {
+        ProcessState::Stopped(libc::WSTOPSIG(status))
+    } else if libc::WIFCONTINUED(status) {
+        ProcessState::Running
+    } else {
+        let status: ExitStatus = process::ExitStatus::from_raw(status).into();
+        ProcessState::Done(status.code)
+    };
+
+    Ok(Some(state))
+}
+
+/// Gives the terminal to the process group `pgid`.
+fn give_terminal_to(pgid: pid_t) {
+    unsafe {
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+    }
+}
+
+/// Puts a freshly created child process into the state it should be in before
+/// running a command. Only async-signal-safe functions may be called in here,
+/// since it runs between `fork` and `exec`.
+pub(super) fn prepare_child(take_terminal: bool) {
+    unsafe {
+        if take_terminal {
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        }
+
+        for signal in JOB_CONTROL_SIGNALS {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+impl<W: Write> Engine<W> {
+    /// Makes the shell manage the terminal, which is needed to stop and resume jobs.
+    /// Does nothing if stdin is not a terminal.
+    pub fn enable_job_control(&mut self) -> Result<()> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Ok(());
+            }
+
+            // If we were started in the background, wait until we are put in the foreground.
+            loop {
+                let pgrp = libc::getpgrp();
+                if libc::tcgetpgrp(libc::STDIN_FILENO) == pgrp {
+                    break;
+                }
+                libc::kill(-pgrp, libc::SIGTTIN);
+            }
+
+            for signal in JOB_CONTROL_SIGNALS {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+
+            let pid = libc::getpid();
+            if libc::getpgrp() != pid {
+                libc::setpgid(pid, pid);
+            }
+            give_terminal_to(pid);
+        }
+
+        self.job_control = true;
+        Ok(())
+    }
+
+    fn next_job_id(&self) -> usize {
+        self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+    }
+
+    /// Adds a job that was started in the background to the job table.
+    pub(super) fn add_job(&mut self, pgid: pid_t, pids: &[pid_t], command: String) -> Result<()> {
+        let id = self.next_job_id();
+        self.last_background_pid = pids.last().copied();
+
+        self.jobs.push(Job {
+            id,
+            pgid,
+            processes: pids
+                .iter()
+                .map(|&pid| Process {
+                    pid,
+                    state: ProcessState::Running,
+                })
+                .collect(),
+            command,
+        });
+
+        if self.job_control {
+            writeln!(self.writer, "[{id}] {}", pids.last().unwrap_or(&pgid))?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for a job in the foreground until all of its processes have finished,
+    /// or until it is stopped, in which case it is put in the job table.
+    pub(super) fn wait_for_job(&mut self, mut job: Job) -> Result<Vec<ExitStatus>> {
+        if self.job_control {
+            give_terminal_to(job.pgid);
+        }
+
+        for process in &mut job.processes {
+            if let ProcessState::Running = process.state {
+                if let Some(state) = wait_pid(process.pid, libc::WUNTRACED)? {
+                    process.state = state;
+                }
+            }
+        }
+
+        if self.job_control {
+            give_terminal_to(unsafe { libc::getpgrp() });
+        }
+
+        let statuses = job.statuses();
+
+        if job.is_stopped() {
+            if job.id == 0 {
+                job.id = self.next_job_id();
+            }
+            writeln!(self.writer)?;
+            writeln!(self.writer, "{job}")?;
+            self.jobs.push(job);
+        }
+
+        Ok(statuses)
+    }
+
+    /// Runs `cmd` in a forked copy of the shell, in its own process group.
+    /// Returns the pid of the child.
+    pub(super) fn fork_background(&mut self, cmd: CommandType) -> Result<pid_t> {
+        self.writer.flush()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error().into()),
+
+            0 => {
+                unsafe {
+                    libc::setpgid(0, 0);
+                }
+                prepare_child(false);
+
+                // Without job control, background jobs must not read from the terminal.
+                if !self.job_control {
+                    if let Ok(null) = File::open("/dev/null") {
+                        unsafe {
+                            libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+                        }
+                    }
+                }
+
+                // Like external commands in the background, everything is written
+                // straight to stdout, since the writer of the shell may just be a
+                // copy that would be lost when the child exits.
+                let mut engine = self.subshell(io::stdout());
+                engine.inherit_stdout = true;
+
+                let code = match engine.execute(cmd) {
+                    Ok(statuses) => statuses.last().map_or(0, |s| s.code),
+                    Err(e) => {
+                        eprintln!("posh: {e}");
+                        1
+                    }
+                };
+
+                let _ = engine.writer.flush();
+                unsafe { libc::_exit(code) }
+            }
+
+            pid => {
+                // Also set the process group from here, so that it is in place
+                // regardless of whether the parent or the child runs first.
+                unsafe {
+                    libc::setpgid(pid, pid);
+                }
+                Ok(pid)
+            }
+        }
+    }
+
+    /// Runs `cmd` as a stage of a pipeline in a forked copy of the shell, reading
+    /// from `stdin` like an external command would. Unless `inherit_stdout` is
+    /// set, its output can be read from the returned file descriptor. The child
+    /// joins the process group `pgid` if there is one, or 0 for its own.
+    pub(super) fn fork_stage(
+        &mut self,
+        cmd: CommandType,
+        stdin: Option<Option<OwnedFd>>,
+        inherit_stdout: bool,
+        pgid: Option<pid_t>,
+        take_terminal: bool,
+    ) -> Result<(pid_t, Option<OwnedFd>)> {
+        let pipe = if inherit_stdout {
+            None
+        } else {
+            Some(io::pipe()?)
+        };
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error().into()),
+
+            0 => {
+                if let Some(pgid) = pgid {
+                    unsafe {
+                        libc::setpgid(0, pgid);
+                    }
+                }
+                prepare_child(take_terminal);
+
+                let stdin = match stdin {
+                    Some(Some(fd)) => Some(fd),
+                    Some(None) => File::open("/dev/null").ok().map(OwnedFd::from),
+                    None => None,
+                };
+                if let Some(fd) = stdin {
+                    unsafe {
+                        libc::dup2(fd.as_raw_fd(), libc::STDIN_FILENO);
+                    }
+                }
+
+                if let Some((reader, writer)) = pipe {
+                    drop(reader);
+                    unsafe {
+                        libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO);
+                    }
+                }
+
+                // Everything is written straight to what is now stdout
+                let mut engine = self.subshell(io::stdout());
+                engine.inherit_stdout = true;
+
+                // The subshell is this child already, so it needn't fork again
+                let cmd = match cmd {
+                    CommandType::Subshell(list) => CommandType::Group(list),
+                    CommandType::Redirected { command, redirects } => CommandType::Redirected {
+                        command: Box::new(match *command {
+                            CommandType::Subshell(list) => CommandType::Group(list),
+                            command => command,
+                        }),
+                        redirects,
+                    },
+                    cmd => cmd,
+                };
+
+                let statuses = match cmd {
+                    CommandType::Single(cmd) => engine.run_simple(cmd),
+                    cmd => engine.execute(cmd),
+                };
+
+                let code = match statuses {
+                    Ok(statuses) => statuses.last().map_or(0, |s| s.code),
+                    Err(e) => {
+                        eprintln!("posh: {e}");
+                        1
+                    }
+                };
+
+                let _ = engine.writer.flush();
+                unsafe { libc::_exit(code) }
+            }
+
+            pid => {
+                // Like with background jobs, the process group is set from both sides
+                if let Some(pgid) = pgid {
+                    unsafe {
+                        libc::setpgid(pid, if pgid == 0 { pid } else { pgid });
+                    }
+                }
+                Ok((pid, pipe.map(|(reader, _)| OwnedFd::from(reader))))
+            }
+        }
+    }
+
+    /// Reports (and forgets) every background job that has finished since the
+    /// last time this was called.
+    pub fn notify_jobs(&mut self) -> Result<()> {
+        for job in &mut self.jobs {
+            job.update()?;
+        }
+
+        let mut i = 0;
+        while i < self.jobs.len() {
+            if self.jobs[i].is_done() {
+                let job = self.jobs.remove(i);
+                writeln!(self.writer, "{job}")?;
+            } else {
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the index of the job referred to by `spec`, which is either the job id,
+    /// optionally prefixed by `%`, or `None` for the current (most recent) job.
+    fn find_job(&self, spec: Option<&str>) -> Option<usize> {
+        match spec {
+            None | Some("%" | "%%" | "%+") => self.jobs.len().checked_sub(1),
+            Some(spec) => {
+                let id = spec
+                    .strip_prefix('%')
+                    .unwrap_or(spec)
+                    .parse::<usize>()
+                    .ok()?;
+                self.jobs.iter().position(|job| job.id == id)
+            }
+        }
+    }
+
+    pub(super) fn list_jobs(&mut self) -> Result<ExitStatus> {
+        for job in &mut self.jobs {
+            job.update()?;
+        }
+
+        for job in &self.jobs {
+            writeln!(self.writer, "{job}")?;
+        }
+
+        self.jobs.retain(|job| !job.is_done());
+
+        Ok(ExitStatus::from(0))
+    }
+
+    pub(super) fn fg(&mut self, spec: Option<&str>) -> Result<ExitStatus> {
+        if !self.job_control {
+            writeln!(self.writer, "fg: No job control in this shell.")?;
+            return Ok(ExitStatus::from(1));
+        }
+
+        let Some(index) = self.find_job(spec) else {
+            writeln!(self.writer, "fg: No such job.")?;
+            return Ok(ExitStatus::from(1));
+        };
+
+        let mut job = self.jobs.remove(index);
+        writeln!(self.writer, "{}", job.command)?;
+        self.writer.flush()?;
+
+        give_terminal_to(job.pgid);
+        unsafe {
+            libc::kill(-job.pgid, libc::SIGCONT);
+        }
+        job.set_running();
+
+        let statuses = self.wait_for_job(job)?;
+        Ok(statuses
+            .into_iter()
+            .last()
+            .unwrap_or_else(|| ExitStatus::from(0)))
+    }
+
+    pub(super) fn bg(&mut self, spec: Option<&str>) -> Result<ExitStatus> {
+        let Some(index) = self.find_job(spec) else {
+            writeln!(self.writer, "bg: No such job.")?;
+            return Ok(ExitStatus::from(1));
+        };
+
+        let job = &mut self.jobs[index];
+        unsafe {
+            libc::kill(-job.pgid, libc::SIGCONT);
+        }
+        job.set_running();
+
+        writeln!(self.writer, "[{}] {} &", job.id, job.command)?;
+        Ok(ExitStatus::from(0))
+    }
+
+    /// Waits for the given jobs (or pids) to finish or be stopped, or all of
+    /// them if none are given. Returns the status of the last one waited for.
+    pub(super) fn wait(&mut self, specs: &[String]) -> Result<ExitStatus> {
+        let indices = if specs.is_empty() {
+            (0..self.jobs.len()).collect()
+        } else {
+            let mut indices = Vec::with_capacity(specs.len());
+
+            for spec in specs {
+                let index = match spec.parse::<pid_t>() {
+                    Ok(pid) => self
+                        .jobs
+                        .iter()
+                        .position(|job| job.processes.iter().any(|p| p.pid == pid)),
+                    Err(_) => self.find_job(Some(spec)),
+                };
+
+                match index {
+                    Some(index) => indices.push(index),
+                    None => {
+                        writeln!(self.writer, "wait: No such job: {spec}")?;
+                        return Ok(ExitStatus::from(127));
+                    }
+                }
+            }
+
+            indices
+        };
+
+        let mut status = ExitStatus::from(0);
+
+        for &index in &indices {
+            let job = &mut self.jobs[index];
+            job.update()?;
+
+            for process in &mut job.processes {
+                if let ProcessState::Running = process.state {
+                    if let Some(state) = wait_pid(process.pid, libc::WUNTRACED)? {
+                        process.state = state;
+                    }
+                }
+
+                // Waiting for the rest would block until the job is resumed
+                if let ProcessState::Stopped(_) = process.state {
+                    break;
+                }
+            }
+
+            if job.is_stopped() {
+                writeln!(self.writer, "{job}")?;
+            }
+
+            if !specs.is_empty() {
+                if let Some(last) = job.statuses().pop() {
+                    status = last;
+                }
+            }
+        }
+
+        self.jobs.retain(|job| !job.is_done());
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: usize, states: &[ProcessState]) -> Job {
+        Job {
+            id,
+            pgid: 0,
+            processes: states
+                .iter()
+                .map(|&state| Process { pid: 0, state })
+                .collect(),
+            command: "cmd".to_string(),
+        }
+    }
+
+    #[test]
+    fn job_states() {
+        let done = job(1, &[ProcessState::Done(0), ProcessState::Done(2)]);
+        assert!(done.is_done());
+        assert!(!done.is_stopped());
+        assert_eq!(
+            vec![0, 2],
+            done.statuses().iter().map(|s| s.code).collect::<Vec<_>>()
+        );
+        assert_eq!("[1]  Exit 2    cmd", done.to_string());
+
+        let stopped = job(2, &[ProcessState::Running, ProcessState::Stopped(20)]);
+        assert!(!stopped.is_done());
+        assert!(stopped.is_stopped());
+        assert_eq!(
+            vec![0, 148],
+            stopped
+                .statuses()
+                .iter()
+                .map(|s| s.code)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("[2]  Stopped   cmd", stopped.to_string());
+
+        assert_eq!(
+            "[3]  Done      cmd",
+            job(3, &[ProcessState::Done(0)]).to_string()
+        );
+        assert_eq!(
+            "[4]  Running   cmd",
+            job(4, &[ProcessState::Running]).to_string()
+        );
+    }
+
+    #[test]
+    fn finding_jobs() {
+        let mut engine = Engine::with_writer(Vec::new());
+        assert_eq!(None, engine.find_job(None));
+
+        engine.jobs = vec![
+            job(1, &[ProcessState::Running]),
+            job(3, &[ProcessState::Running]),
+        ];
+
+        assert_eq!(Some(1), engine.find_job(None));
+        assert_eq!(Some(1), engine.find_job(Some("%%")));
+        assert_eq!(Some(0), engine.find_job(Some("%1")));
+        assert_eq!(Some(1), engine.find_job(Some("3")));
+        assert_eq!(None, engine.find_job(Some("%2")));
+        assert_eq!(None, engine.find_job(Some("%x")));
+        assert_eq!(4, engine.next_job_id());
+    }
+
+    #[test]
+    fn waiting_for_background_jobs() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!("3\n", engine.output("sh -c 'exit 3' & wait $!; echo $?"));
+        assert_eq!("0\n", engine.output("sleep 0 & sleep 0 & wait; echo $?"));
+        assert!(engine.jobs.is_empty());
+        assert_eq!(
+            "wait: No such job: %9\n127\n",
+            engine.output("wait %9; echo $?")
+        );
+    }
+
+    #[test]
+    fn waiting_for_stopped_jobs() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "[1]  Stopped   sleep 10 &\n147\n",
+            engine.output("sleep 10 & kill -STOP $!; wait $!; echo $?")
+        );
+        assert!(engine.jobs[0].is_stopped());
+
+        engine.output("kill -KILL $!");
+    }
+
+    #[test]
+    fn background_output() {
+        let mut engine = Engine::with_writer(Vec::new());
+        let path = std::env::temp_dir().join(format!("posh-background-{}", process::id()));
+        let path = path.display();
+
+        assert_eq!(
+            "2\nb\n",
+            engine.output(format!(
+                "{{ math 1 + 1; echo b; }} >{path} & wait; cat {path}; rm {path}"
+            ))
+        );
+    }
+}
@@ -1,23 +1,113 @@
+pub mod arithmetic;
+mod control;
+mod functions;
+pub mod glob;
 pub mod history;
+pub mod jobs;
 pub mod parser;
-
-use std::io::{self, Stdout, Write};
+mod redirects;
+pub mod variables;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Stdout, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::PathBuf;
-use std::process::{self, ChildStdout, Stdio};
+use std::process::{self, Stdio};
 
 use crate::{path, Error, Result};
 
-pub use self::history::{FileHistory, History};
-use self::parser::ast::{parse, Command, CommandType, Expand, Redirect, SyntaxTree};
+use self::control::Control;
+pub use self::history::{DummyHistory, FileHistory, History};
+pub use self::jobs::Job;
+use self::parser::ast::{parse, Command, CommandType, Expand, Meta, SyntaxTree};
+use self::variables::Variable;
+pub use self::variables::Variables;
+
+const BUILTINS: [&str; 15] = [
+    "cd", "exit", "jobs", "fg", "bg", "wait", "export", "readonly", "unset", "set", "math",
+    "break", "continue", "local", "return",
+];
 
 pub struct Engine<W: Write> {
     pub writer: W,
     pub prev_dir: Option<PathBuf>,
     pub commands: Vec<String>,
     pub history: Box<dyn History>,
+    pub jobs: Vec<Job>,
+    pub vars: Variables,
+    pub options: Options,
+
+    /// The name of the shell, `$0`, followed by the positional parameters.
+    pub args: Vec<String>,
+
+    /// The functions that have been defined, by name.
+    pub functions: BTreeMap<String, SyntaxTree>,
+
+    /// The exit status of the latest command, `$?`.
+    pub last_status: i32,
+
+    /// The process id of the latest background job, `$!`.
+    pub last_background_pid: Option<i32>,
+
+    /// The process id of the shell, `$$`, which stays the same in subshells.
+    pid: u32,
+
+    /// The exit status of the latest command substitution in the command
+    /// being executed, which becomes the status of a bare assignment.
+    substitution_status: Option<i32>,
+
+    /// How many loops the commands being run are inside of.
+    loop_depth: usize,
+
+    /// A `break`, `continue` or `return` that has yet to reach its loop or function.
+    control: Option<Control>,
+
+    /// The variables made local by each function being called, innermost
+    /// last, along with what they were before so that they can be restored.
+    locals: Vec<Vec<(String, Option<Variable>)>>,
+
+    /// Whether the shell is in control of the terminal, and can thus run
+    /// jobs in the foreground and stop them.
+    job_control: bool,
+
+    /// Whether child processes should write directly to the shell's own stdout.
+    /// When this is `false`, their output is instead streamed through `writer`.
+    inherit_stdout: bool,
+
+    /// The processes of the process substitutions in the commands being run,
+    /// along with the shell's end of their pipes.
+    process_substitutions: Vec<(libc::pid_t, OwnedFd)>,
 }
 
 impl<W: Write> Engine<W> {
+    /// Creates an engine whose output, including that of any child processes,
+    /// is written to `writer` instead of the terminal.
+    pub fn with_writer(writer: W) -> Self {
+        Self {
+            prev_dir: None,
+            writer,
+            commands: path::get_cmds_from_path(),
+            history: Box::new(DummyHistory),
+            jobs: Vec::new(),
+            vars: Variables::from_env(),
+            options: Options::default(),
+            args: vec!["posh".to_string()],
+            functions: BTreeMap::new(),
+            last_status: 0,
+            last_background_pid: None,
+            pid: process::id(),
+            substitution_status: None,
+            loop_depth: 0,
+            control: None,
+            locals: Vec::new(),
+            job_control: false,
+            inherit_stdout: false,
+            process_substitutions: Vec::new(),
+        }
+    }
+
     fn cd(&mut self, dir: Option<&str>) -> Result<ExitStatus> {
         let path = match dir {
             Some("-") if self.prev_dir.is_some() => self.prev_dir.take().unwrap(),
@@ -39,7 +129,10 @@ impl<W: Write> Engine<W> {
                 return Ok(ExitStatus::from(2));
             }
 
-            None => PathBuf::from(path::home_dir()),
+            None => match self.vars.get("HOME") {
+                Some(home) => PathBuf::from(home),
+                None => PathBuf::from(path::home_dir()),
+            },
         };
 
         self.prev_dir = Some(std::env::current_dir()?);
@@ -54,11 +147,11 @@ impl<W: Write> Engine<W> {
     pub fn has_builtin(&self, cmd: impl AsRef<str>) -> bool {
         let cmd = cmd.as_ref();
         let has = |s| cmd == s || cmd.starts_with(&format!("{s} "));
-        has("cd") || has("exit")
+        BUILTINS.into_iter().any(has)
     }
 
     pub fn execute_builtin(&mut self, cmd: Command) -> Result<ExitStatus> {
-        let cmd = cmd.expand()?;
+        let cmd = cmd.expand(self)?;
         let command = cmd.cmd_name();
         let args = cmd.args();
 
@@ -80,6 +173,33 @@ impl<W: Write> Engine<W> {
                 Ok(ExitStatus::from(1))
             }
 
+            ("jobs", []) => self.list_jobs(),
+
+            ("fg", []) => self.fg(None),
+            ("fg", [job]) => self.fg(Some(job)),
+
+            ("bg", []) => self.bg(None),
+            ("bg", [job]) => self.bg(Some(job)),
+
+            ("jobs" | "fg" | "bg", _) => {
+                writeln!(self.writer, "invalid number of arguments")?;
+                Ok(ExitStatus::from(1))
+            }
+
+            ("wait", jobs) => self.wait(jobs),
+
+            ("export" | "readonly", args) => self.mark_vars(command, args),
+            ("unset", names) => self.unset(names),
+            ("set", args) => self.set(args),
+
+            ("math", args) => self.math(args),
+
+            ("break" | "continue", args) => self.loop_control(command, args),
+
+            ("local", args) => self.local(args),
+
+            ("return", args) => self.return_from_function(args),
+
             (c, _) => Err(Error::UnknownCommand(c.to_string())),
         }
     }
@@ -94,131 +214,449 @@ impl<W: Write> Engine<W> {
     }
 
     pub fn execute_line(&mut self, line: impl ToString) -> Result<Vec<ExitStatus>> {
-        let ast = parse(line.to_string());
+        let ast = parse(line.to_string())?;
         self.walk_ast(ast)
     }
 
+    /// Builds the process for an external command. Its redirections are
+    /// opened here, but only applied in the child, in the order they were
+    /// written, on top of the pipes connecting it to the rest of the pipeline.
     fn build_command(
-        &self,
+        &mut self,
         command: &Command,
-        prev_stdout: Option<Option<ChildStdout>>,
-        final_cmd: bool,
+        prev_stdout: Option<Option<OwnedFd>>,
+        inherit_stdout: bool,
     ) -> Result<process::Command> {
-        let (stdin_redirect, stdout_redirect, stderr_redirect) = command.redirections();
-
-        let mut stdin = match prev_stdout {
+        let stdin = match prev_stdout {
             Some(Some(stdout)) => Stdio::from(stdout),
             Some(None) => Stdio::null(),
             None => Stdio::inherit(),
         };
 
-        if let Some(Redirect::Input { to }) = stdin_redirect {
-            let f = std::fs::OpenOptions::new().read(true).open(to.name)?;
-            stdin = Stdio::from(f);
-        }
-
-        let stdout = if let Some(Redirect::Output {
-            from: _,
-            to,
-            append,
-        }) = stdout_redirect
-        {
-            let f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(append)
-                .open(to.name)?;
-            Stdio::from(f)
-        } else if final_cmd {
+        let stdout = if inherit_stdout {
             Stdio::inherit()
         } else {
             Stdio::piped()
         };
 
-        let stderr = if let Some(Redirect::Output {
-            from: _,
-            to,
-            append,
-        }) = stderr_redirect
-        {
-            let f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(append)
-                .open(to.name)?;
-            Stdio::from(f)
-        } else {
-            Stdio::inherit()
-        };
+        let mut redirects = Vec::new();
+        for redirect in command.redirects() {
+            redirects.extend(self.open_redirect(redirect)?);
+        }
 
         let mut cmd = process::Command::new(command.cmd_name());
-        let cmd = cmd
-            .args(command.args())
+        cmd.args(command.args())
+            .env_clear()
+            .envs(self.vars.exported())
             .envs(command.vars())
             .stdin(stdin)
-            .stdout(stdout)
-            .stderr(stderr);
+            .stdout(stdout);
+
+        if !redirects.is_empty() {
+            unsafe {
+                cmd.pre_exec(move || {
+                    for (fd, target) in &redirects {
+                        redirects::redirect_fd(*fd, target)?;
+                    }
+                    Ok(())
+                });
+            }
+        }
 
-        let dummy = process::Command::new("tmp");
-        Ok(std::mem::replace(cmd, dummy))
+        Ok(cmd)
     }
 
     pub fn execute(&mut self, cmd: CommandType) -> Result<Vec<ExitStatus>> {
-        let cmd = cmd.expand()?;
+        let process_substitutions = self.process_substitutions.len();
+        let statuses = self.execute_command(cmd);
+        self.finish_process_substitutions(process_substitutions);
 
-        match cmd {
-            CommandType::Single(cmd) if self.has_builtin(cmd.cmd_name()) => {
-                self.execute_builtin(cmd).map(|r| vec![r])
+        self.last_status = match &statuses {
+            Ok(statuses) => statuses.last().map_or(0, |s| s.code),
+            Err(_) => 1,
+        };
+
+        statuses
+    }
+
+    fn execute_command(&mut self, cmd: CommandType) -> Result<Vec<ExitStatus>> {
+        // The right hand side of an AND/OR list may depend on what the left hand
+        // side did, so these must be expanded one at a time as they are executed.
+        let cmd = match cmd {
+            CommandType::And(lhs, rhs) => {
+                let statuses = self.execute(*lhs)?;
+                return if ExitStatus::succeeded(&statuses) && self.control.is_none() {
+                    self.execute(*rhs)
+                } else {
+                    Ok(statuses)
+                };
             }
 
-            CommandType::Single(cmd) => {
-                if !self.has_command(cmd.cmd_name()) {
-                    writeln!(self.writer, "Unknown command: {}", cmd.cmd_name())?;
-                    return Ok(vec![ExitStatus::from(127)]);
+            CommandType::Or(lhs, rhs) => {
+                let statuses = self.execute(*lhs)?;
+                return if ExitStatus::succeeded(&statuses) || self.control.is_some() {
+                    Ok(statuses)
+                } else {
+                    self.execute(*rhs)
+                };
+            }
+
+            CommandType::Background(cmd) => {
+                self.run_in_background(*cmd)?;
+                return Ok(vec![ExitStatus::from(0)]);
+            }
+
+            CommandType::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    let statuses = self.walk_ast(condition)?;
+                    if self.control.is_some() {
+                        return Ok(statuses);
+                    }
+
+                    if ExitStatus::succeeded(&statuses) {
+                        return self.walk_ast(body);
+                    }
                 }
 
-                let mut command = self.build_command(&cmd, None, true)?;
-                let output = command.output()?;
-                self.writer.write_all(&output.stdout)?;
-                let code = output.status.code().unwrap_or_default();
+                return match otherwise {
+                    Some(body) => self.walk_ast(body),
+                    None => Ok(vec![ExitStatus::from(0)]),
+                };
+            }
+
+            CommandType::While {
+                condition,
+                body,
+                until,
+            } => return self.run_while(condition, body, until),
 
-                Ok(vec![ExitStatus::from(code)])
+            CommandType::For { name, words, body } => return self.run_for(&name, words, body),
+
+            CommandType::Case { word, arms } => return self.run_case(word, arms),
+
+            CommandType::Function { name, body } => {
+                self.functions.insert(name, body);
+                return Ok(vec![ExitStatus::from(0)]);
             }
 
-            CommandType::Pipeline(cmds) => {
-                if let Some(cmd) = cmds.iter().find(|cmd| {
-                    !self.has_command(cmd.cmd_name()) && !self.has_builtin(cmd.cmd_name())
-                }) {
-                    writeln!(self.writer, "Unknown command: {}", cmd.cmd_name())?;
-                    return Ok(vec![ExitStatus::from(127)]);
+            CommandType::Subshell(list) => {
+                return self.run_pipeline(vec![CommandType::Subshell(list)], true)
+            }
+
+            CommandType::Group(list) => return self.walk_ast(list),
+
+            CommandType::Redirected { command, redirects } => {
+                // A subshell applies the redirections in its own process instead
+                if let CommandType::Subshell(_) = *command {
+                    let cmd = CommandType::Redirected { command, redirects };
+                    return self.run_pipeline(vec![cmd], true);
+                }
+                return self.run_redirected(*command, redirects);
+            }
+
+            cmd => {
+                self.substitution_status = None;
+                cmd.expand(self)?
+            }
+        };
+
+        match cmd {
+            CommandType::Single(cmd) => self.run_simple(cmd),
+
+            CommandType::Pipeline(cmds) => self.run_pipeline(cmds, true),
+
+            CommandType::And(_, _)
+            | CommandType::Or(_, _)
+            | CommandType::Background(_)
+            | CommandType::If { .. }
+            | CommandType::While { .. }
+            | CommandType::For { .. }
+            | CommandType::Case { .. }
+            | CommandType::Function { .. }
+            | CommandType::Subshell(_)
+            | CommandType::Group(_)
+            | CommandType::Redirected { .. } => {
+                unreachable!("AND/OR lists, background jobs and compound commands should have been executed already")
+            }
+        }
+    }
+
+    /// Runs a simple command whose words have already been expanded, which is
+    /// either an assignment, a builtin, a function or an external command.
+    fn run_simple(&mut self, mut cmd: Command) -> Result<Vec<ExitStatus>> {
+        // Anything that runs in the shell itself needs the redirections applied
+        // to the shell, while external commands apply them once spawned.
+        let redirects = cmd.redirects();
+        if !redirects.is_empty() && !self.is_external(&cmd) {
+            let is_not_redirect = |meta: &Meta| !matches!(meta, Meta::Redirect(_));
+            cmd.prefixes.retain(is_not_redirect);
+            cmd.suffixes.retain(is_not_redirect);
+            return self.with_redirects(redirects, |engine| engine.run_simple(cmd));
+        }
+
+        if cmd.cmd_name().is_empty() {
+            self.assign(cmd).map(|r| vec![r])
+        } else if self.has_builtin(cmd.cmd_name()) {
+            self.execute_builtin(cmd).map(|r| vec![r])
+        } else if self.has_function(cmd.cmd_name()) {
+            self.call_function(cmd)
+        } else {
+            self.run_pipeline(vec![CommandType::Single(cmd)], true)
+        }
+    }
+
+    /// Whether `cmd` runs an external command, rather than something that
+    /// needs the shell itself.
+    fn is_external(&self, cmd: &Command) -> bool {
+        let name = cmd.cmd_name();
+        !name.is_empty() && !self.has_builtin(name) && !self.has_function(name)
+    }
+
+    /// Spawns every command of a pipeline as a single job, which is waited
+    /// for if it is run in the foreground. External commands are spawned
+    /// directly, while the rest are run in forked copies of the shell.
+    fn run_pipeline(
+        &mut self,
+        cmds: Vec<CommandType>,
+        foreground: bool,
+    ) -> Result<Vec<ExitStatus>> {
+        if let Some(cmd) = cmds.iter().find_map(|cmd| match cmd {
+            CommandType::Single(cmd)
+                if self.is_external(cmd) && !self.has_command(cmd.cmd_name()) =>
+            {
+                Some(cmd)
+            }
+            _ => None,
+        }) {
+            writeln!(self.writer, "Unknown command: {}", cmd.cmd_name())?;
+            return Ok(vec![ExitStatus::from(127)]);
+        }
+
+        self.writer.flush()?;
+
+        let command = cmds
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        // Without job control, background jobs must not read from the terminal.
+        let mut prev_stdout: Option<Option<OwnedFd>> = if foreground || self.job_control {
+            None
+        } else {
+            Some(None)
+        };
+
+        let len = cmds.len();
+        let mut pids = Vec::with_capacity(len);
+        let mut output = None;
+        let mut pgid = 0;
+
+        // Every stage has to be running before any of them is waited on,
+        // since a stage may block until the next one reads its output.
+        for (i, cmd) in cmds.into_iter().enumerate() {
+            let is_final = i == len - 1;
+            let inherit_stdout = is_final && (self.inherit_stdout || !foreground);
+            let take_terminal = foreground && self.job_control;
+
+            // Each job gets its own process group, so that it can be
+            // signalled (and given the terminal) as a whole.
+            let set_pgid = self.job_control || !foreground;
+
+            let spawned = match cmd {
+                CommandType::Single(cmd) if self.is_external(&cmd) => self
+                    .build_command(&cmd, prev_stdout.take(), inherit_stdout)
+                    .and_then(|mut command| {
+                        if set_pgid {
+                            command.process_group(pgid);
+                        }
+
+                        unsafe {
+                            command.pre_exec(move || {
+                                jobs::prepare_child(take_terminal);
+                                Ok(())
+                            });
+                        }
+
+                        let mut child = command.spawn()?;
+                        Ok((child.id() as i32, child.stdout.take().map(OwnedFd::from)))
+                    }),
+
+                cmd => self.fork_stage(
+                    cmd,
+                    prev_stdout.take(),
+                    inherit_stdout,
+                    set_pgid.then_some(pgid),
+                    take_terminal,
+                ),
+            };
+
+            let (pid, stdout) = match spawned {
+                Ok(spawned) => spawned,
+                Err(e) => {
+                    // The stages that did start will finish on their own now that
+                    // the rest of the pipeline is gone, so just reap them.
+                    for pid in pids {
+                        jobs::wait_pid(pid, 0)?;
+                    }
+                    return Err(e);
                 }
+            };
+
+            if pgid == 0 {
+                pgid = pid;
+            }
+
+            if is_final {
+                output = stdout;
+            } else {
+                prev_stdout = Some(stdout);
+            }
+
+            pids.push(pid);
+        }
+
+        if !foreground {
+            self.add_job(pgid, &pids, command + " &")?;
+            return Ok(vec![ExitStatus::from(0)]);
+        }
+
+        // Copy the output of the last stage into `writer` as it is produced
+        if let Some(output) = output {
+            io::copy(&mut File::from(output), &mut self.writer)?;
+            self.writer.flush()?;
+        }
+
+        let job = Job {
+            id: 0,
+            pgid,
+            processes: pids
+                .into_iter()
+                .map(|pid| jobs::Process {
+                    pid,
+                    state: jobs::ProcessState::Running,
+                })
+                .collect(),
+            command,
+        };
+
+        self.wait_for_job(job)
+    }
+
+    /// Starts `cmd` as a background job. Pipelines of external commands are spawned
+    /// directly, while everything else is run in a forked copy of the shell.
+    fn run_in_background(&mut self, cmd: CommandType) -> Result<()> {
+        let command = format!("{cmd} &");
+
+        match cmd {
+            CommandType::Single(cmd) if self.is_external(&cmd) => {
+                let cmd = cmd.expand(self)?;
+                self.run_pipeline(vec![CommandType::Single(cmd)], false)?;
+            }
 
-                let mut prev_result: Option<ChildStdout> = None;
-                let mut statuses = Vec::with_capacity(cmds.len());
+            CommandType::Pipeline(cmds) => {
+                let cmds = cmds
+                    .into_iter()
+                    .map(|c| c.expand(self))
+                    .collect::<Result<Vec<_>>>()?;
+                self.run_pipeline(cmds, false)?;
+            }
 
-                for (i, cmd) in cmds.iter().enumerate() {
-                    let is_final = i == cmds.len() - 1;
-                    let mut command = self.build_command(cmd, Some(prev_result), is_final)?;
+            cmd => {
+                let pid = self.fork_background(cmd)?;
+                self.add_job(pid, &[pid], command)?;
+            }
+        }
 
-                    let mut child = command.spawn()?;
+        Ok(())
+    }
 
-                    prev_result = child.stdout.take();
+    /// Runs `ast` in a forked child of the shell and returns everything it wrote
+    /// to stdout, which is how command substitutions are performed.
+    pub(crate) fn capture_output(&mut self, ast: SyntaxTree) -> Result<String> {
+        let (mut reader, writer) = io::pipe()?;
+        self.writer.flush()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error().into()),
+
+            0 => {
+                drop(reader);
+                jobs::prepare_child(false);
+
+                let mut engine = self.subshell(writer);
+                let code = match engine.walk_ast(ast) {
+                    Ok(statuses) => statuses.last().map_or(0, |s| s.code),
+                    Err(e) => {
+                        eprintln!("posh: {e}");
+                        1
+                    }
+                };
+
+                let _ = engine.writer.flush();
+                unsafe { libc::_exit(code) }
+            }
 
-                    let output = child.wait_with_output()?;
-                    self.writer.write_all(&output.stdout)?;
+            pid => {
+                drop(writer);
 
-                    statuses.push(ExitStatus::from(output.status.code().unwrap_or_default()));
+                let mut output = Vec::new();
+                let read = reader.read_to_end(&mut output);
+                if let Some(jobs::ProcessState::Done(code)) = jobs::wait_pid(pid, 0)? {
+                    self.substitution_status = Some(code);
                 }
+                read?;
 
-                Ok(statuses)
+                Ok(String::from_utf8_lossy(&output).into_owned())
             }
         }
     }
 
+    /// Creates a copy of the engine that writes to `writer`, for running
+    /// commands in a child of the shell.
+    fn subshell<V: Write>(&self, writer: V) -> Engine<V> {
+        Engine {
+            writer,
+            prev_dir: self.prev_dir.clone(),
+            commands: self.commands.clone(),
+            history: Box::new(DummyHistory),
+            jobs: Vec::new(),
+            vars: self.vars.clone(),
+            options: self.options.clone(),
+            args: self.args.clone(),
+            functions: self.functions.clone(),
+            last_status: self.last_status,
+            last_background_pid: self.last_background_pid,
+            pid: self.pid,
+            substitution_status: None,
+            loop_depth: self.loop_depth,
+            control: None,
+            locals: self.locals.clone(),
+            job_control: false,
+            inherit_stdout: false,
+            process_substitutions: Vec::new(),
+        }
+    }
+
     fn walk_ast(&mut self, ast: SyntaxTree) -> Result<Vec<ExitStatus>> {
-        ast.commands
-            .into_iter()
-            .fold(Ok(vec![]), |_, c| self.execute(c))
+        let mut statuses = Ok(vec![]);
+        for command in ast.commands {
+            // Only the result of the last command is returned, so errors in
+            // the ones before it have to be reported here instead.
+            if let Err(e) = statuses {
+                eprintln!("posh: {e}");
+            }
+            statuses = self.execute(command);
+
+            // The rest of the commands are skipped by `break` and `continue`
+            if self.control.is_some() {
+                break;
+            }
+        }
+        statuses
     }
 }
 
@@ -226,10 +664,9 @@ impl Engine<Stdout> {
     pub fn new() -> Self {
         let history = FileHistory::init().expect("could not initialize history");
         Self {
-            prev_dir: None,
-            writer: io::stdout(),
-            commands: path::get_cmds_from_path(),
             history: Box::new(history),
+            inherit_stdout: true,
+            ..Self::with_writer(io::stdout())
         }
     }
 }
@@ -240,6 +677,55 @@ impl Default for Engine<Stdout> {
     }
 }
 
+/// Settings that change how the shell behaves.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Whether a glob that matches no files is an error, like in fish. If not,
+    /// the glob is passed along as it is, like in POSIX shells.
+    pub failglob: bool,
+
+    /// Whether `>` refuses to overwrite existing files, which `>|` still does.
+    pub noclobber: bool,
+}
+
+impl Options {
+    /// The name and value of every option, as used by `set -o`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> {
+        [("failglob", self.failglob), ("noclobber", self.noclobber)].into_iter()
+    }
+
+    /// Sets the option called `name`, returning whether there is such an option.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "failglob" => self.failglob = value,
+            "noclobber" => self.noclobber = value,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sets the option with the single-letter `flag`, as in `set -C`, returning
+    /// whether there is such an option. The other options of POSIX shells are
+    /// accepted so that scripts using them still run, but they do nothing.
+    pub fn set_flag(&mut self, flag: char, value: bool) -> bool {
+        match flag {
+            'C' => self.noclobber = value,
+            'a' | 'b' | 'e' | 'f' | 'h' | 'm' | 'n' | 'u' | 'v' | 'x' => {}
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            failglob: true,
+            noclobber: false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ExitStatus {
     pub code: i32,
@@ -249,4 +735,195 @@ impl ExitStatus {
     pub fn from(code: i32) -> Self {
         Self { code }
     }
+
+    /// Whether the statuses of a pipeline count as a success, which is
+    /// decided by the status of its last command.
+    pub fn succeeded(statuses: &[Self]) -> bool {
+        statuses.last().is_none_or(|status| status.code == 0)
+    }
+}
+
+impl From<process::ExitStatus> for ExitStatus {
+    fn from(status: process::ExitStatus) -> Self {
+        // Processes killed by a signal have no exit code, so follow the
+        // convention of reporting them as 128 + the signal number.
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Self { code },
+            (None, Some(signal)) => Self { code: 128 + signal },
+            (None, None) => Self { code: 1 },
+        }
+    }
+}
+
+#[cfg(test)]
+impl Engine<Vec<u8>> {
+    /// Runs `line`, returning everything that has been written since the last
+    /// time this was called. Panics if running it fails.
+    pub(crate) fn output(&mut self, line: impl ToString) -> String {
+        self.execute_line(line).unwrap();
+        String::from_utf8(std::mem::take(&mut self.writer)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_streamed_through_writer() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        engine.execute_line("echo foo").unwrap();
+        engine.execute_line("echo bar | rev").unwrap();
+
+        assert_eq!("foo\nrab\n", String::from_utf8_lossy(&engine.writer));
+    }
+
+    #[test]
+    fn command_substitution() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!("a b\n", engine.output("echo $(echo a; echo b; echo; echo)"));
+        assert_eq!(
+            "foo bar\n",
+            engine.output("echo \"$(echo $(echo foo)  bar)\"")
+        );
+        assert_eq!("xy zw\n", engine.output("$(echo echo) x$(echo 'y  z')w"));
+        assert_eq!("[] end\n", engine.output("echo \"[$(true)]\" $(true) end"));
+        assert_eq!("/\n", engine.output("echo $(cd /; pwd)"));
+    }
+
+    #[test]
+    fn parameter_operators() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "file.tar file tar.gz gz 11\n",
+            engine.output("x=file.tar.gz; echo ${x%.*} ${x%%.*} ${x#*.} ${x##*.} ${#x}")
+        );
+        assert_eq!(
+            "empty set\n",
+            engine.output("y=; echo ${y-unset} ${y:-empty} ${y+set} ${y:+set} ${z:+set}")
+        );
+        assert_eq!(
+            "a b\na b\n",
+            engine.output("echo ${z:=$(echo a b)}; echo $z")
+        );
+        assert!(engine.execute_line("echo ${w:?}").is_err());
+
+        // Quotes inside of the braces, even when they are in quotes themselves
+        assert_eq!(
+            "a  b|c d|}|\n",
+            engine.output(r#"x="c d"; printf '%s|' "${u-"a  b"}" "${x#"a "}" ${u-"}"}; echo"#)
+        );
+
+        // Quoted or escaped parts of patterns only match themselves
+        assert_eq!(
+            "*b a a a*\n",
+            engine.output(r#"y='a*b'; echo ${y#"a"} ${y%"*b"} ${y%\*b} ${y%*b}"#)
+        );
+    }
+
+    #[test]
+    fn if_conditionals() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "a\n",
+            engine.output("if true; then echo a; else echo b; fi")
+        );
+        assert_eq!(
+            "c\n",
+            engine.output("if false; then echo a; elif false; then echo b; else echo c; fi")
+        );
+        assert_eq!(
+            "one\n",
+            engine.output("x=1; if [ $x = 1 ]\nthen\n echo one\nfi")
+        );
+        assert_eq!("0\n", engine.output("if false; then echo a; fi; echo $?"));
+    }
+
+    #[test]
+    fn subshells_and_groups() {
+        let mut engine = Engine::with_writer(Vec::new());
+        let path = std::env::temp_dir().join(format!("posh-groups-{}", process::id()));
+
+        assert_eq!("2\n1\n", engine.output("x=1; (x=2; echo $x); echo $x"));
+        assert_eq!("3\n3\n", engine.output("{ x=3; echo $x; }; echo $x"));
+        assert_eq!("A\nB\n", engine.output("{ echo a; echo b; } | tr a-z A-Z"));
+        assert_eq!(
+            "",
+            engine.output(format!("(echo c; echo d) >{}", path.display()))
+        );
+
+        assert_eq!("c\nd\n", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn field_splitting() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "<a><b>< a  b ><xy><c><d>\n",
+            engine.output(r#"x=\ a\ \ b\ ; e=; printf '<%s>' $x "$x" $e x${e}y ${u:-c\ d}; echo"#)
+        );
+        assert_eq!(
+            "<a><><b><c><d><e><f:g>\n",
+            engine.output(
+                r#"IFS=\ :; x=a::b\ :\ c:; printf '<%s>' $x $(echo d:e) "$(echo f:g)"; echo"#
+            )
+        );
+        assert_eq!(
+            "<a,b><a::b : c:>\n<a::b : c:>",
+            engine.output(
+                r#"IFS=,; set -- a b; printf '<%s>' "$*" $x; echo; IFS=; printf '<%s>' $x"#
+            )
+        );
+
+        // Quoted parts of words are never split, but what is assigned from them is
+        assert_eq!(
+            "<a><b><a b><c><d><c d><ab cd><$x a b><><q><a><b:>\n",
+            engine.output(
+                r#"unset IFS; x="a b"; y='c d'; printf '<%s>' $x "$x" $y "$y" a"b c"d '$x'" $x" $e"" q$e; IFS=:; set -- a b; printf '<%s>' "$@"':'$e; echo"#
+            )
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "a#b\n1\n2\n",
+            engine.output("# nothing\necho a#b # c\nfor x in 1 \\\n  2; do # d\necho $x; done")
+        );
+        assert_eq!(
+            "1\n2\n",
+            engine.output("if true; then\n\t# e\n\techo\t1\t# f\nfi\nwhile true; do\n\t# g\n\techo 2; break\ndone")
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "a|b c|$x|$x \\q|*|\n",
+            engine.output(r#"x=a\ b; printf '%s|' $x\ c \$x "\$x \q" \*; echo"#)
+        );
+        assert_eq!("a\tb!\n", engine.output(r"echo $'a\tb\x21'"));
+
+        // `$'...'` can be a part of a word, like any other quotes
+        assert_eq!(
+            "<a\tb><a\tb><a b\tc d><x\u{e9}>\n",
+            engine.output(
+                r#"x=$'a\tb'; printf '<%s>' "$x" a$'\t'b $'a b\t'"c d" 'x'$'\u00e9'; echo"#
+            )
+        );
+        assert_eq!(
+            "<a b><c>\n",
+            engine.output("IFS=$'\\n'; x=$'a b\\nc'; printf '<%s>' $x; echo")
+        );
+    }
 }
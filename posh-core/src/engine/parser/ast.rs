@@ -1,17 +1,69 @@
-use std::ops::RangeInclusive;
+use std::fmt;
+use std::io::Write;
+use std::mem;
+use std::ops::{Range, RangeInclusive};
 
+use crate::engine::{arithmetic, glob, variables, Engine};
 use crate::path::home_dir;
 use crate::{Error, Result};
 
-use super::{util, Token};
+use super::{lexer, util, Token};
 
-pub fn parse(line: impl AsRef<str>) -> SyntaxTree {
-    let tokens = super::lex(line, false);
-    parse_tokens(tokens)
+pub fn parse(line: impl AsRef<str>) -> ParseResult<SyntaxTree> {
+    let tokens = super::lexer::lex_with_spans(line, false);
+    parse_tokens(&tokens)
+}
+
+pub type ParseResult<T> = std::result::Result<T, ParseError>;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseError {
+    pub message: String,
+
+    /// The byte range of the input that caused the error.
+    pub span: Range<usize>,
+}
+
+impl ParseError {
+    fn new(message: impl ToString, span: Range<usize>) -> Self {
+        Self {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    /// Moves the span of the error, for errors found in a part of the input
+    /// that was parsed on its own.
+    fn offset(mut self, by: usize) -> Self {
+        self.span = self.span.start + by..self.span.end + by;
+        self
+    }
+
+    /// Renders `input` with the span of the error marked by carets underneath.
+    pub fn underline(&self, input: &str) -> String {
+        let column = |offset| {
+            input
+                .char_indices()
+                .take_while(|&(i, _)| i < offset)
+                .count()
+        };
+
+        let start = column(self.span.start);
+        let width = column(self.span.end).saturating_sub(start).max(1);
+
+        format!("{input}\n{}{}", " ".repeat(start), "^".repeat(width))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
 pub trait Expand: Sized {
-    fn expand(self) -> Result<Self>;
+    /// Performs the expansions, using `engine` to run any commands they contain.
+    fn expand<W: Write>(self, engine: &mut Engine<W>) -> Result<Self>;
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -37,44 +89,206 @@ impl Default for SyntaxTree {
     }
 }
 
-impl ToString for SyntaxTree {
-    fn to_string(&self) -> String {
-        self.commands
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<_>>()
-            .join("; ")
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.commands
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        )
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum CommandType {
     Single(Command),
-    Pipeline(Vec<Command>),
+
+    /// `a | b | c`, where each stage may be a compound command as well.
+    Pipeline(Vec<CommandType>),
+
+    /// `lhs && rhs`, where `rhs` only runs if `lhs` succeeded.
+    And(Box<CommandType>, Box<CommandType>),
+
+    /// `lhs || rhs`, where `rhs` only runs if `lhs` failed.
+    Or(Box<CommandType>, Box<CommandType>),
+
+    /// `cmd &`, which runs `cmd` as a job without waiting for it.
+    Background(Box<CommandType>),
+
+    /// `while condition; do ...; done`, or `until` which loops until the
+    /// condition succeeds instead.
+    While {
+        condition: SyntaxTree,
+        body: SyntaxTree,
+        until: bool,
+    },
+
+    /// `for name in words; do ...; done`, which runs the body with `name` set
+    /// to each of the fields that `words` expand to.
+    For {
+        name: String,
+
+        /// The words to loop over, or `None` for the positional parameters.
+        words: Option<Vec<Word>>,
+        body: SyntaxTree,
+    },
+
+    /// `case word in pattern) ...;; esac`, which runs the arm with the first
+    /// pattern that matches `word`.
+    Case {
+        word: Word,
+        arms: Vec<CaseArm>,
+    },
+
+    /// `name() { ...; }`, or `function name; ...; end` like in fish, which
+    /// defines a function that runs the body when called.
+    Function {
+        name: String,
+        body: SyntaxTree,
+    },
+
+    /// `if condition; then ...; elif condition; then ...; else ...; fi`
+    If {
+        /// Every condition along with what to run if it succeeds, in order.
+        branches: Vec<(SyntaxTree, SyntaxTree)>,
+        otherwise: Option<SyntaxTree>,
+    },
+
+    /// `( list )`, which runs in a child of the shell so that nothing it
+    /// changes, like variables or the working directory, is seen outside of it.
+    Subshell(SyntaxTree),
+
+    /// `{ list; }`, which groups commands without leaving the current shell.
+    Group(SyntaxTree),
+
+    /// A compound command followed by redirections, like `{ a; b; } >file`,
+    /// which apply to everything it runs.
+    Redirected {
+        command: Box<CommandType>,
+        redirects: Vec<Redirect>,
+    },
+}
+
+/// An arm of a `case` command, like `a | b) ...;;`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CaseArm {
+    pub patterns: Vec<Word>,
+    pub body: SyntaxTree,
+
+    /// Whether the arm ends with `;&`, which goes on to run the body of the
+    /// next arm too.
+    pub fallthrough: bool,
 }
 
 impl Expand for CommandType {
-    fn expand(self) -> Result<Self> {
+    fn expand<W: Write>(self, engine: &mut Engine<W>) -> Result<Self> {
         match self {
-            Self::Single(cmd) => Ok(Self::Single(cmd.expand()?)),
+            Self::Single(cmd) => Ok(Self::Single(cmd.expand(engine)?)),
             Self::Pipeline(cmds) => Ok(Self::Pipeline(
                 cmds.into_iter()
-                    .map(|c| c.expand())
+                    .map(|c| c.expand(engine))
                     .collect::<Result<Vec<_>>>()?,
             )),
+            Self::And(lhs, rhs) => Ok(Self::And(
+                Box::new(lhs.expand(engine)?),
+                Box::new(rhs.expand(engine)?),
+            )),
+            Self::Or(lhs, rhs) => Ok(Self::Or(
+                Box::new(lhs.expand(engine)?),
+                Box::new(rhs.expand(engine)?),
+            )),
+            Self::Background(cmd) => Ok(Self::Background(Box::new(cmd.expand(engine)?))),
+
+            // The parts of compound commands are expanded as they are executed
+            cmd @ (Self::If { .. }
+            | Self::While { .. }
+            | Self::For { .. }
+            | Self::Case { .. }
+            | Self::Function { .. }
+            | Self::Subshell(_)
+            | Self::Group(_)
+            | Self::Redirected { .. }) => Ok(cmd),
         }
     }
 }
 
-impl ToString for CommandType {
-    fn to_string(&self) -> String {
+impl fmt::Display for CommandType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Single(cmd) => cmd.to_string(),
-            Self::Pipeline(cmds) => cmds
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<_>>()
-                .join(" | "),
+            Self::Single(cmd) => write!(f, "{cmd}"),
+            Self::Pipeline(cmds) => write!(
+                f,
+                "{}",
+                cmds.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" | ")
+            ),
+            Self::And(lhs, rhs) => write!(f, "{lhs} && {rhs}"),
+            Self::Or(lhs, rhs) => write!(f, "{lhs} || {rhs}"),
+            Self::Background(cmd) => write!(f, "{cmd} &"),
+            Self::If {
+                branches,
+                otherwise,
+            } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{keyword} {condition}; then {body}; ")?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, "else {otherwise}; ")?;
+                }
+                write!(f, "fi")
+            }
+            Self::While {
+                condition,
+                body,
+                until,
+            } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{keyword} {condition}; do {body}; done")
+            }
+            Self::For { name, words, body } => {
+                write!(f, "for {name}")?;
+                if let Some(words) = words {
+                    write!(f, " in")?;
+                    for word in words {
+                        write!(f, " {word}")?;
+                    }
+                }
+                write!(f, "; do {body}; done")
+            }
+            Self::Case { word, arms } => {
+                write!(f, "case {word} in ")?;
+                for arm in arms {
+                    let patterns = arm
+                        .patterns
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(" | ");
+                    let end = if arm.fallthrough { ";&" } else { ";;" };
+                    write!(f, "{patterns}) {}{end} ", arm.body)?;
+                }
+                write!(f, "esac")
+            }
+            Self::Function { name, body } if body.commands.is_empty() => {
+                write!(f, "{name}() {{ }}")
+            }
+            Self::Function { name, body } => write!(f, "{name}() {{ {body}; }}"),
+            Self::Subshell(list) => write!(f, "({list})"),
+            Self::Group(list) => write!(f, "{{ {list}; }}"),
+            Self::Redirected { command, redirects } => {
+                write!(f, "{command}")?;
+                for redirect in redirects {
+                    write!(f, " {}", Meta::Redirect(redirect.clone()))?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -86,106 +300,504 @@ pub struct Command {
     pub suffixes: Vec<Meta>,
 }
 
-fn expand_meta(vars: &[(String, String)], meta: Meta) -> Result<Meta> {
+fn expand_meta<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    meta: Meta,
+) -> Result<Vec<Meta>> {
     match meta {
         Meta::Redirect(redirect) => match redirect {
             Redirect::Output {
-                from: Some(from),
+                from,
                 to,
                 append,
-            } => Ok(Meta::Redirect(Redirect::Output {
-                from: Some(expand_word(vars, from)?),
-                to: expand_word(vars, to)?,
-                append,
-            })),
-            Redirect::Output {
-                from: None,
+                clobber,
+            } => {
+                let from = match from {
+                    Some(from) => Some(expand_word_joined(engine, vars, from)?),
+                    None => None,
+                };
+
+                Ok(vec![Meta::Redirect(Redirect::Output {
+                    from,
+                    to: expand_word_joined(engine, vars, to)?,
+                    append,
+                    clobber,
+                })])
+            }
+            Redirect::Input { from, to } => Ok(vec![Meta::Redirect(Redirect::Input {
+                from,
+                to: expand_word_joined(engine, vars, to)?,
+            })]),
+            Redirect::Duplicate { from, to, output } => {
+                Ok(vec![Meta::Redirect(Redirect::Duplicate {
+                    from,
+                    to: expand_word_joined(engine, vars, to)?,
+                    output,
+                })])
+            }
+            Redirect::Both {
                 to,
                 append,
-            } => Ok(Meta::Redirect(Redirect::Output {
-                from: None,
-                to: expand_word(vars, to)?,
+                clobber,
+            } => Ok(vec![Meta::Redirect(Redirect::Both {
+                to: expand_word_joined(engine, vars, to)?,
                 append,
-            })),
-            Redirect::Input { to } => Ok(Meta::Redirect(Redirect::Input {
-                to: expand_word(vars, to)?,
-            })),
+                clobber,
+            })]),
+            Redirect::HereDocument { delimiter, body } => {
+                Ok(vec![Meta::Redirect(Redirect::HereDocument {
+                    delimiter,
+                    body: expand_word_joined(engine, vars, body)?,
+                })])
+            }
+            Redirect::HereString { word } => Ok(vec![Meta::Redirect(Redirect::HereString {
+                word: expand_word_joined(engine, vars, word)?,
+            })]),
         },
-        Meta::Word(word) => Ok(Meta::Word(expand_word(vars, word)?)),
-        Meta::Assignment(var, val) => Ok(Meta::Assignment(
-            expand_word(vars, var)?,
-            expand_word(vars, val)?,
-        )),
+        Meta::Word(word) => Ok(expand_word(engine, vars, word)?
+            .into_iter()
+            .map(Meta::Word)
+            .collect()),
+        Meta::Assignment(var, val) => Ok(vec![Meta::Assignment(
+            expand_word_joined(engine, vars, var)?,
+            expand_word_joined(engine, vars, val)?,
+        )]),
     }
 }
 
-fn expand_word(vars: &[(String, String)], mut word: Word) -> Result<Word> {
+/// Expands `word` into the fields it results in, which is usually one, but can
+/// be any number if it contains unquoted expansions.
+fn expand_word<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    word: Word,
+) -> Result<Vec<Word>> {
+    if word.expansions.is_empty() {
+        return Ok(vec![word]);
+    }
+
+    // Brace expansion comes before every other expansion, and each of the words
+    // it results in are then expanded on their own.
+    if word
+        .expansions
+        .iter()
+        .any(|e| matches!(e, Expansion::Brace { .. }))
+    {
+        let mut words = Vec::new();
+        for variant in word.brace_variants() {
+            let variant = parse_word(variant, ExpansionType::All, 0)?;
+            words.extend(expand_word(engine, vars, variant)?);
+        }
+        return Ok(words);
+    }
+
+    let split = !word.quoted;
+    Ok(expand_fields(engine, vars, word, split, false)?
+        .into_iter()
+        .map(|field| Word::new(field, Vec::new()))
+        .collect())
+}
+
+/// Expands every word in `words` into the fields they result in, such as
+/// those that a `for` loop goes over.
+pub fn expand_words<W: Write>(engine: &mut Engine<W>, words: Vec<Word>) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    for word in words {
+        fields.extend(expand_word(engine, &[], word)?.into_iter().map(|w| w.name));
+    }
+    Ok(fields)
+}
+
+/// Expands `word` into a pattern, such as that of an arm of a `case` command
+/// or of `${name#pattern}`, in which quoted words only match themselves.
+pub fn expand_pattern<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    mut word: Word,
+) -> Result<String> {
+    let quoted = word.quoted;
+
+    // Patterns keep their escapes, which make characters only match themselves
+    word.expansions
+        .retain(|e| !matches!(e, Expansion::Escape { .. }));
+
+    let pattern = expand_fields(engine, vars, word, false, true)?.concat();
+    Ok(if quoted {
+        glob::escape(&pattern)
+    } else {
+        pattern
+    })
+}
+
+/// Expands `word` into exactly one field, for places such as assignments and
+/// redirections where the results of expansions are never split.
+pub fn expand_word_joined<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    word: Word,
+) -> Result<Word> {
     if word.expansions.is_empty() {
         return Ok(word);
     }
 
-    let mut to_remove = Vec::new();
+    let fields = expand_fields(engine, vars, word, false, false)?;
+    Ok(Word::new(fields.concat(), Vec::new()))
+}
+
+/// Expands `word` into its fields, which are only split if `split` is set. If
+/// it is a `pattern`, its quoted parts are escaped so that they only match
+/// themselves.
+fn expand_fields<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    word: Word,
+    split: bool,
+    pattern: bool,
+) -> Result<Vec<String>> {
+    // Without `IFS`, fields are split on whitespace
+    let ifs = engine.vars.get("IFS").unwrap_or(" \t\n").to_string();
+
+    let mut fields = Fields::new(split, &ifs);
+    let mut last = 0;
+
+    let has_glob = word
+        .expansions
+        .iter()
+        .any(|e| matches!(e, Expansion::Glob { .. }));
+
+    for expansion in &word.expansions {
+        let range = word.byte_range(expansion);
+
+        fields.push_literal(&word.name[last..range.start]);
+        last = range.end;
 
-    for (i, expansion) in word.expansions.iter().enumerate().rev() {
         match expansion {
-            Expansion::Tilde { index } => {
-                let home = home_dir();
-                word.name.replace_range(index..=index, &home);
-                to_remove.push(i);
-            }
+            Expansion::Tilde { .. } => match engine.vars.get("HOME") {
+                Some(home) => fields.push_literal(home),
+                None => fields.push_literal(&home_dir()),
+            },
 
-            Expansion::Parameter { range, name } => {
-                for (var, val) in vars.iter() {
-                    if var == name {
-                        word.name.replace_range(range.clone(), val);
-                        to_remove.push(i);
+            // `$@` is the only parameter that can be several fields even when
+            // quoted, and `$*` is the same thing but joined into one by the
+            // first character of `IFS`.
+            Expansion::Parameter { name, .. } if name == "@" || name == "*" => {
+                let params = &engine.args[1..];
+                match (name.as_str(), split) {
+                    ("*", false) => {
+                        let separator = ifs.chars().next().map(String::from);
+                        fields.push_literal(&params.join(separator.as_deref().unwrap_or("")));
                     }
+                    _ => fields.push_fields(params),
                 }
             }
 
-            Expansion::Command { range: _, ast: _ } => {
-                return Err(Error::Unimplemented(
-                    "command expansions are not yet implemented".to_string(),
-                ))
+            Expansion::Parameter { name, .. } => {
+                let value = get_param(engine, vars, name);
+                fields.push_expansion(&value.unwrap_or_default());
             }
 
-            Expansion::Glob {
-                range: _,
-                pattern: _,
-                recursive: _,
+            Expansion::Operator {
+                name,
+                operator,
+                word,
+                ..
             } => {
-                return Err(Error::Unimplemented(
-                    "glob expansions are not yet implemented".to_string(),
-                ))
+                let value = expand_operator(engine, vars, name, *operator, word)?;
+                fields.push_expansion(&value);
+            }
+
+            Expansion::Command { ast, .. } => {
+                let output = engine.capture_output(ast.clone())?;
+                fields.push_expansion(output.trim_end_matches('\n'));
+            }
+
+            Expansion::Process { ast, output, .. } => {
+                let path = engine.substitute_process(ast.clone(), *output)?;
+                fields.push_literal(&path);
+            }
+
+            Expansion::Arithmetic { expression, .. } => {
+                let expression = expand_word_joined(engine, vars, expression.clone())?;
+                let value = arithmetic::evaluate(&expression.name, engine)?;
+                fields.push_expansion(&value.to_string());
+            }
+
+            // Brace expansions have already been performed by now, unless they
+            // are somewhere that they should not be expanded.
+            Expansion::Brace { .. } => fields.push_literal(&word.name[range]),
+
+            // Globs are matched once the fields are known, since they can
+            // contain the results of other expansions.
+            Expansion::Glob { .. } => fields.push_literal(&word.name[range]),
+
+            // An escaped newline continues the line instead, so it disappears
+            Expansion::Escape { .. } => match &word.name[range.start + 1..range.end] {
+                "\n" => {}
+                escaped if has_glob => fields.push_literal(&glob::escape(escaped)),
+                escaped => fields.push_literal(escaped),
+            },
+
+            Expansion::Quoted { word, .. } => {
+                let mut values = expand_fields(engine, vars, word.clone(), false, false)?;
+                if pattern || split && has_glob {
+                    values = values.iter().map(|value| glob::escape(value)).collect();
+                }
+                fields.push_quoted(&values);
+            }
+        }
+    }
+
+    fields.push_literal(&word.name[last..]);
+
+    if !split || !has_glob {
+        return Ok(fields.finish());
+    }
+
+    let mut paths = Vec::new();
+    for field in fields.finish() {
+        if !glob::is_pattern(&field) {
+            paths.push(field);
+            continue;
+        }
+
+        let matches = glob::expand(&field);
+        if !matches.is_empty() {
+            paths.extend(matches);
+        } else if engine.options.failglob {
+            return Err(Error::NoMatch(field));
+        } else {
+            paths.push(field);
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Looks up a parameter, preferring the assignments in front of the command.
+fn get_param<W: Write>(
+    engine: &Engine<W>,
+    vars: &[(String, String)],
+    name: &str,
+) -> Option<String> {
+    match vars.iter().find(|(var, _)| var == name) {
+        Some((_, value)) => Some(value.clone()),
+        None => engine.get_param(name),
+    }
+}
+
+/// Expands `${name}` with `operator` applied to it. The word is only expanded
+/// if it is needed, since it may have side effects.
+fn expand_operator<W: Write>(
+    engine: &mut Engine<W>,
+    vars: &[(String, String)],
+    name: &str,
+    operator: ParameterOperator,
+    word: &Word,
+) -> Result<String> {
+    let value = get_param(engine, vars, name);
+
+    let is_unset = |colon: bool| match &value {
+        Some(value) => colon && value.is_empty(),
+        None => true,
+    };
+
+    let expand = |engine: &mut Engine<W>| {
+        expand_word_joined(engine, vars, word.clone()).map(|word| word.name)
+    };
+
+    match operator {
+        ParameterOperator::Default { colon } if is_unset(colon) => expand(engine),
+
+        ParameterOperator::Assign { colon } if is_unset(colon) => {
+            let word = expand(engine)?;
+            if !variables::is_valid_name(name) {
+                return Err(Error::BadAssignment(name.to_string()));
+            }
+            engine.set_var(name, &word)?;
+            Ok(word)
+        }
+
+        ParameterOperator::Error { colon } if is_unset(colon) => {
+            let message = match expand(engine)? {
+                message if message.is_empty() => "parameter not set".to_string(),
+                message => message,
+            };
+            Err(Error::NotSet(name.to_string(), message))
+        }
+
+        ParameterOperator::Alternative { colon } if is_unset(colon) => Ok(String::new()),
+        ParameterOperator::Alternative { .. } => expand(engine),
+
+        ParameterOperator::RemovePrefix { longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(engine, vars, word.clone())?;
+
+            let mut ends = value.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+            ends.push(value.len());
+            if longest {
+                ends.reverse();
+            }
+
+            Ok(ends
+                .into_iter()
+                .find(|&end| glob::matches(&pattern, &value[..end]))
+                .map_or(value.clone(), |end| value[end..].to_string()))
+        }
+
+        ParameterOperator::RemoveSuffix { longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(engine, vars, word.clone())?;
+
+            let mut starts = value.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+            starts.push(value.len());
+            if !longest {
+                starts.reverse();
+            }
+
+            Ok(starts
+                .into_iter()
+                .find(|&start| glob::matches(&pattern, &value[start..]))
+                .map_or(value.clone(), |start| value[..start].to_string()))
+        }
+
+        // The length of `$@` or `$*` is the number of positional parameters
+        ParameterOperator::Length if name == "@" || name == "*" => {
+            Ok(get_param(engine, vars, "#").unwrap_or_default())
+        }
+        ParameterOperator::Length => Ok(value.unwrap_or_default().chars().count().to_string()),
+
+        ParameterOperator::Default { .. }
+        | ParameterOperator::Assign { .. }
+        | ParameterOperator::Error { .. } => Ok(value.unwrap_or_default()),
+    }
+}
+
+/// Collects the fields of a word as it is being expanded.
+struct Fields<'a> {
+    fields: Vec<String>,
+    current: Option<String>,
+    split: bool,
+
+    /// The characters that the results of expansions are split on.
+    ifs: &'a str,
+
+    /// Whether the latest field was ended by whitespace in `ifs`, so that any
+    /// other character of `ifs` right after it belongs to the same delimiter.
+    after_whitespace: bool,
+
+    /// Whether a `"$@"` without any positional parameters was pushed, which
+    /// makes an otherwise empty quoted word disappear instead.
+    empty_fields: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn new(split: bool, ifs: &'a str) -> Self {
+        Self {
+            fields: Vec::new(),
+            current: None,
+            split,
+            ifs,
+            after_whitespace: false,
+            empty_fields: false,
+        }
+    }
+
+    /// Adds text that is never split, such as the parts of the word that are
+    /// not expansions.
+    fn push_literal(&mut self, s: &str) {
+        if !s.is_empty() || !self.split {
+            self.current.get_or_insert_with(String::new).push_str(s);
+            self.after_whitespace = false;
+        }
+    }
+
+    /// Adds the result of an expansion, which is split on the characters in
+    /// `IFS` unless the word is quoted. Whitespace in `IFS` is only ever a
+    /// part of a delimiter, while the other characters each end a field, even
+    /// if it is empty.
+    fn push_expansion(&mut self, s: &str) {
+        if !self.split {
+            return self.push_literal(s);
+        }
+
+        for c in s.chars() {
+            if !self.ifs.contains(c) {
+                self.current.get_or_insert_with(String::new).push(c);
+                self.after_whitespace = false;
+            } else if " \t\n".contains(c) {
+                if let Some(field) = self.current.take() {
+                    self.fields.push(field);
+                    self.after_whitespace = true;
+                }
+            } else if !mem::take(&mut self.after_whitespace) {
+                self.fields.push(self.current.take().unwrap_or_default());
+            }
+        }
+    }
+
+    /// Adds the fields of a quoted part of the word, which are never split, and
+    /// are kept even if they are empty. There is only ever more than one of
+    /// them if the quoted part is a `"$@"`.
+    fn push_quoted(&mut self, values: &[String]) {
+        self.empty_fields |= values.is_empty();
+
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.fields.extend(self.current.take());
             }
+            self.current.get_or_insert_with(String::new).push_str(value);
+            self.after_whitespace = false;
         }
     }
 
-    to_remove.sort();
-    to_remove.dedup();
-    for i in to_remove.into_iter().rev() {
-        word.expansions.remove(i);
+    /// Adds values that each become a field of their own, with the first and
+    /// last being joined with whatever comes before and after them.
+    fn push_fields(&mut self, values: &[String]) {
+        self.empty_fields |= values.is_empty();
+
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.fields.extend(self.current.take());
+                self.after_whitespace = false;
+            }
+            self.push_expansion(value);
+        }
     }
 
-    Ok(word)
+    fn finish(mut self) -> Vec<String> {
+        if self.empty_fields && self.fields.is_empty() && self.current.as_deref() == Some("") {
+            return Vec::new();
+        }
+
+        if let Some(field) = self.current.take() {
+            self.fields.push(field);
+        }
+        self.fields
+    }
 }
 
 impl Expand for Command {
-    fn expand(mut self) -> Result<Self> {
+    fn expand<W: Write>(mut self, engine: &mut Engine<W>) -> Result<Self> {
+        // The prefixes are expanded first, since the assignments among them
+        // are visible to the rest of the command.
+        let mut prefixes = Vec::new();
+        for meta in self.prefixes {
+            prefixes.extend(expand_meta(engine, &[], meta)?);
+        }
+        self.prefixes = prefixes;
+
         let vars = self.vars();
-        self.name = expand_word(&vars, self.name)?;
 
-        self.prefixes = self
-            .prefixes
-            .into_iter()
-            .map(|m| expand_meta(&vars, m))
-            .collect::<Result<Vec<_>>>()?;
+        // If the name expands to several fields, the rest of them are arguments.
+        let mut fields = expand_word(engine, &vars, self.name)?.into_iter();
+        self.name = fields.next().unwrap_or_else(|| Word::new("", Vec::new()));
 
-        self.suffixes = self
-            .suffixes
-            .into_iter()
-            .map(|m| expand_meta(&vars, m))
-            .collect::<Result<Vec<_>>>()?;
+        let mut suffixes = fields.map(Meta::Word).collect::<Vec<_>>();
+        for meta in self.suffixes {
+            suffixes.extend(expand_meta(engine, &vars, meta)?);
+        }
+        self.suffixes = suffixes;
 
         Ok(self)
     }
@@ -196,21 +808,13 @@ impl Command {
         &self.name.name
     }
 
+    /// The variables assigned by the prefixes of the command, which only apply
+    /// to the command itself (unless it has no name).
     pub fn vars(&self) -> Vec<(String, String)> {
-        let mut vars = Vec::new();
-
-        for (var, val) in std::env::vars() {
-            vars.push((var, val));
-        }
+        let mut vars: Vec<(String, String)> = Vec::new();
 
-        for meta in self.prefixes.clone().into_iter().filter_map(|m| {
-            if let Meta::Assignment(_, _) = m {
-                Some(m)
-            } else {
-                None
-            }
-        }) {
-            if let Ok(Meta::Assignment(var, val)) = expand_meta(&vars, meta) {
+        for meta in &self.prefixes {
+            if let Meta::Assignment(var, val) = meta {
                 if let Some(index) = vars.iter().position(|(v, _)| v == &var.name) {
                     vars.remove(index);
                 }
@@ -221,37 +825,16 @@ impl Command {
         vars
     }
 
-    pub fn redirections(&self) -> (Option<Redirect>, Option<Redirect>, Option<Redirect>) {
-        let mut stdin_redirect = None;
-        let mut stdout_redirect = None;
-        let mut stderr_redirect = None;
-
-        for meta in self
-            .prefixes
+    /// The redirections of the command, in the order that they are applied.
+    pub fn redirects(&self) -> Vec<Redirect> {
+        self.prefixes
             .iter()
-            .cloned()
-            .chain(self.suffixes.iter().cloned())
-        {
-            if let Meta::Redirect(redirect) = meta {
-                match redirect {
-                    Redirect::Output { from: None, .. } => stdout_redirect = Some(redirect.clone()),
-
-                    Redirect::Output {
-                        from: Some(ref s), ..
-                    } if s.name == "1" => stdout_redirect = Some(redirect.clone()),
-
-                    Redirect::Output {
-                        from: Some(ref s), ..
-                    } if s.name == "2" => stderr_redirect = Some(redirect.clone()),
-
-                    Redirect::Input { .. } => stdin_redirect = Some(redirect.clone()),
-
-                    _ => {}
-                }
-            }
-        }
-
-        (stdin_redirect, stdout_redirect, stderr_redirect)
+            .chain(&self.suffixes)
+            .filter_map(|meta| match meta {
+                Meta::Redirect(redirect) => Some(redirect.clone()),
+                _ => None,
+            })
+            .collect()
     }
 
     pub fn args(&self) -> Vec<String> {
@@ -268,8 +851,8 @@ impl Command {
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let prefixes = self
             .prefixes
             .iter()
@@ -284,15 +867,16 @@ impl ToString for Command {
             .collect::<Vec<_>>()
             .join(" ");
 
-        format!(
-            "{}{}{}",
-            if prefixes.is_empty() {
-                "".to_string()
-            } else {
-                prefixes + " "
-            },
-            self.name.to_string() + if suffixes.is_empty() { "" } else { " " },
-            suffixes,
+        let name = self.name.to_string();
+
+        write!(
+            f,
+            "{}",
+            [prefixes, name, suffixes]
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
         )
     }
 }
@@ -301,6 +885,10 @@ impl ToString for Command {
 pub struct Word {
     pub name: String,
     pub expansions: Vec<Expansion>,
+
+    /// Whether the word was in double quotes, which keeps the results of its
+    /// expansions from being split into several fields.
+    pub quoted: bool,
 }
 
 impl Word {
@@ -308,13 +896,62 @@ impl Word {
         Self {
             name: name.to_string(),
             expansions,
+            quoted: false,
+        }
+    }
+
+    /// The byte range of `name` that `expansion` covers, since the ranges
+    /// of expansions count characters rather than bytes.
+    fn byte_range(&self, expansion: &Expansion) -> Range<usize> {
+        let byte_index = |index: usize| {
+            self.name
+                .char_indices()
+                .nth(index)
+                .map_or(self.name.len(), |(i, _)| i)
+        };
+
+        let (start, end) = match expansion {
+            Expansion::Parameter { range, .. }
+            | Expansion::Command { range, .. }
+            | Expansion::Process { range, .. }
+            | Expansion::Escape { range }
+            | Expansion::Glob { range, .. }
+            | Expansion::Brace { range, .. }
+            | Expansion::Arithmetic { range, .. }
+            | Expansion::Operator { range, .. }
+            | Expansion::Quoted { range, .. } => (*range.start(), *range.end()),
+            Expansion::Tilde { index } => (*index, *index),
+        };
+
+        byte_index(start)..byte_index(end + 1)
+    }
+
+    /// Every combination of the alternatives of the brace expansions in the word.
+    fn brace_variants(&self) -> Vec<String> {
+        let mut variants = vec![String::new()];
+        let mut last = 0;
+
+        for expansion in &self.expansions {
+            if let Expansion::Brace { alternatives, .. } = expansion {
+                let range = self.byte_range(expansion);
+                let literal = &self.name[last..range.start];
+                last = range.end;
+
+                variants = variants
+                    .iter()
+                    .flat_map(|v| alternatives.iter().map(move |a| format!("{v}{literal}{a}")))
+                    .collect();
+            }
         }
+
+        let rest = &self.name[last..];
+        variants.into_iter().map(|v| v + rest).collect()
     }
 }
 
-impl ToString for Word {
-    fn to_string(&self) -> String {
-        self.name.clone()
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
     }
 }
 
@@ -325,48 +962,88 @@ pub enum Meta {
     Assignment(Word, Word),
 }
 
-impl ToString for Meta {
-    fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for Meta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
             Self::Word(word) => word.name.clone(),
             Self::Redirect(redirect) => match redirect {
-                Redirect::Input { to } => format!("<{}", to.name),
-                Redirect::Output {
-                    from: None,
-                    to,
-                    append: false,
-                } => format!(">{}", to.name),
-                Redirect::Output {
-                    from: None,
-                    to,
-                    append: true,
-                } => format!(">>{}", to.name),
-                Redirect::Output {
-                    from: Some(from),
+                Redirect::Input { from, to } => format!("{}<{}", fd_name(from), to.name),
+                Redirect::Duplicate { from, to, output } => {
+                    let operator = if *output { ">&" } else { "<&" };
+                    format!("{}{operator}{}", fd_name(from), to.name)
+                }
+                Redirect::Both {
                     to,
-                    append: false,
-                } => format!("{}>{}", from.name, to.name),
+                    append,
+                    clobber,
+                } => format!("&{}{}", output_operator(*append, *clobber), to.name),
                 Redirect::Output {
-                    from: Some(from),
+                    from,
                     to,
-                    append: true,
-                } => format!("{}>>{}", from.name, to.name),
+                    append,
+                    clobber,
+                } => format!(
+                    "{}{}{}",
+                    fd_name(from),
+                    output_operator(*append, *clobber),
+                    to.name
+                ),
+                Redirect::HereDocument { delimiter, .. } => format!("<<{delimiter}"),
+                Redirect::HereString { word } => format!("<<<{}", word.name),
             },
             Self::Assignment(var, val) => format!("{}={}", var.name, val.name),
-        }
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The file descriptor a redirection applies to, as written.
+fn fd_name(fd: &Option<Word>) -> &str {
+    fd.as_ref().map_or("", |fd| &fd.name)
+}
+
+/// `>`, `>>` or `>|`.
+fn output_operator(append: bool, clobber: bool) -> &'static str {
+    match (append, clobber) {
+        (true, _) => ">>",
+        (false, true) => ">|",
+        (false, false) => ">",
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Redirect {
+    /// `n>file` or `n>>file`, where `n` is stdout if left out. `n>|file` is
+    /// `clobber`, which overwrites the file even if `noclobber` is set.
     Output {
         from: Option<Word>,
         to: Word,
         append: bool,
+        clobber: bool,
     },
-    Input {
+
+    /// `n<file`, where `n` is stdin if left out.
+    Input { from: Option<Word>, to: Word },
+
+    /// `n>&m` or `n<&m`, which makes `n` a copy of `m`, or closes it if `m` is `-`.
+    Duplicate {
+        from: Option<Word>,
+        to: Word,
+        output: bool,
+    },
+
+    /// `&>file` or `&>>file`, which redirects both stdout and stderr to `file`.
+    Both {
         to: Word,
+        append: bool,
+        clobber: bool,
     },
+
+    /// `<<delimiter`, whose body is expanded unless the delimiter was quoted.
+    HereDocument { delimiter: String, body: Word },
+
+    /// `<<<word`, which is fed to stdin followed by a newline.
+    HereString { word: Word },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -381,6 +1058,14 @@ pub enum Expansion {
         ast: SyntaxTree,
     },
 
+    /// `<(ast)`, or `>(ast)` if `output` is set, which is replaced by the path
+    /// of a pipe that the output of `ast` can be read from, or its input written to.
+    Process {
+        range: RangeInclusive<usize>,
+        ast: SyntaxTree,
+        output: bool,
+    },
+
     Glob {
         range: RangeInclusive<usize>,
         pattern: String,
@@ -390,373 +1075,2553 @@ pub enum Expansion {
     Tilde {
         index: usize,
     },
-}
 
-fn parse_tokens(tokens: Vec<Token>) -> SyntaxTree {
-    // Split tokens by semicolons to get list of commands,
-    // then each command by pipe to get pipeline in command
-    let commands = tokens
-        .split(|t| matches!(t, Token::Semicolon))
-        .map(|tokens| {
-            tokens
-                .split(|t| matches!(t, Token::Pipe))
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
+    /// `\c`, which is just `c` without any special meaning it would have had.
+    Escape {
+        range: RangeInclusive<usize>,
+    },
 
-    let mut ast = SyntaxTree {
-        commands: Vec::with_capacity(commands.len()),
-    };
+    /// `{a,b}` or `{1..10}`, along with what it expands to.
+    Brace {
+        range: RangeInclusive<usize>,
+        alternatives: Vec<String>,
+    },
 
-    for pipeline in commands {
-        match &pipeline[..] {
-            &[cmd] if !cmd.is_empty() => {
-                if let Some(cmd) = parse_command(cmd) {
-                    ast.add_command(CommandType::Single(cmd));
-                } else {
-                    panic!("could not parse command");
-                    // FIXME: syntax error?
-                }
+    /// `$((expression))`, where the expression is expanded before it is evaluated.
+    Arithmetic {
+        range: RangeInclusive<usize>,
+        expression: Word,
+    },
+
+    /// `${name}` with an operator, like `${name:-word}` or `${#name}`.
+    Operator {
+        range: RangeInclusive<usize>,
+        name: String,
+        operator: ParameterOperator,
+        word: Word,
+    },
+
+    /// `'word'`, `"word"` or `$'word'` as a part of a larger word, like in
+    /// `a="b c"`. The quotes are not a part of `word`, and neither are the
+    /// escapes of a `$'word'`.
+    Quoted {
+        range: RangeInclusive<usize>,
+        word: Word,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ParameterOperator {
+    /// `${name:-word}` uses `word` if the parameter is unset or empty.
+    Default { colon: bool },
+
+    /// `${name:=word}` also assigns `word` to the parameter.
+    Assign { colon: bool },
+
+    /// `${name:?word}` fails with `word` as the message.
+    Error { colon: bool },
+
+    /// `${name:+word}` uses `word` only if the parameter is set and not empty.
+    Alternative { colon: bool },
+
+    /// `${name#word}`, or `${name##word}` for the longest match.
+    RemovePrefix { longest: bool },
+
+    /// `${name%word}`, or `${name%%word}` for the longest match.
+    RemoveSuffix { longest: bool },
+
+    /// `${#name}`
+    Length,
+}
+
+/// The operators that can follow the name in `${name...}`. Without the colon,
+/// the first four only check whether the parameter is unset, not if it is empty.
+const PARAMETER_OPERATORS: [(&str, ParameterOperator); 12] = [
+    (":-", ParameterOperator::Default { colon: true }),
+    (":=", ParameterOperator::Assign { colon: true }),
+    (":?", ParameterOperator::Error { colon: true }),
+    (":+", ParameterOperator::Alternative { colon: true }),
+    ("-", ParameterOperator::Default { colon: false }),
+    ("=", ParameterOperator::Assign { colon: false }),
+    ("?", ParameterOperator::Error { colon: false }),
+    ("+", ParameterOperator::Alternative { colon: false }),
+    ("##", ParameterOperator::RemovePrefix { longest: true }),
+    ("#", ParameterOperator::RemovePrefix { longest: false }),
+    ("%%", ParameterOperator::RemoveSuffix { longest: true }),
+    ("%", ParameterOperator::RemoveSuffix { longest: false }),
+];
+
+/// The keywords that start a compound command, and those that end one.
+const OPENING_KEYWORDS: [&str; 6] = ["if", "while", "until", "for", "case", "function"];
+const CLOSING_KEYWORDS: [&str; 4] = ["fi", "done", "esac", "end"];
+
+/// Keeps track of the compound commands, subshells and groups that are open
+/// while going through tokens, innermost last.
+#[derive(Default)]
+struct Nesting<'a>(Vec<&'a str>);
+
+impl<'a> Nesting<'a> {
+    /// Opens or closes whatever `token` opens or closes, returning whether it did.
+    fn update(&mut self, token: &'a Token) -> bool {
+        match token {
+            Token::Keyword(k) if OPENING_KEYWORDS.contains(&k.as_str()) => self.0.push(k),
+            Token::LParen | Token::LBrace => self.0.push(token.keyword()),
+
+            // The `)` after the patterns of a `case` arm closes nothing
+            Token::RParen if self.0.last() == Some(&"case") => return false,
+
+            Token::Keyword(k) if CLOSING_KEYWORDS.contains(&k.as_str()) => {
+                self.0.pop();
             }
+            Token::RParen | Token::RBrace => {
+                self.0.pop();
+            }
+
+            _ => return false,
+        }
+        true
+    }
 
-            cmds => {
-                let mut commands = Vec::new();
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
-                for &command in cmds {
-                    if command.is_empty() {
-                        continue;
-                    }
+/// Whether each of `tokens` is outside of every compound command, like the last
+/// `;` in `if true; then ls; fi; echo`. Only those tokens separate commands.
+fn top_level(tokens: &[(Token, Range<usize>)]) -> Vec<bool> {
+    let mut nesting = Nesting::default();
 
-                    if let Some(cmd) = parse_command(command) {
-                        commands.push(cmd);
-                    } else {
-                        // FIXME: syntax error?
-                        panic!("could not parse command");
-                    }
-                }
+    tokens
+        .iter()
+        .map(|(token, _)| !nesting.update(token) && nesting.is_empty())
+        .collect()
+}
 
-                if !commands.is_empty() {
-                    ast.add_command(CommandType::Pipeline(commands));
+fn parse_tokens(tokens: &[(Token, Range<usize>)]) -> ParseResult<SyntaxTree> {
+    // Split tokens by semicolons, ampersands and newlines to get list of commands,
+    // then each command by AND/OR, and finally by pipe to get pipeline in command
+    let top_level = top_level(tokens);
+    let mut lists = Vec::new();
+    let mut start = 0;
+
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        let separates = top_level[i]
+            && match token {
+                Token::Semicolon | Token::Ampersand => true,
+
+                // A line can be continued after `&&`, `||` and `|`, and the
+                // body of a function can start on the line after its name.
+                Token::Newline => {
+                    let continues = matches!(
+                        i.checked_sub(1).map(|i| &tokens[i].0),
+                        Some(Token::And | Token::Or | Token::Pipe | Token::Newline)
+                    );
+                    let after_name = i >= 3 && is_function_definition(&tokens[i - 3..i]);
+                    !continues && !after_name
                 }
-            }
+
+                _ => false,
+            };
+
+        if separates {
+            lists.push(&tokens[start..=i]);
+            start = i + 1;
+        }
+    }
+    lists.push(&tokens[start..]);
+
+    let mut ast = SyntaxTree::new();
+
+    for list in lists {
+        let (list, background) = match list.split_last() {
+            Some(((Token::Ampersand, _), list)) => (list, true),
+            Some(((Token::Semicolon | Token::Newline, _), list)) => (list, false),
+            _ => (list, false),
         };
+
+        if list.iter().all(|(t, _)| matches!(t, Token::Newline)) {
+            continue;
+        }
+
+        let cmd = parse_and_or(list)?;
+
+        if background {
+            ast.add_command(CommandType::Background(Box::new(cmd)));
+        } else {
+            ast.add_command(cmd);
+        }
+    }
+
+    Ok(ast)
+}
+
+fn parse_and_or(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    // `&&` and `||` have equal precedence and are left-associative,
+    // so the rightmost operator ends up at the root of the tree.
+    let top_level = top_level(tokens);
+    let Some(i) = tokens
+        .iter()
+        .zip(top_level)
+        .rposition(|((t, _), top)| top && matches!(t, Token::And | Token::Or))
+    else {
+        return parse_pipeline(tokens);
+    };
+
+    let (operator, span) = &tokens[i];
+    let operator = if let Token::And = operator {
+        "&&"
+    } else {
+        "||"
+    };
+
+    let (lhs, rhs) = (&tokens[..i], &tokens[i + 1..]);
+
+    if lhs.is_empty() {
+        return Err(ParseError::new(
+            format!("expected a command before `{operator}`"),
+            span.clone(),
+        ));
     }
 
-    ast
+    if rhs.is_empty() {
+        return Err(ParseError::new(
+            format!("expected a command after `{operator}`"),
+            span.clone(),
+        ));
+    }
+
+    let lhs = Box::new(parse_and_or(lhs)?);
+    let rhs = Box::new(parse_pipeline(rhs)?);
+
+    if operator == "&&" {
+        Ok(CommandType::And(lhs, rhs))
+    } else {
+        Ok(CommandType::Or(lhs, rhs))
+    }
 }
 
-fn parse_command(tokens: &[Token]) -> Option<Command> {
-    let tokens = tokens.iter().peekable();
+fn parse_pipeline(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let pipes = tokens
+        .iter()
+        .zip(top_level(tokens))
+        .enumerate()
+        .filter(|(_, ((t, _), top))| *top && matches!(t, Token::Pipe))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let mut commands = Vec::with_capacity(pipes.len() + 1);
+    let mut start = 0;
+
+    for (i, &end) in pipes.iter().chain([&tokens.len()]).enumerate() {
+        let command = &tokens[start..end];
+        start = end + 1;
+
+        if command.iter().all(|(t, _)| matches!(t, Token::Newline)) {
+            return Err(match pipes.get(i) {
+                Some(&pipe) => {
+                    ParseError::new("expected a command before `|`", tokens[pipe].1.clone())
+                }
+                None => ParseError::new(
+                    "expected a command after `|`",
+                    tokens[pipes[i - 1]].1.clone(),
+                ),
+            });
+        }
+
+        let start = command
+            .iter()
+            .position(|(t, _)| *t != Token::Newline)
+            .unwrap_or(0);
+        if matches!(
+            command[start],
+            (Token::Keyword(_) | Token::LParen | Token::LBrace, _)
+        ) || is_function_definition(&command[start..])
+        {
+            commands.push(parse_compound(command)?);
+        } else {
+            commands.push(CommandType::Single(parse_command(command)?));
+        }
+    }
+
+    if commands.len() == 1 {
+        Ok(commands.remove(0))
+    } else {
+        Ok(CommandType::Pipeline(commands))
+    }
+}
 
+fn parse_command(tokens: &[(Token, Range<usize>)]) -> ParseResult<Command> {
     let mut name = None;
     let mut prefixes = Vec::new();
     let mut suffixes = Vec::new();
 
-    for token in tokens {
+    for (token, span) in tokens {
         match token {
-            token @ (Token::String(_)
+            Token::String(_)
             | Token::SingleQuotedString(_, _)
-            | Token::DoubleQuotedString(_, _)) => match parse_meta(token, name.is_none()) {
-                Some(word @ Meta::Word(_)) => {
+            | Token::DoubleQuotedString(_, _)
+            | Token::AnsiCString(_, _) => match parse_meta(token, span, name.is_none())? {
+                Meta::Word(word) => {
                     if name.is_none() {
                         name = Some(word);
                     } else {
-                        suffixes.push(word);
+                        suffixes.push(Meta::Word(word));
                     }
                 }
-                Some(Meta::Assignment(dest, var)) => {
+                Meta::Assignment(dest, var) => {
                     if name.is_none() {
                         prefixes.push(Meta::Assignment(dest, var));
                     } else {
                         suffixes.push(Meta::Assignment(dest, var));
                     }
                 }
-                Some(meta) => panic!("disallowed type: {:?}", meta),
-                None => {}
+                Meta::Redirect(_) => unreachable!("strings are never parsed as redirections"),
             },
 
-            token @ Token::RedirectOutput(_, _, _, _) => {
-                if let Some(redirect) = parse_meta(token, name.is_none()) {
-                    match name {
-                        None => prefixes.push(redirect),
-                        Some(_) => suffixes.push(redirect),
-                    }
+            Token::RedirectOutput(_, _, _, _, _)
+            | Token::RedirectInput(_, _)
+            | Token::HereDocument { .. }
+            | Token::HereString(_) => {
+                let redirect = parse_meta(token, span, name.is_none())?;
+                match name {
+                    None => prefixes.push(redirect),
+                    Some(_) => suffixes.push(redirect),
                 }
             }
 
-            Token::RedirectInput(_) => {
-                if let Some(redirect) = parse_meta(token, name.is_none()) {
-                    match name {
-                        None => prefixes.push(redirect),
-                        Some(_) => suffixes.push(redirect),
-                    }
-                }
+            Token::LParen
+            | Token::RParen
+            | Token::LBrace
+            | Token::RBrace
+            | Token::DoubleSemicolon
+            | Token::SemicolonAmpersand => {
+                let symbol = match token {
+                    Token::DoubleSemicolon => ";;",
+                    Token::SemicolonAmpersand => ";&",
+                    _ => token.keyword(),
+                };
+                return Err(ParseError::new(
+                    format!("unexpected `{symbol}`"),
+                    span.clone(),
+                ));
             }
 
-            // Token::LParen => todo!("( subshells are not yet implemented"),
-            // Token::RParen => todo!(") subshells are not yet implemented"),
-
-            // Token::LBrace => todo!("{{ command grouping is not yet implemented"),
-            // Token::RBrace => todo!("}} command grouping is not yet implemented"),
-            Token::And => todo!("AND is not yet implemented"),
-            Token::Or => todo!("OR is not yet implemented"),
+            Token::And => unreachable!("ANDs should have been found already"),
+            Token::Or => unreachable!("ORs should have been found already"),
 
-            Token::Space => {}
+            Token::Space | Token::Comment(_) | Token::Newline => {}
 
-            Token::Ampersand => todo!("asynchronous execution is not yet implemented"),
+            Token::Keyword(keyword) => {
+                return Err(ParseError::new(
+                    format!("unexpected `{keyword}`"),
+                    span.clone(),
+                ))
+            }
 
+            Token::Ampersand => unreachable!("ampersands should have been found already"),
             Token::Semicolon => unreachable!("semicolons should have been found already"),
             Token::Pipe => unreachable!("pipes should have been found already"),
         }
     }
 
-    if let Some(Meta::Word(name)) = name {
-        Some(Command {
+    match name {
+        Some(name) => Ok(Command {
             name,
             prefixes,
             suffixes,
+        }),
+
+        // Assignments on their own set shell variables
+        None if prefixes.iter().any(|m| matches!(m, Meta::Assignment(_, _))) => Ok(Command {
+            name: Word::new("", Vec::new()),
+            prefixes,
+            suffixes,
+        }),
+
+        None => {
+            let start = tokens.first().map_or(0, |(_, span)| span.start);
+            let end = tokens.last().map_or(0, |(_, span)| span.end);
+            Err(ParseError::new("expected a command", start..end))
+        }
+    }
+}
+
+/// Parses a compound command, which `tokens` start with the keyword (or
+/// parenthesis or brace) of, along with any redirections after it.
+fn parse_compound(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let tokens = match tokens.iter().position(|(t, _)| *t != Token::Newline) {
+        Some(start) => &tokens[start..],
+        None => tokens,
+    };
+
+    let end = tokens
+        .iter()
+        .rposition(|(t, _)| {
+            !matches!(
+                t,
+                Token::RedirectOutput(_, _, _, _, _)
+                    | Token::RedirectInput(_, _)
+                    | Token::HereDocument { .. }
+                    | Token::HereString(_)
+                    | Token::Newline
+            )
         })
+        .map_or(0, |i| i + 1);
+    let (tokens, rest) = tokens.split_at(end);
+
+    let command = match &tokens[0] {
+        _ if is_function_definition(tokens) => parse_function_definition(tokens),
+        (Token::Keyword(k), _) if k == "function" => parse_function(tokens),
+        (Token::Keyword(k), _) if k == "if" => parse_if(tokens),
+        (Token::Keyword(k), _) if k == "while" || k == "until" => parse_while(tokens),
+        (Token::Keyword(k), _) if k == "for" => parse_for(tokens),
+        (Token::Keyword(k), _) if k == "case" => parse_case(tokens),
+        (Token::LParen, _) => parse_subshell(tokens),
+        (Token::LBrace, _) => parse_group(tokens),
+        (Token::Keyword(k), span) => {
+            Err(ParseError::new(format!("unexpected `{k}`"), span.clone()))
+        }
+        (_, span) => Err(ParseError::new("expected a keyword", span.clone())),
+    }?;
+
+    let mut redirects = Vec::new();
+    for (token, span) in rest {
+        if let Meta::Redirect(redirect) = parse_meta(token, span, false)? {
+            redirects.push(redirect);
+        }
+    }
+
+    if redirects.is_empty() {
+        Ok(command)
     } else {
-        eprintln!("{name:?}");
-        None
+        Ok(CommandType::Redirected {
+            command: Box::new(command),
+            redirects,
+        })
     }
 }
 
-enum ExpansionType {
-    All,
-    VariablesAndCommands,
-    None,
+/// Finds the `)` or `}` that closes the `(` or `{` at the start of `tokens`.
+fn find_closing(tokens: &[(Token, Range<usize>)]) -> Option<usize> {
+    let mut nesting = Nesting::default();
+    tokens
+        .iter()
+        .position(|(token, _)| nesting.update(token) && nesting.is_empty())
 }
 
-fn parse_word(s: impl AsRef<str>, expand: ExpansionType) -> Word {
-    if let ExpansionType::None = expand {
-        return Word::new(s.as_ref(), Vec::new());
+/// Parses `( list )`.
+fn parse_subshell(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let Some(end) = find_closing(tokens) else {
+        return Err(ParseError::new(
+            "expected `)` to end `(`",
+            tokens[0].1.clone(),
+        ));
+    };
+
+    let list = parse_body(&tokens[..end], 0)?;
+    expect_end(tokens, end)?;
+
+    Ok(CommandType::Subshell(list))
+}
+
+/// Parses `{ list; }`.
+fn parse_group(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let Some(end) = find_closing(tokens) else {
+        return Err(ParseError::new(
+            "expected `}` to end `{`",
+            tokens[0].1.clone(),
+        ));
+    };
+
+    let list = parse_body(&tokens[..end], 0)?;
+    expect_end(tokens, end)?;
+
+    Ok(CommandType::Group(list))
+}
+
+/// Finds the keywords in `names` that belong to the compound command at the
+/// start of `tokens`, and not to any other compound command nested in it,
+/// stopping at `last`.
+fn find_keywords<'a>(
+    tokens: &'a [(Token, Range<usize>)],
+    names: &[&str],
+    last: &str,
+) -> Vec<(&'a str, usize)> {
+    let mut nesting = Nesting::default();
+    let mut found = Vec::new();
+
+    for (i, (token, _)) in tokens.iter().enumerate().skip(1) {
+        match token {
+            Token::Keyword(keyword) if nesting.is_empty() && names.contains(&keyword.as_str()) => {
+                found.push((keyword.as_str(), i));
+                if keyword == last {
+                    break;
+                }
+            }
+            token => {
+                nesting.update(token);
+            }
+        }
     }
 
-    let s = s.as_ref();
-    let mut chars = s.chars().peekable();
-    let mut expansions = Vec::new();
-    let mut index = 0;
+    found
+}
 
-    let mut prev_char = None;
+/// Parses the commands between two keywords of a compound command, which
+/// cannot be empty.
+fn parse_body(tokens: &[(Token, Range<usize>)], after: usize) -> ParseResult<SyntaxTree> {
+    let (keyword, span) = &tokens[after];
+    let body = parse_tokens(&tokens[after + 1..])?;
 
-    while let Some(ch) = chars.next() {
-        match ch {
-            ' ' => {}
+    if body.commands.is_empty() {
+        let keyword = match keyword.keyword() {
+            "" => "keyword",
+            keyword => keyword,
+        };
+        return Err(ParseError::new(
+            format!("expected a command after `{keyword}`"),
+            span.clone(),
+        ));
+    }
 
-            // should be guarded by !matches!(expand, Expand::None), but since
-            // we have an early return specifically for Expand::None, it is not
-            // needed.
-            '$' => match chars.peek() {
-                Some(&c) if util::is_valid_first_character_of_expansion(c) => {
-                    let c = chars.next().unwrap();
+    Ok(body)
+}
 
-                    let mut var = c.to_string();
-                    let start_index = index;
+/// Makes sure that nothing follows the keyword that ends a compound command.
+fn expect_end(tokens: &[(Token, Range<usize>)], end: usize) -> ParseResult<()> {
+    match tokens[end + 1..].iter().find(|(t, _)| *t != Token::Newline) {
+        Some((_, span)) => Err(ParseError::new(
+            format!("unexpected input after `{}`", tokens[end].0.keyword()),
+            span.clone(),
+        )),
+        None => Ok(()),
+    }
+}
 
-                    while let Some(&c) = chars.peek() {
-                        if !util::is_valid_first_character_of_expansion(c) {
-                            break;
-                        }
-                        var.push(chars.next().unwrap());
-                        index += 1;
-                    }
+/// Parses `if list; then list; [elif list; then list;]... [else list;] fi`.
+fn parse_if(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let keywords = find_keywords(tokens, &["then", "elif", "else", "fi"], "fi");
 
-                    index += 1;
+    let mut branches = Vec::new();
+    let mut otherwise = None;
+    let mut condition = None;
+    let mut prev = ("if", 0);
 
-                    expansions.push(Expansion::Parameter {
-                        name: var,
-                        range: start_index..=index,
-                    });
-                }
+    for (keyword, i) in keywords {
+        let body = parse_body(&tokens[..i], prev.1)?;
 
-                Some(&'(') => {
-                    let mut nested_level = 0;
-                    let start_index = index;
-                    chars.next();
-                    let mut subcmd = String::new();
-                    while let Some(next) = chars.next() {
-                        if next == '$' {
-                            if let Some(&'(') = chars.peek() {
-                                nested_level += 1;
-                            }
-                        }
-                        index += 1;
-                        if next == ')' {
-                            if nested_level > 0 {
-                                nested_level -= 1;
-                            } else {
-                                break;
-                            }
-                        }
-                        subcmd.push(next);
-                    }
-                    index += 1;
-                    let ast = parse(subcmd);
-                    expansions.push(Expansion::Command {
-                        ast,
-                        range: start_index..=index,
-                    });
-                }
+        match (prev.0, keyword) {
+            ("if" | "elif", "then") => condition = Some(body),
+            ("then", "elif" | "else" | "fi") => {
+                branches.push((condition.take().unwrap(), body));
+            }
+            ("else", "fi") => otherwise = Some(body),
+            (_, keyword) => {
+                return Err(ParseError::new(
+                    format!("unexpected `{keyword}`"),
+                    tokens[i].1.clone(),
+                ))
+            }
+        }
 
-                c => panic!("got unexpected: {c:?}"),
-            },
+        prev = (keyword, i);
+    }
+
+    if prev.0 != "fi" {
+        return Err(ParseError::new(
+            "expected `fi` to end `if`",
+            tokens[0].1.clone(),
+        ));
+    }
+
+    expect_end(tokens, prev.1)?;
+
+    Ok(CommandType::If {
+        branches,
+        otherwise,
+    })
+}
+
+/// Parses `while list; do list; done` and `until list; do list; done`.
+fn parse_while(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let keyword = tokens[0].0.keyword();
+
+    match find_keywords(tokens, &["do", "done"], "done")[..] {
+        [("do", i), ("done", j)] => {
+            let condition = parse_body(&tokens[..i], 0)?;
+            let body = parse_body(&tokens[..j], i)?;
+            expect_end(tokens, j)?;
+
+            Ok(CommandType::While {
+                condition,
+                body,
+                until: keyword == "until",
+            })
+        }
+
+        [(unexpected @ "done", i), ..] | [_, (unexpected @ "do", i), ..] => Err(ParseError::new(
+            format!("unexpected `{unexpected}`"),
+            tokens[i].1.clone(),
+        )),
+
+        _ => Err(ParseError::new(
+            format!("expected `done` to end `{keyword}`"),
+            tokens[0].1.clone(),
+        )),
+    }
+}
+
+/// Parses `for name [in word...]; do list; done`.
+fn parse_for(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let name = match tokens.get(1) {
+        Some((Token::String(name), _)) if variables::is_valid_name(name) => name.clone(),
+        Some((_, span)) => {
+            return Err(ParseError::new(
+                "expected a variable name after `for`",
+                span.clone(),
+            ))
+        }
+        None => {
+            return Err(ParseError::new(
+                "expected a variable name after `for`",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    let (i, j) = match find_keywords(tokens, &["do", "done"], "done")[..] {
+        [("do", i), ("done", j)] => (i, j),
+
+        [(unexpected @ "done", i), ..] | [_, (unexpected @ "do", i), ..] => {
+            return Err(ParseError::new(
+                format!("unexpected `{unexpected}`"),
+                tokens[i].1.clone(),
+            ))
+        }
+
+        _ => {
+            return Err(ParseError::new(
+                "expected `done` to end `for`",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    let mut rest = &tokens[2..i];
+    let words = match rest.first() {
+        Some((Token::Keyword(k), _)) if k == "in" => {
+            let end = rest
+                .iter()
+                .position(|(t, _)| matches!(t, Token::Semicolon | Token::Newline))
+                .unwrap_or(rest.len());
+
+            let words = rest[1..end]
+                .iter()
+                .map(|(token, span)| match token {
+                    Token::String(_)
+                    | Token::SingleQuotedString(_, _)
+                    | Token::DoubleQuotedString(_, _)
+                    | Token::AnsiCString(_, _) => match parse_meta(token, span, false)? {
+                        Meta::Word(word) => Ok(word),
+                        _ => unreachable!("strings are only parsed as words outside of prefixes"),
+                    },
+                    _ => Err(ParseError::new("expected a word", span.clone())),
+                })
+                .collect::<ParseResult<Vec<_>>>()?;
+
+            rest = &rest[end..];
+            Some(words)
+        }
+        _ => None,
+    };
+
+    // The words have to be followed by a `;` or newlines before the `do`
+    for (k, (token, span)) in rest.iter().enumerate() {
+        if !(*token == Token::Newline || k == 0 && *token == Token::Semicolon) {
+            return Err(ParseError::new("expected `do`", span.clone()));
+        }
+    }
+
+    let body = parse_body(&tokens[..j], i)?;
+    expect_end(tokens, j)?;
+
+    Ok(CommandType::For { name, words, body })
+}
+
+/// Parses `case word in [(]pattern [| pattern]...) list;; ... esac`, where
+/// the last arm does not need the `;;`.
+fn parse_case(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let word = match tokens.get(1) {
+        Some((
+            token @ (Token::String(_)
+            | Token::SingleQuotedString(_, _)
+            | Token::DoubleQuotedString(_, _)
+            | Token::AnsiCString(_, _)),
+            span,
+        )) => parse_pattern(token, span)?,
+        Some((_, span)) => {
+            return Err(ParseError::new(
+                "expected a word after `case`",
+                span.clone(),
+            ))
+        }
+        None => {
+            return Err(ParseError::new(
+                "expected a word after `case`",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    match tokens.get(2) {
+        Some((Token::Keyword(k), _)) if k == "in" => {}
+        Some((_, span)) => return Err(ParseError::new("expected `in`", span.clone())),
+        None => return Err(ParseError::new("expected `in`", tokens[1].1.clone())),
+    }
+
+    let end = match find_keywords(tokens, &["esac"], "esac")[..] {
+        [(_, end)] => end,
+        _ => {
+            return Err(ParseError::new(
+                "expected `esac` to end `case`",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    let body = &tokens[3..end];
+    let top_level = top_level(body);
+    let mut arms = Vec::new();
+    let mut start = 0;
+
+    for (i, (token, span)) in body.iter().enumerate() {
+        let fallthrough = match token {
+            Token::DoubleSemicolon if top_level[i] => false,
+            Token::SemicolonAmpersand if top_level[i] => true,
+            _ => continue,
+        };
+
+        arms.push(parse_case_arm(&body[start..i], span, fallthrough)?);
+        start = i + 1;
+    }
+
+    let rest = &body[start..];
+    if rest.iter().any(|(t, _)| *t != Token::Newline) {
+        arms.push(parse_case_arm(rest, &tokens[end].1, false)?);
+    }
+
+    expect_end(tokens, end)?;
+
+    Ok(CommandType::Case { word, arms })
+}
+
+/// Parses `[(]pattern [| pattern]...) list`, an arm of a `case` command that
+/// is ended by the token at `end`.
+fn parse_case_arm(
+    tokens: &[(Token, Range<usize>)],
+    end: &Range<usize>,
+    fallthrough: bool,
+) -> ParseResult<CaseArm> {
+    let tokens = match tokens.iter().position(|(t, _)| *t != Token::Newline) {
+        Some(start) => &tokens[start..],
+        None => &[],
+    };
+
+    let tokens = match tokens.first() {
+        Some((Token::LParen, _)) => &tokens[1..],
+        _ => tokens,
+    };
+
+    let Some(close) = tokens.iter().position(|(t, _)| *t == Token::RParen) else {
+        let span = tokens.first().map_or(end, |(_, span)| span);
+        return Err(ParseError::new("expected a pattern", span.clone()));
+    };
+
+    // The patterns are words separated by pipes
+    let mut patterns = Vec::new();
+    for (i, (token, span)) in tokens[..=close].iter().enumerate() {
+        match token {
+            Token::String(_)
+            | Token::SingleQuotedString(_, _)
+            | Token::DoubleQuotedString(_, _)
+            | Token::AnsiCString(_, _)
+                if i % 2 == 0 =>
+            {
+                patterns.push(parse_pattern(token, span)?)
+            }
+            Token::Pipe if i % 2 == 1 => {}
+            Token::RParen if i % 2 == 1 => {}
+            _ => return Err(ParseError::new("expected a pattern", span.clone())),
+        }
+    }
+
+    Ok(CaseArm {
+        patterns,
+        body: parse_tokens(&tokens[close + 1..])?,
+        fallthrough,
+    })
+}
+
+/// Parses a word of a `case` command, which is quoted if any quotes were used,
+/// since quoted patterns only match themselves.
+fn parse_pattern(token: &Token, span: &Range<usize>) -> ParseResult<Word> {
+    match parse_meta(token, span, false)? {
+        Meta::Word(word) => Ok(Word {
+            quoted: word.quoted
+                || matches!(
+                    token,
+                    Token::SingleQuotedString(_, _) | Token::AnsiCString(_, _)
+                ),
+            ..word
+        }),
+        _ => unreachable!("strings are only parsed as words outside of prefixes"),
+    }
+}
+
+/// Whether `tokens` start with `name()`, which defines a function.
+fn is_function_definition(tokens: &[(Token, Range<usize>)]) -> bool {
+    matches!(
+        tokens,
+        [
+            (Token::String(_), _),
+            (Token::LParen, _),
+            (Token::RParen, _),
+            ..
+        ]
+    )
+}
+
+/// Parses the name of a function, which can contain dashes and dots unlike
+/// the names of variables.
+fn parse_function_name(token: &Token, span: &Range<usize>) -> ParseResult<String> {
+    match token {
+        Token::String(name)
+            if name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) =>
+        {
+            Ok(name.clone())
+        }
+        Token::String(name) => Err(ParseError::new(
+            format!("'{name}' is not a valid function name"),
+            span.clone(),
+        )),
+        _ => Err(ParseError::new(
+            "expected the name of a function",
+            span.clone(),
+        )),
+    }
+}
+
+/// Parses `name() compound-command`, where the body is usually `{ list; }`.
+fn parse_function_definition(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let name = parse_function_name(&tokens[0].0, &tokens[0].1)?;
+
+    let body = match tokens[3..].iter().position(|(t, _)| *t != Token::Newline) {
+        Some(start) => &tokens[3 + start..],
+        None => {
+            return Err(ParseError::new(
+                "expected the body of the function",
+                tokens[2].1.clone(),
+            ))
+        }
+    };
+
+    let body = match &body[0] {
+        (Token::Keyword(_) | Token::LParen | Token::LBrace, _) => match parse_compound(body)? {
+            CommandType::Group(list) => list,
+            command => SyntaxTree {
+                commands: vec![command],
+            },
+        },
+
+        (_, span) => {
+            return Err(ParseError::new(
+                "expected `{` to start the body of the function",
+                span.clone(),
+            ))
+        }
+    };
+
+    Ok(CommandType::Function { name, body })
+}
+
+/// Parses `function name; list; end`, which is how functions are defined in fish.
+fn parse_function(tokens: &[(Token, Range<usize>)]) -> ParseResult<CommandType> {
+    let name = match tokens.get(1) {
+        Some((token, span)) => parse_function_name(token, span)?,
+        None => {
+            return Err(ParseError::new(
+                "expected the name of a function",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    let end = match find_keywords(tokens, &["end"], "end")[..] {
+        [(_, end)] => end,
+        _ => {
+            return Err(ParseError::new(
+                "expected `end` to end `function`",
+                tokens[0].1.clone(),
+            ))
+        }
+    };
+
+    match &tokens[2] {
+        (Token::Semicolon | Token::Newline, _) => {}
+        (_, span) => {
+            return Err(ParseError::new(
+                "expected a newline or `;` after the name of the function",
+                span.clone(),
+            ))
+        }
+    }
+
+    let body = parse_tokens(&tokens[2..end])?;
+    expect_end(tokens, end)?;
+
+    Ok(CommandType::Function { name, body })
+}
+
+enum ExpansionType {
+    All,
+    VariablesAndCommands,
+    None,
+}
+
+/// Parses the expansions in `s`, which starts at byte `offset` of the input.
+/// The offset is only used to point out where in the input errors are.
+fn parse_word(s: impl AsRef<str>, expand: ExpansionType, offset: usize) -> ParseResult<Word> {
+    if let ExpansionType::None = expand {
+        return Ok(Word::new(s.as_ref(), Vec::new()));
+    }
+
+    let s = s.as_ref();
+    let mut chars = s.chars().peekable();
+    let mut expansions = Vec::new();
+    let mut index = 0;
+
+    let mut prev_char = None;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' => {}
+
+            // Inside of double quotes, only the characters that would otherwise
+            // be special there can be escaped.
+            '\\' => match chars.peek() {
+                Some(&c) if matches!(expand, ExpansionType::All) || "$`\"\\\n".contains(c) => {
+                    chars.next();
+                    expansions.push(Expansion::Escape {
+                        range: index..=index + 1,
+                    });
+                    index += 1;
+                }
+                _ => {}
+            },
+
+            // should be guarded by !matches!(expand, Expand::None), but since
+            // we have an early return specifically for Expand::None, it is not
+            // needed.
+            '$' => match chars.peek().copied() {
+                Some(c) if util::is_valid_first_character_of_expansion(c) => {
+                    let c = chars.next().unwrap();
+
+                    let mut var = c.to_string();
+                    let start_index = index;
+
+                    while let Some(&c) = chars.peek() {
+                        if !util::is_valid_character_of_expansion(c) {
+                            break;
+                        }
+                        var.push(chars.next().unwrap());
+                        index += 1;
+                    }
+
+                    index += 1;
+
+                    expansions.push(Expansion::Parameter {
+                        name: var,
+                        range: start_index..=index,
+                    });
+                }
+
+                Some(c) if util::is_special_parameter(c) => {
+                    let start_index = index;
+                    chars.next();
+                    index += 1;
+
+                    expansions.push(Expansion::Parameter {
+                        name: c.to_string(),
+                        range: start_index..=index,
+                    });
+                }
+
+                Some('{') => {
+                    let start_index = index;
+                    chars.next();
+                    index += 1;
+
+                    // Braces in quotes, or escaped ones, do not end the expansion
+                    let mut depth = 0;
+                    let mut quote = None;
+                    let mut escaped = false;
+                    let mut finished = false;
+                    let mut content = String::new();
+                    for next in chars.by_ref() {
+                        index += 1;
+                        match (quote, next) {
+                            _ if mem::take(&mut escaped) => {}
+                            (_, '\\') => escaped = true,
+                            (Some(q), c) if c == q => quote = None,
+                            (Some(_), _) => {}
+                            (None, '"' | '\'') => quote = Some(next),
+                            (None, '{') => depth += 1,
+                            (None, '}') if depth == 0 => {
+                                finished = true;
+                                break;
+                            }
+                            (None, '}') => depth -= 1,
+                            _ => {}
+                        }
+                        content.push(next);
+                    }
+
+                    if !finished {
+                        return Err(ParseError::new(
+                            "unterminated parameter expansion",
+                            offset + start_index..offset + s.len(),
+                        ));
+                    }
+
+                    // What is inside the braces starts after the `${`
+                    expansions.push(parse_parameter(
+                        &content,
+                        start_index..=index,
+                        offset + start_index + 2,
+                    )?);
+                }
+
+                Some('(') if chars.clone().nth(1) == Some('(') => {
+                    let start_index = index;
+                    chars.next();
+                    chars.next();
+                    index += 2;
+
+                    let mut depth = 0;
+                    let mut finished = false;
+                    let mut expression = String::new();
+                    while let Some(next) = chars.next() {
+                        index += 1;
+                        match next {
+                            '(' => depth += 1,
+                            ')' if depth > 0 => depth -= 1,
+                            ')' if chars.peek() == Some(&')') => {
+                                chars.next();
+                                index += 1;
+                                finished = true;
+                                break;
+                            }
+                            ')' => break,
+                            _ => {}
+                        }
+                        expression.push(next);
+                    }
+
+                    if !finished {
+                        return Err(ParseError::new(
+                            "unterminated arithmetic expansion",
+                            offset + start_index..offset + s.len(),
+                        ));
+                    }
+
+                    // The expression starts after the `$((`
+                    let expression = parse_word(
+                        expression,
+                        ExpansionType::VariablesAndCommands,
+                        offset + start_index + 3,
+                    )?;
+                    expansions.push(Expansion::Arithmetic {
+                        range: start_index..=index,
+                        expression,
+                    });
+                }
+
+                Some('(') => {
+                    let start_index = index;
+                    chars.next();
+
+                    // The command starts after the `$(`
+                    let ast = parse_subcommand(
+                        &mut chars,
+                        &mut index,
+                        offset + start_index..offset + s.len(),
+                        "command substitution",
+                    )?;
+                    expansions.push(Expansion::Command {
+                        ast,
+                        range: start_index..=index,
+                    });
+                }
+
+                // `$'...'`, whose escapes are replaced right away
+                Some('\'') if matches!(expand, ExpansionType::All) => {
+                    let start_index = index;
+                    chars.next();
+
+                    let rest = chars.clone().collect::<String>();
+                    let Some((quoted, len)) = lexer::lex_ansi_c_part(&rest) else {
+                        return Err(ParseError::new(
+                            "unterminated quote",
+                            offset + start_index..offset + s.len(),
+                        ));
+                    };
+
+                    chars.by_ref().take(len).for_each(drop);
+                    index += len + 1;
+
+                    expansions.push(Expansion::Quoted {
+                        range: start_index..=index,
+                        word: Word::new(util::unescape_ansi_c(&quoted), Vec::new()),
+                    });
+                }
+
+                // A `$` that does not start an expansion is just a `$`
+                _ => {}
+            },
+
+            // Process substitutions are only recognized at the start of a word
+            '<' | '>'
+                if index == 0
+                    && matches!(expand, ExpansionType::All)
+                    && chars.peek() == Some(&'(') =>
+            {
+                chars.next();
+
+                let ast = parse_subcommand(
+                    &mut chars,
+                    &mut index,
+                    offset..offset + s.len(),
+                    "process substitution",
+                )?;
+                expansions.push(Expansion::Process {
+                    range: 0..=index,
+                    ast,
+                    output: ch == '>',
+                });
+            }
+
+            '*' | '?' | '['
+                if matches!(expand, ExpansionType::All)
+                    && (ch != '[' || has_closing_bracket(chars.clone())) =>
+            {
+                let mut recursive = false;
+                let mut pattern = ch.to_string();
+                let start_index = index;
+
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        '*' if pattern == "*" => {
+                            chars.next();
+                            index += 1;
+                            recursive = true;
+                            pattern.push('*');
+                        }
+
+                        c => {
+                            if " /$'\"".contains(c) {
+                                break;
+                            }
+                            pattern.push(c);
+                            chars.next();
+                            index += 1;
+                        }
+                    }
+                }
+
+                expansions.push(Expansion::Glob {
+                    pattern,
+                    recursive,
+                    range: start_index..=index,
+                });
+            }
+
+            '\'' | '"' if matches!(expand, ExpansionType::All) => {
+                let start_index = index;
+                let rest = chars.clone().collect::<String>();
+                let Some((quoted, len)) = lexer::lex_quoted_part(&rest, ch) else {
+                    let quote = if ch == '"' { "double" } else { "single" };
+                    return Err(ParseError::new(
+                        format!("unterminated {quote} quote"),
+                        offset + start_index..offset + s.len(),
+                    ));
+                };
+
+                chars.by_ref().take(len).for_each(drop);
+                index += len;
+
+                let word = if ch == '"' {
+                    let word = parse_word(
+                        quoted,
+                        ExpansionType::VariablesAndCommands,
+                        offset + start_index + 1,
+                    )?;
+                    Word {
+                        quoted: true,
+                        ..word
+                    }
+                } else {
+                    Word::new(quoted, Vec::new())
+                };
+
+                expansions.push(Expansion::Quoted {
+                    range: start_index..=index,
+                    word,
+                });
+            }
+
+            '{' if matches!(expand, ExpansionType::All) => {
+                if let Some((len, alternatives)) = parse_brace(&chars.clone().collect::<String>()) {
+                    let start_index = index;
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                    index += len;
+
+                    expansions.push(Expansion::Brace {
+                        range: start_index..=index,
+                        alternatives,
+                    });
+                }
+            }
+
+            '~' if matches!(expand, ExpansionType::All)
+                && matches!(prev_char, Some(' ' | '=') | None) =>
+            {
+                match chars.peek() {
+                    Some(' ') | Some('/') | None => {
+                        expansions.push(Expansion::Tilde { index });
+                    }
+                    _ => {}
+                }
+            }
+
+            _ => {}
+        }
+        index += 1;
+        prev_char = Some(ch);
+    }
+
+    // Every combination of the alternatives becomes a word of its own
+    let words = expansions
+        .iter()
+        .filter_map(|e| match e {
+            Expansion::Brace { alternatives, .. } => Some(alternatives.len()),
+            _ => None,
+        })
+        .try_fold(1_usize, |words, len| {
+            words.checked_mul(len).filter(|&n| n <= MAX_BRACE_WORDS)
+        });
+    if words.is_none() {
+        expansions.retain(|e| !matches!(e, Expansion::Brace { .. }));
+    }
+
+    Ok(Word::new(s, expansions))
+}
+
+/// Parses what is inside of `${...}`, which starts at byte `offset` of the input.
+fn parse_parameter(s: &str, range: RangeInclusive<usize>, offset: usize) -> ParseResult<Expansion> {
+    let bad_substitution = || ParseError::new("bad substitution", offset - 2..offset + s.len() + 1);
+
+    // `${#}` is the number of positional parameters, rather than a length.
+    if let Some(name) = s.strip_prefix('#').filter(|name| !name.is_empty()) {
+        if parameter_name_len(name) != name.len() {
+            return Err(bad_substitution());
+        }
+
+        return Ok(Expansion::Operator {
+            range,
+            name: name.to_string(),
+            operator: ParameterOperator::Length,
+            word: Word::new("", Vec::new()),
+        });
+    }
+
+    let (name, rest) = s.split_at(parameter_name_len(s));
+    if name.is_empty() {
+        return Err(bad_substitution());
+    }
+
+    if rest.is_empty() {
+        return Ok(Expansion::Parameter {
+            range,
+            name: name.to_string(),
+        });
+    }
+
+    let Some((op, operator)) = PARAMETER_OPERATORS
+        .iter()
+        .find(|(op, _)| rest.starts_with(op))
+    else {
+        return Err(bad_substitution());
+    };
+
+    let word = &rest[op.len()..];
+    let word = parse_word(word, ExpansionType::All, offset + s.len() - word.len())?;
+
+    Ok(Expansion::Operator {
+        range,
+        name: name.to_string(),
+        operator: *operator,
+        word,
+    })
+}
+
+/// The length in bytes of the parameter name at the start of `s`, which is
+/// either a variable name, a number or a single special character.
+fn parameter_name_len(s: &str) -> usize {
+    match s.chars().next() {
+        Some(c) if c.is_ascii_digit() => s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()),
+
+        Some(c) if util::is_special_parameter(c) => 1,
+
+        Some(c) if util::is_valid_first_character_of_expansion(c) => s
+            .find(|c| !util::is_valid_character_of_expansion(c))
+            .unwrap_or(s.len()),
+
+        _ => 0,
+    }
+}
+
+/// Parses the brace expansion in `s`, which is what follows a `{`. Returns how
+/// many characters it spans (including the closing `}`) and what it expands to,
+/// or `None` if the `{` does not start a brace expansion.
+fn parse_brace(s: &str) -> Option<(usize, Vec<String>)> {
+    let chars = s.chars().collect::<Vec<_>>();
+
+    let mut depth = 0;
+    let mut parens = 0;
+    let mut commas = Vec::new();
+    let mut end = None;
+
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            // Braces inside of command substitutions are not ours to expand
+            '(' if parens > 0 || (i > 0 && chars[i - 1] == '$') => parens += 1,
+            ')' if parens > 0 => parens -= 1,
+            _ if parens > 0 => {}
+
+            ' ' => return None,
+            '{' => depth += 1,
+            '}' if depth == 0 => {
+                end = Some(i);
+                break;
+            }
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+
+    let end = end?;
+    let body = &chars[..end];
+
+    let alternatives = if commas.is_empty() {
+        brace_sequence(&body.iter().collect::<String>())?
+    } else {
+        let mut bounds = vec![0];
+        bounds.extend(commas.iter().flat_map(|&i| [i, i + 1]));
+        bounds.push(end);
+
+        let mut alternatives = Vec::new();
+        for b in bounds.chunks(2) {
+            alternatives.extend(expand_braces(&body[b[0]..b[1]].iter().collect::<String>())?);
+            if alternatives.len() > MAX_BRACE_WORDS {
+                return None;
+            }
+        }
+        alternatives
+    };
+
+    Some((end + 1, alternatives))
+}
+
+/// Performs every brace expansion in `s`, for braces nested within others.
+/// Returns `None` if that would result in more than [`MAX_BRACE_WORDS`] words.
+fn expand_braces(s: &str) -> Option<Vec<String>> {
+    let mut parens = 0;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' if parens > 0 || s[..i].ends_with('$') => parens += 1,
+            ')' if parens > 0 => parens -= 1,
+            '{' if parens == 0 => {
+                if let Some((len, alternatives)) = parse_brace(&s[i + 1..]) {
+                    let after = s[i + 1..]
+                        .char_indices()
+                        .nth(len)
+                        .map_or(s.len(), |(j, _)| i + 1 + j);
+
+                    let rest = expand_braces(&s[after..])?;
+                    if alternatives.len().saturating_mul(rest.len()) > MAX_BRACE_WORDS {
+                        return None;
+                    }
+
+                    return Some(
+                        alternatives
+                            .iter()
+                            .flat_map(|a| rest.iter().map(move |r| format!("{}{a}{r}", &s[..i])))
+                            .collect(),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(vec![s.to_string()])
+}
+
+/// The most words that the brace expansions of a word can expand to together,
+/// beyond which they are left as they are.
+const MAX_BRACE_WORDS: usize = 100_000;
+
+/// Expands `start..end` or `start..end..step`, where `start` and `end` are
+/// either both integers or both single characters.
+fn brace_sequence(s: &str) -> Option<Vec<String>> {
+    // The integers are `i64`, so none of the maths below can overflow `i128`
+    let (start, end, step) = match s.split("..").collect::<Vec<_>>()[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (
+            start,
+            end,
+            i128::from(step.parse::<i64>().ok()?).abs().max(1),
+        ),
+        _ => return None,
+    };
+
+    let sequence = |start: i128, end: i128| {
+        let step = if start <= end { step } else { -step };
+        let len = (end - start) / step + 1;
+        (len <= MAX_BRACE_WORDS as i128).then(|| (0..len).map(move |i| start + i * step))
+    };
+
+    if let (Ok(a), Ok(b)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        // Like in bash, `{01..10}` pads every number to the same width
+        let padded = |n: &str| {
+            n.trim_start_matches('-').len() > 1 && n.trim_start_matches('-').starts_with('0')
+        };
+        let width = if padded(start) || padded(end) {
+            start.len().max(end.len())
+        } else {
+            0
+        };
+
+        return Some(
+            sequence(a.into(), b.into())?
+                .map(|n| format!("{n:0width$}"))
+                .collect(),
+        );
+    }
+
+    let mut start = start.chars();
+    let mut end = end.chars();
+    match (start.next(), start.next(), end.next(), end.next()) {
+        (Some(a), None, Some(b), None) => Some(
+            sequence(u32::from(a).into(), u32::from(b).into())?
+                .filter_map(|c| char::from_u32(c as u32))
+                .map(String::from)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Parses the command of a `$(...)`, `<(...)` or `>(...)` whose opening
+/// parenthesis has just been consumed, leaving `index` at the closing one.
+/// `span` is where in the input the whole expansion is, for errors.
+fn parse_subcommand(
+    chars: &mut impl Iterator<Item = char>,
+    index: &mut usize,
+    span: Range<usize>,
+    what: &str,
+) -> ParseResult<SyntaxTree> {
+    let mut nested_level = 0;
+    let mut finished = false;
+    let mut subcmd = String::new();
+    for next in chars.by_ref() {
+        if next == '(' {
+            nested_level += 1;
+        }
+        *index += 1;
+        if next == ')' {
+            if nested_level > 0 {
+                nested_level -= 1;
+            } else {
+                finished = true;
+                break;
+            }
+        }
+        subcmd.push(next);
+    }
+    *index += 1;
+
+    if !finished {
+        return Err(ParseError::new(format!("unterminated {what}"), span));
+    }
+
+    parse(subcmd).map_err(|e| e.offset(span.start + 2))
+}
+
+/// Whether the rest of the current path component contains a `]`, which
+/// makes a `[` the start of a pattern rather than just a `[`.
+fn has_closing_bracket(chars: impl Iterator<Item = char>) -> bool {
+    chars
+        .take_while(|&c| c != ' ' && c != '/')
+        .any(|c| c == ']')
+}
+
+fn parse_meta(token: &Token, span: &Range<usize>, is_prefix: bool) -> ParseResult<Meta> {
+    match token {
+        Token::String(s) => {
+            if is_prefix {
+                let item = match s.split_once('=') {
+                    Some((var, val)) if variables::is_valid_name(var) => {
+                        let var_word = parse_word(var, ExpansionType::None, span.start)?;
+                        let val_offset = span.start + var.len() + 1;
+                        let val_word = parse_word(val, ExpansionType::All, val_offset)?;
+                        Meta::Assignment(var_word, val_word)
+                    }
+                    _ => Meta::Word(parse_word(s, ExpansionType::All, span.start)?),
+                };
+
+                Ok(item)
+            } else {
+                Ok(Meta::Word(parse_word(s, ExpansionType::All, span.start)?))
+            }
+        }
+
+        Token::SingleQuotedString(s, finished) => {
+            if *finished {
+                let word = parse_word(s, ExpansionType::None, span.start + 1)?;
+                Ok(Meta::Word(word))
+            } else {
+                Err(ParseError::new("unterminated single quote", span.clone()))
+            }
+        }
+
+        Token::DoubleQuotedString(s, finished) => {
+            if *finished {
+                let word = parse_word(s, ExpansionType::VariablesAndCommands, span.start + 1)?;
+                Ok(Meta::Word(Word {
+                    quoted: true,
+                    ..word
+                }))
+            } else {
+                Err(ParseError::new("unterminated double quote", span.clone()))
+            }
+        }
+
+        Token::AnsiCString(s, finished) => {
+            if *finished {
+                Ok(Meta::Word(Word::new(util::unescape_ansi_c(s), Vec::new())))
+            } else {
+                Err(ParseError::new("unterminated quote", span.clone()))
+            }
+        }
+
+        // FIXME: this should probably not always use ExpansionType::All
+        Token::RedirectInput(from, to) => {
+            let from = match from {
+                Some(s) => Some(parse_word(s, ExpansionType::All, span.start)?),
+                None => None,
+            };
+
+            // FIXME: this should probably not always use ExpansionType::All
+            if let Some(to) = to.strip_prefix('&') {
+                expect_target(to, "<&", span)?;
+                return Ok(Meta::Redirect(Redirect::Duplicate {
+                    from,
+                    to: parse_word(to, ExpansionType::All, span.end - to.len())?,
+                    output: false,
+                }));
+            }
+
+            expect_target(to, "<", span)?;
+            Ok(Meta::Redirect(Redirect::Input {
+                from,
+                to: parse_word(to, ExpansionType::All, span.end - to.len())?,
+            }))
+        }
+
+        Token::RedirectOutput(Some(from), to, _, append, clobber) if from == "&" => {
+            expect_target(
+                to,
+                &format!("&{}", output_operator(*append, *clobber)),
+                span,
+            )?;
+            Ok(Meta::Redirect(Redirect::Both {
+                to: parse_word(to, ExpansionType::All, span.end - to.len())?,
+                append: *append,
+                clobber: *clobber,
+            }))
+        }
+
+        Token::RedirectOutput(from, to, _, append, clobber) => {
+            // FIXME: these should probably not always use ExpansionType::All
+            let from = match from {
+                Some(s) => Some(parse_word(s, ExpansionType::All, span.start)?),
+                None => None,
+            };
+
+            if let Some(to) = to.strip_prefix('&') {
+                expect_target(to, ">&", span)?;
+                return Ok(Meta::Redirect(Redirect::Duplicate {
+                    from,
+                    to: parse_word(to, ExpansionType::All, span.end - to.len())?,
+                    output: true,
+                }));
+            }
+
+            expect_target(to, output_operator(*append, *clobber), span)?;
+            let to = parse_word(to, ExpansionType::All, span.end - to.len())?;
+
+            Ok(Meta::Redirect(Redirect::Output {
+                from,
+                to,
+                append: *append,
+                clobber: *clobber,
+            }))
+        }
+
+        Token::HereDocument {
+            delimiter, body, ..
+        } => {
+            if delimiter.is_empty() {
+                return Err(ParseError::new(
+                    "expected a delimiter after `<<`",
+                    span.clone(),
+                ));
+            }
+
+            let Some(body) = body else {
+                return Err(ParseError::new(
+                    format!("expected `{delimiter}` to end the here-document"),
+                    span.clone(),
+                ));
+            };
+
+            // Like in double quotes, the results of expansions are never split
+            let expand = if delimiter.contains(['\'', '"', '\\']) {
+                ExpansionType::None
+            } else {
+                ExpansionType::VariablesAndCommands
+            };
+            let body = parse_word(body, expand, span.end + 1)?;
+
+            Ok(Meta::Redirect(Redirect::HereDocument {
+                delimiter: delimiter.clone(),
+                body: Word {
+                    quoted: true,
+                    ..body
+                },
+            }))
+        }
+
+        Token::HereString(word) => {
+            if matches!(&**word, Token::String(s) if s.is_empty()) {
+                return Err(ParseError::new("expected a word after `<<<`", span.clone()));
+            }
+
+            let len = match &**word {
+                Token::String(s) => s.len(),
+                Token::SingleQuotedString(s, _) | Token::DoubleQuotedString(s, _) => s.len() + 2,
+                Token::AnsiCString(s, _) => s.len() + 3,
+                _ => 0,
+            };
+            let start = span.end.saturating_sub(len).max(span.start);
+
+            match parse_meta(word, &(start..span.end), false)? {
+                Meta::Word(word) => Ok(Meta::Redirect(Redirect::HereString { word })),
+                _ => unreachable!("here-strings only contain words"),
+            }
+        }
+
+        _ => unreachable!(),
+    }
+}
+
+/// Fails unless there is something for the redirection `operator` to redirect to.
+fn expect_target(to: &str, operator: &str, span: &Range<usize>) -> ParseResult<()> {
+    if to.is_empty() {
+        Err(ParseError::new(
+            format!("expected a word after `{operator}`"),
+            span.clone(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quoted(name: impl ToString, expansions: Vec<Expansion>) -> Word {
+        Word {
+            quoted: true,
+            ..Word::new(name, expansions)
+        }
+    }
+
+    #[test]
+    fn basic_parsing() {
+        let input = "2>&1 echo hello world | lolcat -n;".to_string();
+        let ast = parse(input).unwrap();
+
+        println!("{:#?}", &ast);
+
+        assert_eq!(
+            SyntaxTree {
+                commands: vec![CommandType::Pipeline(vec![
+                    CommandType::Single(Command {
+                        name: Word::new("echo", vec![]),
+                        prefixes: vec![Meta::Redirect(Redirect::Duplicate {
+                            from: Some(Word::new("2", vec![])),
+                            to: Word::new("1", vec![]),
+                            output: true,
+                        }),],
+                        suffixes: vec![
+                            Meta::Word(Word::new("hello", vec![])),
+                            Meta::Word(Word::new("world", vec![])),
+                        ],
+                    }),
+                    CommandType::Single(Command {
+                        name: Word::new("lolcat", vec![]),
+                        prefixes: vec![],
+                        suffixes: vec![Meta::Word(Word::new("-n", vec![])),],
+                    })
+                ]),],
+            },
+            ast
+        );
+    }
+
+    #[test]
+    fn and_or_parsing() {
+        let input = "true && echo foo | rev || echo bar; ls".to_string();
+        let ast = parse(input).unwrap();
+
+        let cmd = |name: &str, args: &[&str]| Command {
+            name: Word::new(name, vec![]),
+            prefixes: vec![],
+            suffixes: args
+                .iter()
+                .map(|arg| Meta::Word(Word::new(arg, vec![])))
+                .collect(),
+        };
+
+        let expected = SyntaxTree {
+            commands: vec![
+                CommandType::Or(
+                    Box::new(CommandType::And(
+                        Box::new(CommandType::Single(cmd("true", &[]))),
+                        Box::new(CommandType::Pipeline(vec![
+                            CommandType::Single(cmd("echo", &["foo"])),
+                            CommandType::Single(cmd("rev", &[])),
+                        ])),
+                    )),
+                    Box::new(CommandType::Single(cmd("echo", &["bar"]))),
+                ),
+                CommandType::Single(cmd("ls", &[])),
+            ],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!("true && echo foo | rev || echo bar; ls", ast.to_string());
+    }
+
+    #[test]
+    fn background_parsing() {
+        let input = "sleep 1 && echo foo & ls &".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![
+                CommandType::Background(Box::new(CommandType::And(
+                    Box::new(CommandType::Single(Command {
+                        name: Word::new("sleep", vec![]),
+                        prefixes: vec![],
+                        suffixes: vec![Meta::Word(Word::new("1", vec![]))],
+                    })),
+                    Box::new(CommandType::Single(Command {
+                        name: Word::new("echo", vec![]),
+                        prefixes: vec![],
+                        suffixes: vec![Meta::Word(Word::new("foo", vec![]))],
+                    })),
+                ))),
+                CommandType::Background(Box::new(CommandType::Single(Command {
+                    name: Word::new("ls", vec![]),
+                    prefixes: vec![],
+                    suffixes: vec![],
+                }))),
+            ],
+        };
+
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn syntax_error_parsing() {
+        let error = |message: &str, span| Err(ParseError::new(message, span));
+
+        assert_eq!(error("expected a command after `|`", 5..6), parse("echo |"));
+        assert_eq!(
+            error("expected a command before `|`", 5..6),
+            parse("ls | | rev")
+        );
+        assert_eq!(
+            error("expected a command before `&&`", 0..2),
+            parse("&& ls")
+        );
+        assert_eq!(
+            error("expected a command after `||`", 9..11),
+            parse("true; ls || ;")
+        );
+        assert_eq!(error("unterminated single quote", 5..9), parse("echo 'foo"));
+        assert_eq!(
+            error("unterminated double quote", 5..9),
+            parse("echo \"foo")
+        );
+        assert_eq!(
+            error("unterminated double quote", 6..10),
+            parse("echo a\"b c")
+        );
+        assert_eq!(error("unterminated single quote", 2..4), parse("x='a"));
+        assert_eq!(error("expected a command", 0..4), parse("2>&1"));
+        assert_eq!(error("expected a word after `>`", 7..8), parse("echo a >"));
+        assert_eq!(
+            error("expected a word after `>&`", 7..10),
+            parse("echo a 2>& ; ls")
+        );
+        assert_eq!(error("expected a word after `<`", 4..5), parse("cat <"));
+        assert_eq!(error("expected a word after `&>>`", 3..6), parse("ls &>>"));
+        assert_eq!(error("expected a word after `<<<`", 4..7), parse("cat <<<"));
+        assert_eq!(
+            error("unterminated command substitution", 5..13),
+            parse("echo $(whoami")
+        );
+        assert_eq!(
+            error("expected a command after `|`", 15..16),
+            parse("echo \"foo $(ls | )\"")
+        );
+        assert_eq!(
+            error("unterminated parameter expansion", 5..10),
+            parse("echo ${foo")
+        );
+        assert_eq!(error("bad substitution", 5..13), parse("echo ${foo!x}"));
+        assert_eq!(
+            error("unterminated arithmetic expansion", 5..14),
+            parse("echo $((1 + 2)")
+        );
+        assert_eq!(
+            error("expected a command after `then`", 9..13),
+            parse("if true; then fi")
+        );
+        assert_eq!(
+            error("expected `fi` to end `if`", 0..2),
+            parse("if a; then b")
+        );
+        assert_eq!(error("unexpected `else`", 4..8), parse("ls; else"));
+        assert_eq!(
+            error("unexpected input after `fi`", 17..18),
+            parse("if a; then b; fi c")
+        );
+        assert_eq!(
+            error("expected `done` to end `until`", 0..5),
+            parse("until a; do b")
+        );
+        assert_eq!(
+            error("unexpected `done`", 9..13),
+            parse("while a; done; do b; done")
+        );
+        assert_eq!(
+            error("expected a variable name after `for`", 4..6),
+            parse("for 1x in a; do b; done")
+        );
+        assert_eq!(
+            error("expected a word", 11..13),
+            parse("for x in a >b; do c; done")
+        );
+        assert_eq!(error("expected `in`", 7..8), parse("case a b in esac"));
+        assert_eq!(
+            error("expected `esac` to end `case`", 0..4),
+            parse("case a in b) c;;")
+        );
+        assert_eq!(
+            error("expected a pattern", 12..13),
+            parse("case a in b|) c;; esac")
+        );
+        assert_eq!(error("unexpected `;;`", 5..7), parse("echo ;; b"));
+        assert_eq!(error("expected `}` to end `{`", 4..5), parse("f() { echo"));
+        assert_eq!(
+            error("expected `{` to start the body of the function", 4..8),
+            parse("f() echo")
+        );
+        assert_eq!(
+            error("'$f' is not a valid function name", 9..11),
+            parse("function $f; end")
+        );
+        assert_eq!(error("expected `)` to end `(`", 0..1), parse("(echo a"));
+        assert_eq!(error("expected a command after `(`", 0..1), parse("( )"));
+        assert_eq!(error("unexpected input after `}`", 7..8), parse("{ a; } b"));
+        assert_eq!(error("unexpected `)`", 5..6), parse("echo )"));
+        assert_eq!(
+            error("expected `EOF` to end the here-document", 4..9),
+            parse("cat <<EOF\na")
+        );
+        assert_eq!(
+            error("expected a delimiter after `<<`", 4..6),
+            parse("cat <<")
+        );
+    }
+
+    #[test]
+    fn assignment_parsing() {
+        let input = "FOO=bar BAR=$FOO; =foo; 1A=b".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![
+                CommandType::Single(Command {
+                    name: Word::new("", vec![]),
+                    prefixes: vec![
+                        Meta::Assignment(Word::new("FOO", vec![]), Word::new("bar", vec![])),
+                        Meta::Assignment(
+                            Word::new("BAR", vec![]),
+                            Word::new(
+                                "$FOO",
+                                vec![Expansion::Parameter {
+                                    range: 0..=3,
+                                    name: "FOO".into(),
+                                }],
+                            ),
+                        ),
+                    ],
+                    suffixes: vec![],
+                }),
+                CommandType::Single(Command {
+                    name: Word::new("=foo", vec![]),
+                    prefixes: vec![],
+                    suffixes: vec![],
+                }),
+                CommandType::Single(Command {
+                    name: Word::new("1A=b", vec![]),
+                    prefixes: vec![],
+                    suffixes: vec![],
+                }),
+            ],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!("FOO=bar BAR=$FOO; =foo; 1A=b", ast.to_string());
+    }
+
+    #[test]
+    fn special_parameter_parsing() {
+        let input = "echo $?$1x $FOO_BAR".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Word(Word::new(
+                        "$?$1x",
+                        vec![
+                            Expansion::Parameter {
+                                range: 0..=1,
+                                name: "?".into(),
+                            },
+                            Expansion::Parameter {
+                                range: 2..=3,
+                                name: "1".into(),
+                            },
+                        ],
+                    )),
+                    Meta::Word(Word::new(
+                        "$FOO_BAR",
+                        vec![Expansion::Parameter {
+                            range: 0..=7,
+                            name: "FOO_BAR".into(),
+                        }],
+                    )),
+                ],
+            })],
+        };
+
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn parameter_operator_parsing() {
+        let input = "echo ${FOO:-$BAR}${#1}${10%.*}".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![Meta::Word(Word::new(
+                    "${FOO:-$BAR}${#1}${10%.*}",
+                    vec![
+                        Expansion::Operator {
+                            range: 0..=11,
+                            name: "FOO".into(),
+                            operator: ParameterOperator::Default { colon: true },
+                            word: Word::new(
+                                "$BAR",
+                                vec![Expansion::Parameter {
+                                    range: 0..=3,
+                                    name: "BAR".into(),
+                                }],
+                            ),
+                        },
+                        Expansion::Operator {
+                            range: 12..=16,
+                            name: "1".into(),
+                            operator: ParameterOperator::Length,
+                            word: Word::new("", vec![]),
+                        },
+                        Expansion::Operator {
+                            range: 17..=24,
+                            name: "10".into(),
+                            operator: ParameterOperator::RemoveSuffix { longest: false },
+                            word: Word::new(
+                                ".*",
+                                vec![Expansion::Glob {
+                                    range: 1..=1,
+                                    pattern: "*".into(),
+                                    recursive: false,
+                                }],
+                            ),
+                        },
+                    ],
+                ))],
+            })],
+        };
+
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn arithmetic_parsing() {
+        let input = "echo $(( (1 + $x) * 2 ))$((0))".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![Meta::Word(Word::new(
+                    "$(( (1 + $x) * 2 ))$((0))",
+                    vec![
+                        Expansion::Arithmetic {
+                            range: 0..=18,
+                            expression: Word::new(
+                                " (1 + $x) * 2 ",
+                                vec![Expansion::Parameter {
+                                    range: 6..=7,
+                                    name: "x".into(),
+                                }],
+                            ),
+                        },
+                        Expansion::Arithmetic {
+                            range: 19..=24,
+                            expression: Word::new("0", vec![]),
+                        },
+                    ],
+                ))],
+            })],
+        };
+
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn if_parsing() {
+        let input = "if a; then b; elif c\nthen if d; then e; fi; else f; fi && g".to_string();
+        let ast = parse(input).unwrap();
+
+        let command = |name: &str| {
+            CommandType::Single(Command {
+                name: Word::new(name, vec![]),
+                prefixes: vec![],
+                suffixes: vec![],
+            })
+        };
+        let list = |commands| SyntaxTree { commands };
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::And(
+                Box::new(CommandType::If {
+                    branches: vec![
+                        (list(vec![command("a")]), list(vec![command("b")])),
+                        (
+                            list(vec![command("c")]),
+                            list(vec![CommandType::If {
+                                branches: vec![(
+                                    list(vec![command("d")]),
+                                    list(vec![command("e")]),
+                                )],
+                                otherwise: None,
+                            }]),
+                        ),
+                    ],
+                    otherwise: Some(list(vec![command("f")])),
+                }),
+                Box::new(command("g")),
+            )],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!(
+            "if a; then b; elif c; then if d; then e; fi; else f; fi && g",
+            ast.to_string()
+        );
+    }
+
+    #[test]
+    fn while_parsing() {
+        let input = "while a; do until b\ndo c; done; done".to_string();
+        let ast = parse(input).unwrap();
+
+        let command = |name: &str| {
+            CommandType::Single(Command {
+                name: Word::new(name, vec![]),
+                prefixes: vec![],
+                suffixes: vec![],
+            })
+        };
+        let list = |commands| SyntaxTree { commands };
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::While {
+                condition: list(vec![command("a")]),
+                body: list(vec![CommandType::While {
+                    condition: list(vec![command("b")]),
+                    body: list(vec![command("c")]),
+                    until: true,
+                }]),
+                until: false,
+            }],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!("while a; do until b; do c; done; done", ast.to_string());
+    }
+
+    #[test]
+    fn for_parsing() {
+        let input = "for x in a \"b c\"; do for y\ndo d; done; done".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::For {
+                name: "x".to_string(),
+                words: Some(vec![
+                    Word::new("a", vec![]),
+                    Word {
+                        quoted: true,
+                        ..Word::new("b c", vec![])
+                    },
+                ]),
+                body: SyntaxTree {
+                    commands: vec![CommandType::For {
+                        name: "y".to_string(),
+                        words: None,
+                        body: SyntaxTree {
+                            commands: vec![CommandType::Single(Command {
+                                name: Word::new("d", vec![]),
+                                prefixes: vec![],
+                                suffixes: vec![],
+                            })],
+                        },
+                    }],
+                },
+            }],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!(
+            "for x in a b c; do for y; do d; done; done",
+            ast.to_string()
+        );
+    }
+
+    #[test]
+    fn case_parsing() {
+        let input = "case $x in\n(a | 'b') c;&\n*) ;;\nesac".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Case {
+                word: Word::new(
+                    "$x",
+                    vec![Expansion::Parameter {
+                        range: 0..=1,
+                        name: "x".to_string(),
+                    }],
+                ),
+                arms: vec![
+                    CaseArm {
+                        patterns: vec![
+                            Word::new("a", vec![]),
+                            Word {
+                                quoted: true,
+                                ..Word::new("b", vec![])
+                            },
+                        ],
+                        body: SyntaxTree {
+                            commands: vec![CommandType::Single(Command {
+                                name: Word::new("c", vec![]),
+                                prefixes: vec![],
+                                suffixes: vec![],
+                            })],
+                        },
+                        fallthrough: true,
+                    },
+                    CaseArm {
+                        patterns: vec![Word::new(
+                            "*",
+                            vec![Expansion::Glob {
+                                range: 0..=0,
+                                recursive: false,
+                                pattern: "*".to_string(),
+                            }],
+                        )],
+                        body: SyntaxTree::new(),
+                        fallthrough: false,
+                    },
+                ],
+            }],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!("case $x in a | b) c;& *) ;; esac", ast.to_string());
+    }
+
+    #[test]
+    fn function_parsing() {
+        let input = "f() {\n a; }; g()\nwhile b; do c; done\nfunction h-i; end".to_string();
+        let ast = parse(input).unwrap();
+
+        let command = |name: &str| {
+            CommandType::Single(Command {
+                name: Word::new(name, vec![]),
+                prefixes: vec![],
+                suffixes: vec![],
+            })
+        };
+        let list = |commands| SyntaxTree { commands };
+
+        let expected = SyntaxTree {
+            commands: vec![
+                CommandType::Function {
+                    name: "f".to_string(),
+                    body: list(vec![command("a")]),
+                },
+                CommandType::Function {
+                    name: "g".to_string(),
+                    body: list(vec![CommandType::While {
+                        condition: list(vec![command("b")]),
+                        body: list(vec![command("c")]),
+                        until: false,
+                    }]),
+                },
+                CommandType::Function {
+                    name: "h-i".to_string(),
+                    body: list(vec![]),
+                },
+            ],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!(
+            "f() { a; }; g() { while b; do c; done; }; h-i() { }",
+            ast.to_string()
+        );
+    }
+
+    #[test]
+    fn group_parsing() {
+        let input = "(a; b) | { c; } 2>e && (if d; then e; fi)".to_string();
+        let ast = parse(input).unwrap();
+
+        let command = |name: &str| {
+            CommandType::Single(Command {
+                name: Word::new(name, vec![]),
+                prefixes: vec![],
+                suffixes: vec![],
+            })
+        };
+        let list = |commands| SyntaxTree { commands };
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::And(
+                Box::new(CommandType::Pipeline(vec![
+                    CommandType::Subshell(list(vec![command("a"), command("b")])),
+                    CommandType::Redirected {
+                        command: Box::new(CommandType::Group(list(vec![command("c")]))),
+                        redirects: vec![Redirect::Output {
+                            from: Some(Word::new("2", vec![])),
+                            to: Word::new("e", vec![]),
+                            append: false,
+                            clobber: false,
+                        }],
+                    },
+                ])),
+                Box::new(CommandType::Subshell(list(vec![CommandType::If {
+                    branches: vec![(list(vec![command("d")]), list(vec![command("e")]))],
+                    otherwise: None,
+                }]))),
+            )],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!("(a; b) | { c; } 2>e && (if d; then e; fi)", ast.to_string());
+    }
 
-            '*' if matches!(expand, ExpansionType::All) => {
-                let mut recursive = false;
-                let mut pattern = '*'.to_string();
-                let start_index = index;
+    #[test]
+    fn here_document_parsing() {
+        let input = "cat <<A <<<$y; cat <<'B'\n$x\nA\n$x\nB".to_string();
+        let ast = parse(input).unwrap();
 
-                while let Some(&c) = chars.peek() {
-                    match c {
-                        '*' => {
-                            chars.next();
-                            index += 1;
-                            recursive = true;
-                            pattern.push('*');
-                        }
+        let expected = SyntaxTree {
+            commands: vec![
+                CommandType::Single(Command {
+                    name: Word::new("cat", vec![]),
+                    prefixes: vec![],
+                    suffixes: vec![
+                        Meta::Redirect(Redirect::HereDocument {
+                            delimiter: "A".to_string(),
+                            body: quoted(
+                                "$x\n",
+                                vec![Expansion::Parameter {
+                                    range: 0..=1,
+                                    name: "x".to_string(),
+                                }],
+                            ),
+                        }),
+                        Meta::Redirect(Redirect::HereString {
+                            word: Word::new(
+                                "$y",
+                                vec![Expansion::Parameter {
+                                    range: 0..=1,
+                                    name: "y".to_string(),
+                                }],
+                            ),
+                        }),
+                    ],
+                }),
+                CommandType::Single(Command {
+                    name: Word::new("cat", vec![]),
+                    prefixes: vec![],
+                    suffixes: vec![Meta::Redirect(Redirect::HereDocument {
+                        delimiter: "'B'".to_string(),
+                        body: quoted("$x\n", vec![]),
+                    })],
+                }),
+            ],
+        };
 
-                        c => {
-                            if " /".contains(c) {
-                                break;
-                            }
-                            pattern.push(c);
-                            chars.next();
-                            index += 1;
-                        }
-                    }
-                }
+        assert_eq!(expected, ast);
+        assert_eq!("cat <<A <<<$y; cat <<'B'", ast.to_string());
+    }
 
-                expansions.push(Expansion::Glob {
-                    pattern,
-                    recursive,
-                    range: start_index..=index,
-                });
-            }
+    #[test]
+    fn duplicating_redirection_parsing() {
+        let input = "cmd 2>&1 &>f 3<&-".to_string();
+        let ast = parse(input).unwrap();
 
-            '~' if matches!(expand, ExpansionType::All)
-                && matches!(prev_char, Some(' ' | '=') | None) =>
-            {
-                match chars.peek() {
-                    Some(' ') | Some('/') | None => {
-                        expansions.push(Expansion::Tilde { index });
-                    }
-                    _ => {}
-                }
-            }
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("cmd", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Redirect(Redirect::Duplicate {
+                        from: Some(Word::new("2", vec![])),
+                        to: Word::new("1", vec![]),
+                        output: true,
+                    }),
+                    Meta::Redirect(Redirect::Both {
+                        to: Word::new("f", vec![]),
+                        append: false,
+                        clobber: false,
+                    }),
+                    Meta::Redirect(Redirect::Duplicate {
+                        from: Some(Word::new("3", vec![])),
+                        to: Word::new("-", vec![]),
+                        output: false,
+                    }),
+                ],
+            })],
+        };
 
-            _ => {}
-        }
-        index += 1;
-        prev_char = Some(ch);
+        assert_eq!(expected, ast);
+        assert_eq!("cmd 2>&1 &>f 3<&-", ast.to_string());
     }
 
-    Word::new(s, expansions)
-}
+    #[test]
+    fn process_substitution_parsing() {
+        let input = "diff <(sort a) \">(z)\"".to_string();
+        let ast = parse(input).unwrap();
 
-fn parse_meta(token: &Token, is_prefix: bool) -> Option<Meta> {
-    match token {
-        Token::String(s) => {
-            if is_prefix {
-                let item = match s.split_once('=') {
-                    Some((var, val)) => {
-                        let var_word = parse_word(var, ExpansionType::None);
-                        let val_word = parse_word(val, ExpansionType::All);
-                        Meta::Assignment(var_word, val_word)
-                    }
-                    None => Meta::Word(parse_word(s, ExpansionType::All)),
-                };
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("diff", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Word(Word::new(
+                        "<(sort a)",
+                        vec![Expansion::Process {
+                            range: 0..=8,
+                            ast: SyntaxTree {
+                                commands: vec![CommandType::Single(Command {
+                                    name: Word::new("sort", vec![]),
+                                    prefixes: vec![],
+                                    suffixes: vec![Meta::Word(Word::new("a", vec![]))],
+                                })],
+                            },
+                            output: false,
+                        }],
+                    )),
+                    Meta::Word(quoted(">(z)", vec![])),
+                ],
+            })],
+        };
 
-                Some(item)
-            } else {
-                Some(Meta::Word(parse_word(s, ExpansionType::All)))
-            }
-        }
+        assert_eq!(expected, ast);
+    }
 
-        Token::SingleQuotedString(s, finished) => {
-            if *finished {
-                let word = parse_word(s, ExpansionType::None);
-                Some(Meta::Word(word))
-            } else {
-                // FIXME: syntax error
-                None
-            }
-        }
+    #[test]
+    fn escape_parsing() {
+        let input = r#"echo \$x "\$y \z" $'\t\x41\u{e9}\101\q'"#.to_string();
+        let ast = parse(input).unwrap();
 
-        Token::DoubleQuotedString(s, finished) => {
-            if *finished {
-                let word = parse_word(s, ExpansionType::VariablesAndCommands);
-                Some(Meta::Word(word))
-            } else {
-                // FIXME: syntax error
-                None
-            }
-        }
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Word(Word::new(r"\$x", vec![Expansion::Escape { range: 0..=1 }])),
+                    Meta::Word(quoted(r"\$y \z", vec![Expansion::Escape { range: 0..=1 }])),
+                    Meta::Word(Word::new("\tAéA\\q", vec![])),
+                ],
+            })],
+        };
 
-        // FIXME: this should probably not always use ExpansionType::All
-        Token::RedirectInput(s) => Some(Meta::Redirect(Redirect::Input {
-            to: parse_word(s, ExpansionType::All),
-        })),
+        assert_eq!(expected, ast);
+    }
 
-        Token::RedirectOutput(from, to, _, append) => {
-            let from = match from {
-                Some(s) => Some(s),
-                None => None,
-            };
-            let to = to.to_string();
-            // FIXME: these should probably not always use ExpansionType::All
-            Some(Meta::Redirect(Redirect::Output {
-                from: from.cloned().map(|s| parse_word(s, ExpansionType::All)),
-                to: parse_word(to, ExpansionType::All),
-                append: *append,
-            }))
-        }
+    #[test]
+    fn quoted_part_parsing() {
+        let input = r#"x="a $y" echo a'b c'd"#.to_string();
+        let ast = parse(input).unwrap();
 
-        _ => unreachable!(),
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![Meta::Assignment(
+                    Word::new("x", vec![]),
+                    Word::new(
+                        r#""a $y""#,
+                        vec![Expansion::Quoted {
+                            range: 0..=5,
+                            word: quoted(
+                                "a $y",
+                                vec![Expansion::Parameter {
+                                    name: "y".into(),
+                                    range: 2..=3,
+                                }],
+                            ),
+                        }],
+                    ),
+                )],
+                suffixes: vec![Meta::Word(Word::new(
+                    "a'b c'd",
+                    vec![Expansion::Quoted {
+                        range: 1..=5,
+                        word: Word::new("b c", vec![]),
+                    }],
+                ))],
+            })],
+        };
+
+        assert_eq!(expected, ast);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn ansi_c_part_parsing() {
+        let input = r"echo a$'\tb'c".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![Meta::Word(Word::new(
+                    r"a$'\tb'c",
+                    vec![Expansion::Quoted {
+                        range: 1..=6,
+                        word: Word::new("\tb", vec![]),
+                    }],
+                ))],
+            })],
+        };
+
+        assert_eq!(expected, ast);
+        assert_eq!(
+            Err(ParseError::new("unterminated quote", 6..10)),
+            parse(r"echo a$'\'")
+        );
+    }
 
     #[test]
-    fn basic_parsing() {
-        let input = "2>&1 echo hello world | lolcat -n;".to_string();
-        let ast = parse(input);
+    fn lone_dollar_parsing() {
+        let input = "echo $ a$".to_string();
+        let ast = parse(input).unwrap();
 
-        println!("{:#?}", &ast);
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Word(Word::new("$", vec![])),
+                    Meta::Word(Word::new("a$", vec![])),
+                ],
+            })],
+        };
 
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn syntax_error_underline() {
+        let error = parse("echo ok && | rev").unwrap_err();
         assert_eq!(
-            SyntaxTree {
-                commands: vec![CommandType::Pipeline(vec![
-                    Command {
-                        name: Word::new("echo", vec![]),
-                        prefixes: vec![Meta::Redirect(Redirect::Output {
-                            from: Some(Word::new("2", vec![])),
-                            to: Word::new("&1", vec![]),
-                            append: false,
-                        }),],
-                        suffixes: vec![
-                            Meta::Word(Word::new("hello", vec![])),
-                            Meta::Word(Word::new("world", vec![])),
-                        ],
-                    },
-                    Command {
-                        name: Word::new("lolcat", vec![]),
-                        prefixes: vec![],
-                        suffixes: vec![Meta::Word(Word::new("-n", vec![])),],
-                    }
-                ]),],
-            },
-            ast
+            "echo ok && | rev\n           ^",
+            error.underline("echo ok && | rev")
         );
     }
 
     #[test]
     fn asterisk_expansion_parsing() {
         let input = "echo **/*.rs".to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
@@ -782,17 +3647,122 @@ mod tests {
         assert_eq!(expected, ast);
     }
 
+    #[test]
+    fn pattern_expansion_parsing() {
+        let input = "ls file?.[ch] [ ]".to_string();
+        let ast = parse(input).unwrap();
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("ls", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    Meta::Word(Word::new(
+                        "file?.[ch]",
+                        vec![Expansion::Glob {
+                            pattern: "?.[ch]".into(),
+                            recursive: false,
+                            range: 4..=9,
+                        }],
+                    )),
+                    Meta::Word(Word::new("[", vec![])),
+                    Meta::Word(Word::new("]", vec![])),
+                ],
+            })],
+        };
+        assert_eq!(expected, ast);
+    }
+
+    #[test]
+    fn brace_expansion_parsing() {
+        let input = "echo x{a,b{c,d}}y {1..3} {c..a} {1..10..4} {01..3} {a} {a..}".to_string();
+        let ast = parse(input).unwrap();
+
+        let brace = |name: &str, end, alternatives: &[&str]| {
+            Meta::Word(Word::new(
+                name,
+                vec![Expansion::Brace {
+                    range: name.find('{').unwrap()..=end,
+                    alternatives: alternatives.iter().map(ToString::to_string).collect(),
+                }],
+            ))
+        };
+
+        let expected = SyntaxTree {
+            commands: vec![CommandType::Single(Command {
+                name: Word::new("echo", vec![]),
+                prefixes: vec![],
+                suffixes: vec![
+                    brace("x{a,b{c,d}}y", 10, &["a", "bc", "bd"]),
+                    brace("{1..3}", 5, &["1", "2", "3"]),
+                    brace("{c..a}", 5, &["c", "b", "a"]),
+                    brace("{1..10..4}", 9, &["1", "5", "9"]),
+                    brace("{01..3}", 6, &["01", "02", "03"]),
+                    Meta::Word(Word::new("{a}", vec![])),
+                    Meta::Word(Word::new("{a..}", vec![])),
+                ],
+            })],
+        };
+
+        assert_eq!(expected, ast);
+
+        let CommandType::Single(cmd) = &ast.commands[0] else {
+            unreachable!()
+        };
+        let Meta::Word(word) = &cmd.suffixes[0] else {
+            unreachable!()
+        };
+        assert_eq!(vec!["xay", "xbcy", "xbdy"], word.brace_variants());
+    }
+
+    #[test]
+    fn brace_sequence_limits() {
+        assert_eq!(
+            Some(vec!["1".to_string()]),
+            brace_sequence("1..2..-9223372036854775808")
+        );
+        assert_eq!(
+            Some(vec![
+                "-9000000000000000000".to_string(),
+                "-5000000000000000000".to_string(),
+                "-1000000000000000000".to_string(),
+                "3000000000000000000".to_string(),
+                "7000000000000000000".to_string(),
+            ]),
+            brace_sequence("-9000000000000000000..9000000000000000000..4000000000000000000")
+        );
+        assert_eq!(
+            Some(vec!["9223372036854775807".to_string(), "-1".to_string()]),
+            brace_sequence("9223372036854775807..-9223372036854775808..-9223372036854775808")
+        );
+        assert_eq!(None, brace_sequence("1..9999999999999"));
+        assert_eq!(None, expand_braces("{1..1000}{1..1000}"));
+        assert_eq!(None, parse_brace("a,{1..1000}{1..1000}}"));
+        assert_eq!(
+            Ok(Word::new("{1..1000}{1..1000}", vec![])),
+            parse_word("{1..1000}{1..1000}", ExpansionType::All, 0)
+        );
+        assert_eq!(
+            1000,
+            parse_word("{1..10}{1..100}", ExpansionType::All, 0)
+                .unwrap()
+                .brace_variants()
+                .len()
+        );
+        assert_eq!(None, brace_sequence("1..99999999999999999999"));
+    }
+
     #[test]
     fn variable_expansion_parsing() {
         let input = "echo \"yo $foo $A\"".to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         assert_eq!(
             SyntaxTree {
                 commands: vec![CommandType::Single(Command {
                     name: Word::new("echo", vec![]),
                     prefixes: vec![],
-                    suffixes: vec![Meta::Word(Word::new(
+                    suffixes: vec![Meta::Word(quoted(
                         "yo $foo $A",
                         vec![
                             Expansion::Parameter {
@@ -814,7 +3784,7 @@ mod tests {
     #[test]
     fn single_quote_doesnt_expand_parsing() {
         let input = "echo '** $foo'".to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
@@ -830,48 +3800,52 @@ mod tests {
     #[test]
     fn nested_pipeline_parsing() {
         let input = r#"echo "I \"am\": $(whoami | rev | grep -o -v foo)" | less"#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Pipeline(vec![
-                Command {
+                CommandType::Single(Command {
                     name: Word::new("echo", vec![]),
                     prefixes: vec![],
-                    suffixes: vec![Meta::Word(Word::new(
-                        "I \"am\": $(whoami | rev | grep -o -v foo)",
-                        vec![Expansion::Command {
-                            range: 8..=39,
-                            ast: SyntaxTree {
-                                commands: vec![CommandType::Pipeline(vec![
-                                    Command {
-                                        name: Word::new("whoami", vec![]),
-                                        prefixes: vec![],
-                                        suffixes: vec![],
-                                    },
-                                    Command {
-                                        name: Word::new("rev", vec![]),
-                                        prefixes: vec![],
-                                        suffixes: vec![],
-                                    },
-                                    Command {
-                                        name: Word::new("grep", vec![]),
-                                        prefixes: vec![],
-                                        suffixes: vec![
-                                            Meta::Word(Word::new("-o", vec![])),
-                                            Meta::Word(Word::new("-v", vec![])),
-                                            Meta::Word(Word::new("foo", vec![])),
-                                        ],
-                                    },
-                                ])],
+                    suffixes: vec![Meta::Word(quoted(
+                        r#"I \"am\": $(whoami | rev | grep -o -v foo)"#,
+                        vec![
+                            Expansion::Escape { range: 2..=3 },
+                            Expansion::Escape { range: 6..=7 },
+                            Expansion::Command {
+                                range: 10..=41,
+                                ast: SyntaxTree {
+                                    commands: vec![CommandType::Pipeline(vec![
+                                        CommandType::Single(Command {
+                                            name: Word::new("whoami", vec![]),
+                                            prefixes: vec![],
+                                            suffixes: vec![],
+                                        }),
+                                        CommandType::Single(Command {
+                                            name: Word::new("rev", vec![]),
+                                            prefixes: vec![],
+                                            suffixes: vec![],
+                                        }),
+                                        CommandType::Single(Command {
+                                            name: Word::new("grep", vec![]),
+                                            prefixes: vec![],
+                                            suffixes: vec![
+                                                Meta::Word(Word::new("-o", vec![])),
+                                                Meta::Word(Word::new("-v", vec![])),
+                                                Meta::Word(Word::new("foo", vec![])),
+                                            ],
+                                        }),
+                                    ])],
+                                },
                             },
-                        }],
+                        ],
                     ))],
-                },
-                Command {
+                }),
+                CommandType::Single(Command {
                     name: Word::new("less", vec![]),
                     prefixes: vec![],
                     suffixes: vec![],
-                },
+                }),
             ])],
         };
 
@@ -881,22 +3855,22 @@ mod tests {
     #[test]
     fn complicated_parsing() {
         let input = r#"CMD=exec=async 2>&1 grep ": $(whoami)" ~/.cache/ | xargs -I {} echo "$CMD: {}" >foo.log"#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Pipeline(vec![
-                Command {
+                CommandType::Single(Command {
                     name: Word::new("grep", vec![]),
                     prefixes: vec![
                         Meta::Assignment(Word::new("CMD", vec![]), Word::new("exec=async", vec![])),
-                        Meta::Redirect(Redirect::Output {
+                        Meta::Redirect(Redirect::Duplicate {
                             from: Some(Word::new("2", vec![])),
-                            to: Word::new("&1", vec![]),
-                            append: false,
+                            to: Word::new("1", vec![]),
+                            output: true,
                         }),
                     ],
                     suffixes: vec![
-                        Meta::Word(Word::new(
+                        Meta::Word(quoted(
                             ": $(whoami)",
                             vec![Expansion::Command {
                                 range: 2..=10,
@@ -911,15 +3885,15 @@ mod tests {
                         )),
                         Meta::Word(Word::new("~/.cache/", vec![Expansion::Tilde { index: 0 }])),
                     ],
-                },
-                Command {
+                }),
+                CommandType::Single(Command {
                     name: Word::new("xargs", vec![]),
                     prefixes: vec![],
                     suffixes: vec![
                         Meta::Word(Word::new("-I", vec![])),
                         Meta::Word(Word::new("{}", vec![])),
                         Meta::Word(Word::new("echo", vec![])),
-                        Meta::Word(Word::new(
+                        Meta::Word(quoted(
                             "$CMD: {}",
                             vec![Expansion::Parameter {
                                 name: "CMD".into(),
@@ -930,9 +3904,10 @@ mod tests {
                             from: None,
                             to: Word::new("foo.log", vec![]),
                             append: false,
+                            clobber: false,
                         }),
                     ],
-                },
+                }),
             ])],
         };
 
@@ -942,13 +3917,13 @@ mod tests {
     #[test]
     fn basic_command_expansion_parsing() {
         let input = r#"echo "bat: $(cat /sys/class/power_supply/BAT0/capacity)""#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
                 name: Word::new("echo", vec![]),
                 prefixes: vec![],
-                suffixes: vec![Meta::Word(Word::new(
+                suffixes: vec![Meta::Word(quoted(
                     "bat: $(cat /sys/class/power_supply/BAT0/capacity)",
                     vec![Expansion::Command {
                         range: 5..=48,
@@ -973,7 +3948,7 @@ mod tests {
     #[test]
     fn tilde_expansion_parsing() {
         let input = "ls ~ ~/ ~/foo foo~ bar/~ ./~ ~% ~baz".to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
@@ -1002,13 +3977,13 @@ mod tests {
     #[test]
     fn nested_quotes_in_command_expansion_parsing() {
         let input = r#"echo "bat: $(cat "/sys/class/power_supply/BAT0/capacity")""#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
                 name: Word::new("echo", vec![]),
                 prefixes: vec![],
-                suffixes: vec![Meta::Word(Word::new(
+                suffixes: vec![Meta::Word(quoted(
                     "bat: $(cat \"/sys/class/power_supply/BAT0/capacity\")",
                     vec![Expansion::Command {
                         range: 5..=50,
@@ -1016,7 +3991,7 @@ mod tests {
                             commands: vec![CommandType::Single(Command {
                                 name: Word::new("cat", vec![]),
                                 prefixes: vec![],
-                                suffixes: vec![Meta::Word(Word::new(
+                                suffixes: vec![Meta::Word(quoted(
                                     "/sys/class/power_supply/BAT0/capacity",
                                     vec![],
                                 ))],
@@ -1033,13 +4008,13 @@ mod tests {
     #[test]
     fn nested_commands_parsing() {
         let input = r#"echo "foo: $(echo "$(whoami | lolcat)") yo""#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
                 name: Word::new("echo", vec![]),
                 prefixes: vec![],
-                suffixes: vec![Meta::Word(Word::new(
+                suffixes: vec![Meta::Word(quoted(
                     r#"foo: $(echo "$(whoami | lolcat)") yo"#,
                     vec![Expansion::Command {
                         range: 5..=32,
@@ -1047,22 +4022,22 @@ mod tests {
                             commands: vec![CommandType::Single(Command {
                                 name: Word::new("echo", vec![]),
                                 prefixes: vec![],
-                                suffixes: vec![Meta::Word(Word::new(
+                                suffixes: vec![Meta::Word(quoted(
                                     "$(whoami | lolcat)",
                                     vec![Expansion::Command {
                                         range: 0..=17,
                                         ast: SyntaxTree {
                                             commands: vec![CommandType::Pipeline(vec![
-                                                Command {
+                                                CommandType::Single(Command {
                                                     name: Word::new("whoami", vec![]),
                                                     prefixes: vec![],
                                                     suffixes: vec![],
-                                                },
-                                                Command {
+                                                }),
+                                                CommandType::Single(Command {
                                                     name: Word::new("lolcat", vec![]),
                                                     prefixes: vec![],
                                                     suffixes: vec![],
-                                                },
+                                                }),
                                             ])],
                                         },
                                     }],
@@ -1080,7 +4055,7 @@ mod tests {
     #[test]
     fn command_expansion_without_quotes_parsing() {
         let input = "echo $(cat $(echo $(cat foo | rev) )) bar".to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
@@ -1110,29 +4085,33 @@ mod tests {
                                                             ast: SyntaxTree {
                                                                 commands: vec![
                                                                     CommandType::Pipeline(vec![
-                                                                        Command {
-                                                                            name: Word::new(
-                                                                                "cat",
-                                                                                vec![],
-                                                                            ),
-                                                                            prefixes: vec![],
-                                                                            suffixes: vec![
-                                                                                Meta::Word(
-                                                                                    Word::new(
-                                                                                        "foo",
-                                                                                        vec![],
+                                                                        CommandType::Single(
+                                                                            Command {
+                                                                                name: Word::new(
+                                                                                    "cat",
+                                                                                    vec![],
+                                                                                ),
+                                                                                prefixes: vec![],
+                                                                                suffixes: vec![
+                                                                                    Meta::Word(
+                                                                                        Word::new(
+                                                                                            "foo",
+                                                                                            vec![],
+                                                                                        ),
                                                                                     ),
+                                                                                ],
+                                                                            },
+                                                                        ),
+                                                                        CommandType::Single(
+                                                                            Command {
+                                                                                name: Word::new(
+                                                                                    "rev",
+                                                                                    vec![],
                                                                                 ),
-                                                                            ],
-                                                                        },
-                                                                        Command {
-                                                                            name: Word::new(
-                                                                                "rev",
-                                                                                vec![],
-                                                                            ),
-                                                                            prefixes: vec![],
-                                                                            suffixes: vec![],
-                                                                        },
+                                                                                prefixes: vec![],
+                                                                                suffixes: vec![],
+                                                                            },
+                                                                        ),
                                                                     ]),
                                                                 ],
                                                             },
@@ -1157,13 +4136,13 @@ mod tests {
     #[test]
     fn multiple_nested_command_expansions_parsing() {
         let input = r#"echo "$(cat $(echo "$(cat foo)"))""#.to_string();
-        let ast = parse(input);
+        let ast = parse(input).unwrap();
 
         let expected = SyntaxTree {
             commands: vec![CommandType::Single(Command {
                 name: Word::new("echo", vec![]),
                 prefixes: vec![],
-                suffixes: vec![Meta::Word(Word::new(
+                suffixes: vec![Meta::Word(quoted(
                     r#"$(cat $(echo "$(cat foo)"))"#,
                     vec![Expansion::Command {
                         range: 0..=26,
@@ -1179,7 +4158,7 @@ mod tests {
                                             commands: vec![CommandType::Single(Command {
                                                 name: Word::new("echo", vec![]),
                                                 prefixes: vec![],
-                                                suffixes: vec![Meta::Word(Word::new(
+                                                suffixes: vec![Meta::Word(quoted(
                                                     "$(cat foo)",
                                                     vec![Expansion::Command {
                                                         range: 0..=9,
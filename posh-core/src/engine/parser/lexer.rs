@@ -1,5 +1,7 @@
 use std::iter::Peekable;
-use std::str::Chars;
+use std::mem;
+use std::ops::Range;
+use std::str::CharIndices;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Token {
@@ -7,24 +9,98 @@ pub enum Token {
     SingleQuotedString(String, bool),
     DoubleQuotedString(String, bool),
 
-    RedirectOutput(Option<String>, String, Option<String>, bool),
-    RedirectInput(String),
+    /// `$'...'`, with its backslash escapes as they were written. Like for the
+    /// other quotes, the second field is whether it was closed.
+    AnsiCString(String, bool),
+
+    /// `n>file`, where `n` is `&` for `&>file`, and the file starts with `&` for
+    /// `n>&m`. The third field is the space before the file, if any, and the last
+    /// two are whether it is `n>>file`, which appends, or `n>|file`, which
+    /// overwrites the file even with `noclobber` set.
+    RedirectOutput(Option<String>, String, Option<String>, bool, bool),
+
+    /// `n<file`, where the file starts with `&` for `n<&m`.
+    RedirectInput(Option<String>, String),
+
+    /// `<<delimiter` or `<<-delimiter`, which redirects stdin to the lines after
+    /// the current one, up until the line that is just the delimiter. `<<-` strips
+    /// the leading tabs of every line. The lines are `None` if the input ends
+    /// before the delimiter does.
+    HereDocument {
+        delimiter: String,
+        strip_tabs: bool,
+        body: Option<String>,
+    },
+
+    /// `<<<word`, which redirects stdin to the word followed by a newline.
+    HereString(Box<Token>),
+
     Pipe,
 
     And,
     Or,
 
+    /// A reserved word like `if`, which is only recognized where a command
+    /// name could be, so that `echo if` is still just an argument.
+    Keyword(String),
+
     Semicolon,
     Ampersand,
+    Newline,
+
+    LParen,
+    RParen,
+
+    /// `{` and `}`, which group commands, and like keywords are only recognized
+    /// where a command name could be.
+    LBrace,
+    RBrace,
+
+    /// `;;`, which ends an arm of a `case` command.
+    DoubleSemicolon,
+
+    /// `;&`, which ends an arm of a `case` command and falls through to the next one.
+    SemicolonAmpersand,
 
     Space,
+
+    /// `# ...`, up until the end of the line. Like spaces, comments are only
+    /// kept when whitespace is included.
+    Comment(String),
+}
+
+/// The reserved words of the shell.
+pub const KEYWORDS: [&str; 15] = [
+    "if", "then", "elif", "else", "fi", "while", "until", "for", "in", "do", "done", "case",
+    "esac", "function", "end",
+];
+
+/// Whether a command name can follow `keyword`, such as after `then` but not after `fi`.
+fn keyword_starts_command(keyword: &str) -> bool {
+    !matches!(
+        keyword,
+        "fi" | "done" | "for" | "in" | "case" | "esac" | "function" | "end"
+    )
 }
 
 impl Token {
+    /// The keyword this token is, including the braces and parentheses that
+    /// group commands, or an empty string if it is not a keyword.
+    pub fn keyword(&self) -> &str {
+        match self {
+            Token::Keyword(keyword) => keyword,
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            _ => "",
+        }
+    }
+
     pub fn try_get_assignment(&self) -> Option<(String, Option<String>)> {
         match self {
             Token::String(s) => match s.split_once('=') {
-                Some((a, b)) if b.is_empty() => Some((a.to_string(), None)),
+                Some((a, "")) => Some((a.to_string(), None)),
                 Some((a, b)) => Some((a.to_string(), Some(b.to_string()))),
                 None => None,
             },
@@ -33,65 +109,181 @@ impl Token {
     }
 }
 
+/// The characters of the input, along with the byte offset of the next one.
+#[derive(Clone)]
+struct Chars<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+}
+
+impl<'a> Chars<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.char_indices().peekable(),
+            len: input.len(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek().map(|(_, c)| c)
+    }
+
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map_or(self.len, |&(i, _)| i)
+    }
+}
+
+impl Iterator for Chars<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chars.next().map(|(_, c)| c)
+    }
+}
+
 pub fn lex(input: impl AsRef<str>, include_whitespace: bool) -> Vec<Token> {
+    lex_with_spans(input, include_whitespace)
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect()
+}
+
+/// Whether the latest of `tokens` separates commands, like `;`.
+fn follows_separator(tokens: &[(Token, Range<usize>)]) -> bool {
+    matches!(
+        tokens.iter().rev().find(|(t, _)| *t != Token::Space),
+        Some((Token::Semicolon | Token::Newline | Token::Ampersand, _))
+    )
+}
+
+/// Whether a `#` at `offset` in `input` starts a comment, which it only does at
+/// the start of a word.
+fn starts_comment(input: &str, offset: usize) -> bool {
+    input[..offset]
+        .chars()
+        .next_back()
+        .is_none_or(|c| " \t\n;&|()<>".contains(c))
+}
+
+/// Like [`lex`], but also returns the byte range in `input` of every token.
+pub fn lex_with_spans(
+    input: impl AsRef<str>,
+    include_whitespace: bool,
+) -> Vec<(Token, Range<usize>)> {
+    let input = input.as_ref();
     let mut tokens = Vec::new();
 
-    let mut chars = input.as_ref().chars().peekable();
+    let mut chars = Chars::new(input);
+
+    // Whether the next word would be the name of a command, which is the only
+    // place where keywords are recognized.
+    let mut command_position = true;
+
+    // How many words are left until the one that is `in` in `for name in` and
+    // `case word in`, which is a keyword even though it is not in command position.
+    let mut words_until_in = 0_u8;
 
-    while let Some(ch) = chars.next() {
-        match ch {
-            ' ' if include_whitespace => tokens.push(Token::Space),
-            ' ' => {}
+    // The here-documents whose contents start after the next newline
+    let mut here_documents = Vec::new();
 
-            '<' => {
-                if let Some(token) = try_lex_redirect_input(&mut chars) {
-                    tokens.push(token);
+    loop {
+        let start = chars.offset();
+
+        let Some(ch) = chars.next() else {
+            break;
+        };
+
+        let token = match ch {
+            ' ' | '\t' if include_whitespace => Some(Token::Space),
+            ' ' | '\t' => None,
+
+            '#' if starts_comment(input, start) => {
+                let mut comment = String::new();
+                while let Some(c) = chars.peek().filter(|&&c| c != '\n') {
+                    comment.push(*c);
+                    chars.next();
                 }
+
+                include_whitespace.then_some(Token::Comment(comment))
+            }
+
+            // A line continuation, which joins the lines around it
+            '\\' if chars.peek() == Some(&'\n') => {
+                chars.next();
+                None
+            }
+
+            '<' | '>' if chars.peek() == Some(&'(') => {
+                Some(lex_string(&mut chars, Some(ch), false))
+            }
+
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                Some(lex_ansi_c(&mut chars))
+            }
+
+            // Whatever is escaped is part of the word, even if it would end it
+            '\\' => {
+                let mut s = String::from(ch);
+                s.extend(chars.next());
+                Some(lex_string(&mut chars, Some(s), false))
+            }
+
+            '<' if chars.peek() == Some(&'<') => {
+                chars.next();
+                try_lex_here(&mut chars)
             }
 
+            '<' => try_lex_redirect_input(&mut chars, None),
+
             '>' => {
                 let append = chars.peek() == Some(&'>');
-                if let Some(token) = try_lex_redirect_output(&mut chars, None, append) {
-                    tokens.push(token);
-                }
+                try_lex_redirect_output(&mut chars, None, append)
             }
 
             '|' => {
                 if let Some(&'|') = chars.peek() {
                     chars.next();
-                    tokens.push(Token::Or);
+                    Some(Token::Or)
                 } else {
-                    tokens.push(Token::Pipe);
+                    Some(Token::Pipe)
                 }
             }
 
-            '"' => match advance_until(&mut chars, '"', true) {
-                Ok(s) => tokens.push(Token::DoubleQuotedString(s, true)),
-                Err(s) => {
-                    // FIXME: this is a syntax error, but is needed for syntax highlighting
-                    tokens.push(Token::DoubleQuotedString(s, false));
+            '"' | '\'' => Some(lex_quoted(&mut chars, ch)),
+
+            ';' => match chars.peek() {
+                Some(&';') => {
+                    chars.next();
+                    Some(Token::DoubleSemicolon)
                 }
-            },
 
-            '\'' => match advance_until(&mut chars, '\'', false) {
-                Ok(s) => tokens.push(Token::SingleQuotedString(s, true)),
-                Err(s) => {
-                    // FIXME: this is a syntax error, but is needed for syntax highlighting
-                    tokens.push(Token::SingleQuotedString(s, false));
+                Some(&'&') => {
+                    chars.next();
+                    Some(Token::SemicolonAmpersand)
                 }
+
+                _ => Some(Token::Semicolon),
             },
 
-            ';' => tokens.push(Token::Semicolon),
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+
+            '\n' => Some(Token::Newline),
 
             '&' => match chars.peek() {
                 Some(&'&') => {
                     chars.next();
-                    tokens.push(Token::And);
+                    Some(Token::And)
                 }
 
-                _ => {
-                    tokens.push(Token::Ampersand);
+                Some(&'>') => {
+                    chars.next();
+                    let append = chars.peek() == Some(&'>');
+                    try_lex_redirect_output(&mut chars, Some("&".to_string()), append)
                 }
+
+                _ => Some(Token::Ampersand),
             },
 
             c if c.is_ascii_digit() => {
@@ -106,7 +298,7 @@ pub fn lex(input: impl AsRef<str>, include_whitespace: bool) -> Vec<Token> {
                     }
                 }
 
-                let token = match chars.peek() {
+                match chars.peek() {
                     Some(&'>') => {
                         chars.next();
                         let append = chars.peek() == Some(&'>');
@@ -115,20 +307,77 @@ pub fn lex(input: impl AsRef<str>, include_whitespace: bool) -> Vec<Token> {
 
                     Some(&'<') => {
                         chars.next();
-                        try_lex_redirect_input(&mut chars)
+                        try_lex_redirect_input(&mut chars, Some(fd))
                     }
 
                     _ => Some(lex_string(&mut chars, Some(fd), false)),
-                };
+                }
+            }
 
-                if let Some(token) = token {
-                    tokens.push(token);
+            c => match lex_string(&mut chars, Some(c), false) {
+                // A word right before a `)` is a pattern of a `case` command,
+                // unless it ends a compound command at the end of a subshell.
+                Token::String(s)
+                    if command_position
+                        && KEYWORDS.contains(&s.as_str())
+                        && (chars.peek() != Some(&')')
+                            || follows_separator(&tokens)
+                                && matches!(s.as_str(), "fi" | "done" | "esac" | "end")) =>
+                {
+                    Some(Token::Keyword(s))
                 }
+                Token::String(s) if command_position && s == "{" => Some(Token::LBrace),
+                Token::String(s) if command_position && s == "}" => Some(Token::RBrace),
+                Token::String(s) if words_until_in == 1 && s == "in" => Some(Token::Keyword(s)),
+                token => Some(token),
+            },
+        };
+
+        if let Some(token) = token {
+            command_position = match &token {
+                Token::Pipe
+                | Token::And
+                | Token::Or
+                | Token::Semicolon
+                | Token::Ampersand
+                | Token::Newline
+                | Token::LParen
+                | Token::RParen
+                | Token::LBrace
+                | Token::DoubleSemicolon
+                | Token::SemicolonAmpersand => true,
+                Token::Keyword(keyword) => keyword_starts_command(keyword),
+                Token::Space | Token::Comment(_) => command_position,
+                _ => false,
+            };
+
+            words_until_in = match &token {
+                Token::Keyword(keyword) if keyword == "for" || keyword == "case" => 2,
+                Token::Space | Token::Comment(_) => words_until_in,
+                _ => words_until_in.saturating_sub(1),
+            };
+
+            let is_newline = token == Token::Newline;
+            if matches!(&token, Token::HereDocument { delimiter, .. } if !delimiter.is_empty()) {
+                here_documents.push(tokens.len());
             }
 
-            c => {
-                let token = lex_string(&mut chars, Some(c), false);
-                tokens.push(token);
+            tokens.push((token, start..chars.offset()));
+
+            if is_newline {
+                for i in here_documents.drain(..) {
+                    if let (
+                        Token::HereDocument {
+                            delimiter,
+                            strip_tabs,
+                            body,
+                        },
+                        _,
+                    ) = &mut tokens[i]
+                    {
+                        *body = read_here_document(&mut chars, delimiter, *strip_tabs);
+                    }
+                }
             }
         }
     }
@@ -136,19 +385,65 @@ pub fn lex(input: impl AsRef<str>, include_whitespace: bool) -> Vec<Token> {
     tokens
 }
 
-fn advance_until(
-    chars: &mut Peekable<Chars>,
-    end: char,
-    check_nested_level: bool,
-) -> Result<String, String> {
+/// Lexes a quoted string after its opening `quote`. If the word goes on after
+/// the closing quote, like in `"a b"c`, all of it is lexed as one string.
+fn lex_quoted(chars: &mut Chars, quote: char) -> Token {
+    let double_quoted = quote == '"';
+    let start = chars.clone();
+
+    match advance_until(chars, quote, double_quoted) {
+        Ok(_) if continues_word(chars) => {
+            let word = format!("{quote}{}", text_between(start, chars));
+            lex_string(chars, Some(word), false)
+        }
+        Ok(s) if double_quoted => Token::DoubleQuotedString(s, true),
+        Ok(s) => Token::SingleQuotedString(s, true),
+
+        // This is a syntax error, but is needed for syntax highlighting
+        Err(s) if double_quoted => Token::DoubleQuotedString(s, false),
+        Err(s) => Token::SingleQuotedString(s, false),
+    }
+}
+
+/// Whether the next character is a part of the same word.
+fn continues_word(chars: &mut Chars) -> bool {
+    chars.peek().is_some_and(|&c| !"<> \t;|\n&()".contains(c))
+}
+
+/// The text from `start` up to where `end` is, as it was written.
+fn text_between(mut start: Chars, end: &mut Chars) -> String {
+    let end = end.offset();
+    let mut s = String::new();
+    while start.offset() < end {
+        s.extend(start.next());
+    }
+    s
+}
+
+/// Lexes the quoted part of a word at the start of `s`, which comes after its
+/// opening `quote`. Returns what is inside of it, like a quoted string on its
+/// own would have it, along with its length in characters including the
+/// closing quote, or `None` if it is unterminated.
+pub(super) fn lex_quoted_part(s: &str, quote: char) -> Option<(String, usize)> {
+    let mut chars = Chars::new(s);
+    let inside = advance_until(&mut chars, quote, quote == '"').ok()?;
+    Some((inside, s[..chars.offset()].chars().count()))
+}
+
+/// Advances past the next unescaped `end`, returning what came before it, or
+/// `Err` with all of it if there was none. Inside double quotes, the `end` can
+/// also be inside of a `$(...)` or a `${...}`, and escapes are kept for the parser.
+fn advance_until(chars: &mut Chars, end: char, double_quoted: bool) -> Result<String, String> {
     let mut s = String::new();
 
     let mut is_escaped = false;
     let mut nested_level = 0;
+    let mut brace_level = 0;
     let mut finished = false;
 
     while let Some(&next) = chars.peek() {
-        if !is_escaped && (nested_level == 0 || !check_nested_level) && next == end {
+        let nested = nested_level > 0 || brace_level > 0;
+        if !is_escaped && (!nested || !double_quoted) && next == end {
             chars.next();
             finished = true;
             break;
@@ -157,19 +452,39 @@ fn advance_until(
         if !is_escaped && next == '\\' {
             is_escaped = true;
             chars.next();
+            if double_quoted {
+                s.push(next);
+            }
             continue;
         }
 
         let next = chars.next().unwrap();
         s.push(next);
-        is_escaped = false;
+        let was_escaped = mem::take(&mut is_escaped);
 
-        if next == '$' {
-            if let Some(&'(') = chars.peek() {
+        if !double_quoted || was_escaped || nested_level > 0 {
+            if next == '(' && (nested_level > 0 || s.ends_with("$(")) {
                 nested_level += 1;
+            } else if next == ')' && nested_level > 0 {
+                nested_level -= 1;
             }
-        } else if next == ')' {
-            nested_level -= 1;
+            continue;
+        }
+
+        match next {
+            '(' if opens_expansion(&s) => nested_level += 1,
+            '{' if brace_level > 0 || opens_expansion(&s) => brace_level += 1,
+            '}' if brace_level > 0 => brace_level -= 1,
+
+            // Quotes inside of a `${...}` are a part of it, along with what
+            // is inside of them
+            '"' | '\'' if brace_level > 0 => {
+                let start = chars.clone();
+                let _ = advance_until(chars, next, next == '"');
+                s.push_str(&text_between(start, chars));
+            }
+
+            _ => {}
         }
     }
 
@@ -180,83 +495,247 @@ fn advance_until(
     }
 }
 
-fn try_lex_redirect_input(chars: &mut Peekable<Chars>) -> Option<Token> {
-    if let Some(&' ') = chars.peek() {
+/// Whether `s` ends with the `$(` or `${` of an expansion, rather than with an
+/// escaped `$` followed by a bracket.
+fn opens_expansion(s: &str) -> bool {
+    let mut chars = s.chars().rev().skip(1);
+    chars.next() == Some('$') && chars.take_while(|&c| c == '\\').count() % 2 == 0
+}
+
+/// Lexes a `$'...'` after its opening quote. Like with [`lex_quoted`], all of
+/// the word is lexed as one string if it goes on after the closing quote.
+fn lex_ansi_c(chars: &mut Chars) -> Token {
+    let start = chars.clone();
+
+    match lex_ansi_c_string(chars) {
+        Token::AnsiCString(_, true) if continues_word(chars) => {
+            let word = format!("$'{}", text_between(start, chars));
+            lex_string(chars, Some(word), false)
+        }
+        token => token,
+    }
+}
+
+/// Like [`lex_quoted_part`], but for the part of a word after a `$'`, which is
+/// returned with its escapes still in it.
+pub(super) fn lex_ansi_c_part(s: &str) -> Option<(String, usize)> {
+    let mut chars = Chars::new(s);
+    match lex_ansi_c_string(&mut chars) {
+        Token::AnsiCString(inside, true) => Some((inside, s[..chars.offset()].chars().count())),
+        _ => None,
+    }
+}
+
+/// Lexes the rest of a `$'...'`, after the opening quote.
+fn lex_ansi_c_string(chars: &mut Chars) -> Token {
+    let mut s = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => return Token::AnsiCString(s, true),
+            '\\' => {
+                s.push(c);
+                s.extend(chars.next());
+            }
+            c => s.push(c),
+        }
+    }
+
+    Token::AnsiCString(s, false)
+}
+
+fn try_lex_redirect_input(chars: &mut Chars, dest: Option<String>) -> Option<Token> {
+    if let Some(' ' | '\t') = chars.peek() {
         chars.next();
     }
 
-    if let Token::String(s) = lex_string(chars, None::<char>, false) {
-        let token = Token::RedirectInput(s);
+    if let Token::String(s) = lex_string(chars, None::<char>, true) {
+        let token = Token::RedirectInput(dest, s);
         Some(token)
     } else {
         None
     }
 }
 
-fn try_lex_redirect_output(
-    chars: &mut Peekable<Chars>,
-    dest: Option<String>,
-    append: bool,
-) -> Option<Token> {
-    if append && chars.peek() == Some(&'>') {
+/// Lexes what follows `<<`, which is a here-document, or a here-string if
+/// there is a third `<`.
+fn try_lex_here(chars: &mut Chars) -> Option<Token> {
+    if chars.peek() == Some(&'<') {
         chars.next();
+        while let Some(' ' | '\t') = chars.peek() {
+            chars.next();
+        }
+
+        let word = match chars.peek().copied() {
+            Some(quote @ ('\'' | '"')) => {
+                chars.next();
+                lex_quoted(chars, quote)
+            }
+            Some('$') if chars.clone().nth(1) == Some('\'') => {
+                chars.nth(1);
+                lex_ansi_c(chars)
+            }
+            _ => lex_string(chars, None::<char>, false),
+        };
+
+        return Some(Token::HereString(Box::new(word)));
     }
 
-    let mut found_space = None;
-    while let Some(&' ') = chars.peek() {
+    let strip_tabs = chars.peek() == Some(&'-');
+    if strip_tabs {
+        chars.next();
+    }
+
+    if let Some(' ' | '\t') = chars.peek() {
         chars.next();
-        if found_space.is_none() {
-            found_space = Some(" ".to_string());
+    }
+
+    match lex_string(chars, None::<char>, false) {
+        Token::String(delimiter) => Some(Token::HereDocument {
+            delimiter,
+            strip_tabs,
+            body: None,
+        }),
+        _ => None,
+    }
+}
+
+/// Reads the lines of a here-document up to and including the one that is just
+/// `delimiter`, without any quotes, returning `None` if there is no such line.
+fn read_here_document(chars: &mut Chars, delimiter: &str, strip_tabs: bool) -> Option<String> {
+    let delimiter = delimiter.replace(['\'', '"', '\\'], "");
+    let mut body = String::new();
+
+    loop {
+        chars.peek()?;
+
+        let mut line = String::new();
+        for c in chars.by_ref() {
+            if c == '\n' {
+                break;
+            }
+            line.push(c);
+        }
+
+        let line = if strip_tabs {
+            line.trim_start_matches('\t')
         } else {
-            found_space = found_space.take().map(|s| s + " ");
+            &line
+        };
+
+        if line == delimiter {
+            return Some(body);
         }
+
+        body.push_str(line);
+        body.push('\n');
+    }
+}
+
+fn try_lex_redirect_output(chars: &mut Chars, dest: Option<String>, append: bool) -> Option<Token> {
+    if append && chars.peek() == Some(&'>') {
+        chars.next();
+    }
+
+    let clobber = !append && chars.peek() == Some(&'|');
+    if clobber {
+        chars.next();
+    }
+
+    let mut found_space = None;
+    while let Some(&c @ (' ' | '\t')) = chars.peek() {
+        chars.next();
+        found_space.get_or_insert_with(String::new).push(c);
     }
 
     if let Token::String(s) = lex_string(chars, None::<char>, true) {
-        let token = Token::RedirectOutput(dest, s, found_space, append);
+        let token = Token::RedirectOutput(dest, s, found_space, append, clobber);
         Some(token)
     } else {
         None
     }
 }
 
-fn lex_string(
-    chars: &mut Peekable<Chars>,
-    start: Option<impl ToString>,
-    allow_ampersand: bool,
-) -> Token {
+fn lex_string(chars: &mut Chars, start: Option<impl ToString>, allow_ampersand: bool) -> Token {
     let mut s = match start {
         Some(s) => s.to_string(),
         None => String::new(),
     };
 
     let mut nested_level = 0;
+    let mut brace_level = 0;
 
     while let Some(&next) = chars.peek() {
-        if "<> ;|".contains(next) && nested_level == 0 {
+        if next == '\\' {
+            chars.next();
+            match chars.next() {
+                // A line continuation, which joins the lines around it
+                Some('\n') => {}
+                escaped => {
+                    s.push(next);
+                    s.extend(escaped);
+                }
+            }
+            continue;
+        }
+
+        // A quoted part of the word is kept as it is, quotes and all, and
+        // whatever is inside of it does not end the word. This includes the
+        // `'...'` of a `$'...'`, since both end at the first unescaped `'`.
+        if (next == '"' || next == '\'') && nested_level == 0 {
+            chars.next();
+            let start = chars.clone();
+            let _ = advance_until(chars, next, next == '"');
+
+            s.push(next);
+            s.push_str(&text_between(start, chars));
+            continue;
+        }
+
+        // What is redirected to may be a `<(...)` or `>(...)`
+        let starts_substitution =
+            s.is_empty() && "<>".contains(next) && chars.clone().nth(1) == Some('(');
+
+        if "<> \t;|\n".contains(next)
+            && nested_level == 0
+            && brace_level == 0
+            && !starts_substitution
+        {
             break;
         }
 
-        if !allow_ampersand && next == '&' {
+        if !allow_ampersand && next == '&' && nested_level == 0 {
             break;
         }
 
-        if &s == "$" && next == '(' {
-            nested_level += 1;
+        // Parentheses end the word, unless they are part of a `$(...)`, or
+        // the `<(...)` or `>(...)` that the word starts with
+        let opens_substitution = s.ends_with('$') || s == "<" || s == ">";
+        if nested_level == 0
+            && brace_level == 0
+            && (next == ')' || next == '(' && !opens_substitution)
+        {
+            break;
         }
 
         s.push(next);
         chars.next();
 
-        if next == '$' {
-            if let Some(&'(') = chars.peek() {
-                nested_level += 1;
-            }
+        if next == '(' && (nested_level > 0 || opens_substitution) {
+            nested_level += 1;
+        }
+
+        if next == '{' && s.ends_with("${") {
+            brace_level += 1;
         }
 
         if nested_level > 0 && next == ')' {
             nested_level -= 1;
         }
+
+        if brace_level > 0 && next == '}' {
+            brace_level -= 1;
+        }
     }
 
     Token::String(s)
@@ -294,7 +773,7 @@ mod tests {
                 String("FOO=".into()),
                 String("ls".into()),
                 DoubleQuotedString("foo".into(), true),
-                RedirectOutput(Some("2".into()), "/dev/null".into(), None, false),
+                RedirectOutput(Some("2".into()), "/dev/null".into(), None, false, false),
                 Semicolon,
             ],
             tokens
@@ -310,7 +789,7 @@ mod tests {
             vec![
                 // Assignment("LC_ALL".into(), Some("en-US".into())),
                 String("LC_ALL=en-US".into()),
-                RedirectOutput(Some("2".into()), "&1".into(), None, false),
+                RedirectOutput(Some("2".into()), "&1".into(), None, false, false),
                 String("ls".into()),
             ],
             tokens,
@@ -326,7 +805,7 @@ mod tests {
             vec![
                 String("groups".into()),
                 DoubleQuotedString("$(whoami)".into(), true),
-                RedirectOutput(Some("2".into()), "&1".into(), None, false),
+                RedirectOutput(Some("2".into()), "&1".into(), None, false, false),
                 Semicolon,
                 String("sleep".into()),
                 String("3".into()),
@@ -346,8 +825,188 @@ mod tests {
         assert_eq!(
             vec![
                 String("cat".into()),
-                RedirectInput("foo.txt".into()),
-                RedirectOutput(Some("2".into()), "/dev/null".to_string(), None, false),
+                RedirectInput(None, "foo.txt".into()),
+                RedirectOutput(
+                    Some("2".into()),
+                    "/dev/null".to_string(),
+                    None,
+                    false,
+                    false
+                ),
+            ],
+            tokens,
+        );
+    }
+
+    #[test]
+    fn lex_duplicating_redirection() {
+        let input = "cmd 2>&1 &>>f 3<&0 <&-".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("cmd".into()),
+                RedirectOutput(Some("2".into()), "&1".to_string(), None, false, false),
+                RedirectOutput(Some("&".into()), "f".to_string(), None, true, false),
+                RedirectInput(Some("3".into()), "&0".into()),
+                RedirectInput(None, "&-".into()),
+            ],
+            tokens,
+        );
+    }
+
+    #[test]
+    fn lex_process_substitution() {
+        let input = "diff <(sort a) <(ls $(pwd)) > >(cat)".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("diff".into()),
+                String("<(sort a)".into()),
+                String("<(ls $(pwd))".into()),
+                RedirectOutput(
+                    None,
+                    ">(cat)".to_string(),
+                    Some(" ".to_string()),
+                    false,
+                    false
+                ),
+            ],
+            tokens,
+        );
+    }
+
+    #[test]
+    fn lex_clobbering_redirection() {
+        let input = "ls >| foo 2>|bar".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("ls".into()),
+                RedirectOutput(None, "foo".to_string(), Some(" ".to_string()), false, true),
+                RedirectOutput(Some("2".into()), "bar".to_string(), None, false, true),
+            ],
+            tokens,
+        );
+    }
+
+    #[test]
+    fn lex_comments() {
+        let input = "echo a#b # c d\n#e\necho $# \\\n  f\\\ng".to_string();
+
+        assert_eq!(
+            vec![
+                String("echo".into()),
+                String("a#b".into()),
+                Newline,
+                Newline,
+                String("echo".into()),
+                String("$#".into()),
+                String("fg".into()),
+            ],
+            lex(input.clone(), false),
+        );
+
+        assert_eq!(
+            vec![
+                String("echo".into()),
+                Space,
+                String("a#b".into()),
+                Space,
+                Comment(" c d".into()),
+                Newline,
+                Comment("e".into()),
+                Newline,
+            ],
+            lex(input, true)[..8],
+        );
+    }
+
+    #[test]
+    fn lex_quoted_parts() {
+        let input = r#"x="a b" y='c d' a"b c"d "e f"'g h'i 'j'"#.to_string();
+
+        assert_eq!(
+            vec![
+                String(r#"x="a b""#.into()),
+                String("y='c d'".into()),
+                String(r#"a"b c"d"#.into()),
+                String(r#""e f"'g h'i"#.into()),
+                SingleQuotedString("j".into(), true),
+            ],
+            lex(input, false),
+        );
+
+        let input = r"x=$'a\'b' a$'\t'b $'c d'e $'f'".to_string();
+
+        assert_eq!(
+            vec![
+                String(r"x=$'a\'b'".into()),
+                String(r"a$'\t'b".into()),
+                String("$'c d'e".into()),
+                AnsiCString("f".into(), true),
+            ],
+            lex(input, false),
+        );
+    }
+
+    #[test]
+    fn lex_quotes_in_parameter_expansions() {
+        let input = r#"echo "${x-"a b"}" "\${" "${x-'}'}""#.to_string();
+
+        assert_eq!(
+            vec![
+                String("echo".into()),
+                DoubleQuotedString(r#"${x-"a b"}"#.into(), true),
+                DoubleQuotedString(r"\${".into(), true),
+                DoubleQuotedString("${x-'}'}".into(), true),
+            ],
+            lex(input, false),
+        );
+    }
+
+    #[test]
+    fn lex_tabs() {
+        let input = "if true;\tthen\n\t# a\n\techo\tb\t# c\n\tcat <\tf >\tg\nfi".to_string();
+
+        assert_eq!(
+            vec![
+                Keyword("if".into()),
+                String("true".into()),
+                Semicolon,
+                Keyword("then".into()),
+                Newline,
+                Newline,
+                String("echo".into()),
+                String("b".into()),
+                Newline,
+                String("cat".into()),
+                RedirectInput(None, "f".into()),
+                RedirectOutput(None, "g".into(), Some("\t".into()), false, false),
+                Newline,
+                Keyword("fi".into()),
+            ],
+            lex(input, false),
+        );
+    }
+
+    #[test]
+    fn lex_escapes() {
+        let input = r#"find . -exec echo a\ b\; \; "\"\$x" $'a\'b\n'"#.to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("find".into()),
+                String(".".into()),
+                String("-exec".into()),
+                String("echo".into()),
+                String(r"a\ b\;".into()),
+                String(r"\;".into()),
+                DoubleQuotedString(r#"\"\$x"#.into(), true),
+                AnsiCString(r"a\'b\n".into(), true),
             ],
             tokens,
         );
@@ -361,12 +1020,12 @@ mod tests {
         assert_eq!(
             vec![
                 String("ls".into()),
-                RedirectOutput(None, "foo".to_string(), Some(" ".to_string()), false),
+                RedirectOutput(None, "foo".to_string(), Some(" ".to_string()), false, false),
                 Semicolon,
                 String("ls".into()),
                 Pipe,
                 String("rev".into()),
-                RedirectOutput(None, "foo".to_string(), None, true),
+                RedirectOutput(None, "foo".to_string(), None, true, false),
             ],
             tokens,
         );
@@ -377,14 +1036,14 @@ mod tests {
             vec![
                 String("ls".into()),
                 Space,
-                RedirectOutput(None, "foo".to_string(), Some(" ".to_string()), false),
+                RedirectOutput(None, "foo".to_string(), Some(" ".to_string()), false, false),
                 Semicolon,
                 Space,
                 String("ls".into()),
                 Pipe,
                 String("rev".into()),
                 Space,
-                RedirectOutput(None, "foo".to_string(), None, true),
+                RedirectOutput(None, "foo".to_string(), None, true, false),
             ],
             tokens,
         );
@@ -400,7 +1059,13 @@ mod tests {
             vec![
                 String("echo".into()),
                 String("123".into()),
-                RedirectOutput(Some("2".into()), "foo.txt".into(), Some(" ".into()), false),
+                RedirectOutput(
+                    Some("2".into()),
+                    "foo.txt".into(),
+                    Some(" ".into()),
+                    false,
+                    false
+                ),
             ],
             tokens,
         );
@@ -410,12 +1075,204 @@ mod tests {
                 Space,
                 String("123".into()),
                 Space,
-                RedirectOutput(Some("2".into()), "foo.txt".into(), Some(" ".into()), false),
+                RedirectOutput(
+                    Some("2".into()),
+                    "foo.txt".into(),
+                    Some(" ".into()),
+                    false,
+                    false
+                ),
             ],
             tokens_with_space,
         );
     }
 
+    #[test]
+    fn lex_expansions() {
+        let input = "echo ${FOO:-a b;c}x ${BAR}| rev $(( (1 + 2) > 1 && 1 ))".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("echo".to_string()),
+                String("${FOO:-a b;c}x".to_string()),
+                String("${BAR}".to_string()),
+                Pipe,
+                String("rev".to_string()),
+                String("$(( (1 + 2) > 1 && 1 ))".to_string()),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_keywords() {
+        let input = "if echo fi; then fi=1\nfi".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                Keyword("if".to_string()),
+                String("echo".to_string()),
+                String("fi".to_string()),
+                Semicolon,
+                Keyword("then".to_string()),
+                String("fi=1".to_string()),
+                Newline,
+                Keyword("fi".to_string()),
+            ],
+            tokens
+        );
+
+        let input = "for in in in; do echo in; done".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                Keyword("for".to_string()),
+                String("in".to_string()),
+                Keyword("in".to_string()),
+                String("in".to_string()),
+                Semicolon,
+                Keyword("do".to_string()),
+                String("echo".to_string()),
+                String("in".to_string()),
+                Semicolon,
+                Keyword("done".to_string()),
+            ],
+            tokens
+        );
+
+        let input = "while done; do :; done".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                Keyword("while".to_string()),
+                Keyword("done".to_string()),
+                Semicolon,
+                Keyword("do".to_string()),
+                String(":".to_string()),
+                Semicolon,
+                Keyword("done".to_string()),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_functions() {
+        let input = "f() { echo {a,b} }; }\nfunction end\nend".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("f".to_string()),
+                LParen,
+                RParen,
+                LBrace,
+                String("echo".to_string()),
+                String("{a,b}".to_string()),
+                String("}".to_string()),
+                Semicolon,
+                RBrace,
+                Newline,
+                Keyword("function".to_string()),
+                String("end".to_string()),
+                Newline,
+                Keyword("end".to_string()),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_groups() {
+        let input = "(if a; then b; fi) | { c; }".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                LParen,
+                Keyword("if".to_string()),
+                String("a".to_string()),
+                Semicolon,
+                Keyword("then".to_string()),
+                String("b".to_string()),
+                Semicolon,
+                Keyword("fi".to_string()),
+                RParen,
+                Pipe,
+                LBrace,
+                String("c".to_string()),
+                Semicolon,
+                RBrace,
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_here_documents() {
+        let input = "cat <<EOF; cat <<-'EOF' <<<\"a b\"\n$x\nEOF\n\tb\n\tEOF\nls <<EOF".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                String("cat".to_string()),
+                HereDocument {
+                    delimiter: "EOF".to_string(),
+                    strip_tabs: false,
+                    body: Some("$x\n".to_string()),
+                },
+                Semicolon,
+                String("cat".to_string()),
+                HereDocument {
+                    delimiter: "'EOF'".to_string(),
+                    strip_tabs: true,
+                    body: Some("b\n".to_string()),
+                },
+                HereString(Box::new(DoubleQuotedString("a b".to_string(), true))),
+                Newline,
+                String("ls".to_string()),
+                HereDocument {
+                    delimiter: "EOF".to_string(),
+                    strip_tabs: false,
+                    body: None,
+                },
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn lex_case() {
+        let input = "case $x in (a|esac) echo $(ls);; *) :;& esac".to_string();
+        let tokens = lex(input, false);
+
+        assert_eq!(
+            vec![
+                Keyword("case".to_string()),
+                String("$x".to_string()),
+                Keyword("in".to_string()),
+                LParen,
+                String("a".to_string()),
+                Pipe,
+                String("esac".to_string()),
+                RParen,
+                String("echo".to_string()),
+                String("$(ls)".to_string()),
+                DoubleSemicolon,
+                String("*".to_string()),
+                RParen,
+                String(":".to_string()),
+                SemicolonAmpersand,
+                Keyword("esac".to_string()),
+            ],
+            tokens
+        );
+    }
+
     #[test]
     fn lex_or() {
         let input = "test -d foo | tee log || exit".to_string();
@@ -2,5 +2,5 @@ pub mod ast;
 pub mod lexer;
 mod util;
 
-pub use ast::{parse, SyntaxTree};
+pub use ast::{parse, ParseError, SyntaxTree};
 pub use lexer::{lex, Token};
@@ -1,4 +1,117 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
 pub fn is_valid_first_character_of_expansion(c: char) -> bool {
-    // TODO: figure out if this is actually "correct" (POSIX)
-    c.is_alphanumeric()
+    c.is_alphabetic() || c == '_'
+}
+
+pub fn is_valid_character_of_expansion(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether `c` is the name of a parameter on its own, like `$?` or `$1`.
+pub fn is_special_parameter(c: char) -> bool {
+    c.is_ascii_digit() || "?$!#@*".contains(c)
+}
+
+/// Replaces the backslash escapes of a `$'...'` string with what they stand
+/// for, like those of C. Escapes that are not known, or that are not valid
+/// characters, are left as they are.
+pub fn unescape_ansi_c(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let Some(c) = chars.next() else {
+            unescaped.push('\\');
+            break;
+        };
+
+        let start = chars.clone();
+        let code = match c {
+            'n' => Some('\n' as u32),
+            't' => Some('\t' as u32),
+            'r' => Some('\r' as u32),
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            'e' | 'E' => Some(0x1b),
+            'f' => Some(0x0c),
+            'v' => Some(0x0b),
+            '\\' | '\'' | '"' | '?' => Some(c as u32),
+
+            '0'..='7' => {
+                let digits = c.to_string() + &take_digits(&mut chars, 8, 2);
+                u32::from_str_radix(&digits, 8).ok()
+            }
+
+            'x' => u32::from_str_radix(&take_digits(&mut chars, 16, 2), 16).ok(),
+
+            // `\u{1f600}` is the same as `\U0001f600`, with the digits in braces
+            'u' if chars.peek() == Some(&'{') => {
+                let mut braced = chars.clone();
+                braced.next();
+                let digits = take_digits(&mut braced, 16, 8);
+
+                match (u32::from_str_radix(&digits, 16), braced.next()) {
+                    (Ok(code), Some('}')) => {
+                        chars = braced;
+                        Some(code)
+                    }
+                    _ => None,
+                }
+            }
+
+            'u' => u32::from_str_radix(&take_digits(&mut chars, 16, 4), 16).ok(),
+            'U' => u32::from_str_radix(&take_digits(&mut chars, 16, 8), 16).ok(),
+
+            _ => None,
+        };
+
+        match code.and_then(char::from_u32) {
+            Some(c) => unescaped.push(c),
+            None => {
+                let len = start.clone().count() - chars.clone().count();
+                unescaped.push('\\');
+                unescaped.push(c);
+                unescaped.extend(start.take(len));
+            }
+        }
+    }
+
+    unescaped
+}
+
+/// Takes at most `max` digits in `radix` from the start of `chars`.
+fn take_digits(chars: &mut Peekable<Chars>, radix: u32, max: usize) -> String {
+    let mut digits = String::new();
+    while digits.len() < max {
+        match chars.peek() {
+            Some(c) if c.is_digit(radix) => digits.extend(chars.next()),
+            _ => break,
+        }
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ansi_c_escapes() {
+        assert_eq!("a\tb\n", unescape_ansi_c(r"a\tb\n"));
+        assert_eq!("AAé😀", unescape_ansi_c(r"\101\x41\u00e9\u{1f600}"));
+        assert_eq!(r"\q\x", unescape_ansi_c(r"\q\x"));
+
+        // Escapes of what are not characters are left as they are
+        assert_eq!(r"\u{d800}!", unescape_ansi_c(r"\u{d800}!"));
+        assert_eq!(r"\ud800", unescape_ansi_c(r"\ud800"));
+        assert_eq!(r"\U7fffffff", unescape_ansi_c(r"\U7fffffff"));
+        assert_eq!(r"\u{110000}", unescape_ansi_c(r"\u{110000}"));
+    }
 }
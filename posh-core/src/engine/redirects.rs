@@ -0,0 +1,525 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::parser::ast::{expand_word_joined, CommandType, Redirect, SyntaxTree, Word};
+use super::{jobs, Engine, ExitStatus};
+use crate::{Error, Result};
+
+/// What a redirection makes a file descriptor into.
+pub(super) enum Target {
+    /// A copy of a file that was opened for the redirection.
+    File(OwnedFd),
+
+    /// A copy of another file descriptor.
+    Duplicate(RawFd),
+
+    Close,
+}
+
+impl<W: Write> Engine<W> {
+    /// Runs `cmd` with `redirects` applied to the file descriptors of the shell
+    /// itself, so that they apply to everything it runs, and puts them back once
+    /// it is done.
+    pub(super) fn run_redirected(
+        &mut self,
+        cmd: CommandType,
+        redirects: Vec<Redirect>,
+    ) -> Result<Vec<ExitStatus>> {
+        self.with_redirects(redirects, |engine| engine.execute_command(cmd))
+    }
+
+    /// Calls `f` with `redirects` applied to the file descriptors of the shell.
+    pub(super) fn with_redirects(
+        &mut self,
+        redirects: Vec<Redirect>,
+        f: impl FnOnce(&mut Self) -> Result<Vec<ExitStatus>>,
+    ) -> Result<Vec<ExitStatus>> {
+        self.writer.flush()?;
+
+        let inherit_stdout = self.inherit_stdout;
+        let mut saved = Vec::with_capacity(redirects.len());
+
+        let result = redirects
+            .into_iter()
+            .try_for_each(|redirect| {
+                for (fd, target) in self.open_redirect(redirect)? {
+                    saved.push((fd, save_fd(fd)));
+                    redirect_fd(fd, &target)?;
+                }
+                Ok(())
+            })
+            .and_then(|()| {
+                // Child processes have to write to the redirected stdout
+                // instead of having their output copied into `writer`.
+                if saved.iter().any(|&(fd, _)| fd == libc::STDOUT_FILENO) {
+                    self.inherit_stdout = true;
+                }
+
+                let statuses = f(self)?;
+                self.writer.flush()?;
+                Ok(statuses)
+            });
+
+        for (fd, saved) in saved.into_iter().rev() {
+            restore_fd(fd, saved);
+        }
+        self.inherit_stdout = inherit_stdout;
+
+        result
+    }
+
+    /// Opens whatever `redirect` points to, returning what each of the file
+    /// descriptors it changes should become, in the order they change.
+    pub(super) fn open_redirect(&mut self, redirect: Redirect) -> Result<Vec<(RawFd, Target)>> {
+        match redirect {
+            Redirect::Input { from, to } => {
+                let fd = self.redirected_fd(from, libc::STDIN_FILENO)?;
+                let to = expand_word_joined(self, &[], to)?;
+                let file = high_fd(File::open(to.name)?.into())?;
+                Ok(vec![(fd, Target::File(file))])
+            }
+
+            Redirect::Output {
+                from,
+                to,
+                append,
+                clobber,
+            } => {
+                let fd = self.redirected_fd(from, libc::STDOUT_FILENO)?;
+                let file = self.open_output(to, append, clobber)?;
+                Ok(vec![(fd, Target::File(file))])
+            }
+
+            Redirect::Duplicate { from, to, output } => {
+                let default = if output {
+                    libc::STDOUT_FILENO
+                } else {
+                    libc::STDIN_FILENO
+                };
+                let fd = self.redirected_fd(from, default)?;
+
+                let to = expand_word_joined(self, &[], to)?.name;
+                let target = if to == "-" {
+                    Target::Close
+                } else {
+                    Target::Duplicate(parse_fd(to)?)
+                };
+
+                Ok(vec![(fd, target)])
+            }
+
+            Redirect::Both {
+                to,
+                append,
+                clobber,
+            } => Ok(vec![
+                (
+                    libc::STDOUT_FILENO,
+                    Target::File(self.open_output(to, append, clobber)?),
+                ),
+                (libc::STDERR_FILENO, Target::Duplicate(libc::STDOUT_FILENO)),
+            ]),
+
+            Redirect::HereDocument { body, .. } => {
+                let body = expand_word_joined(self, &[], body)?;
+                let file = high_fd(file_with_contents(body.name)?.into())?;
+                Ok(vec![(libc::STDIN_FILENO, Target::File(file))])
+            }
+
+            Redirect::HereString { word } => {
+                let word = expand_word_joined(self, &[], word)?;
+                let file = high_fd(file_with_contents(word.name + "\n")?.into())?;
+                Ok(vec![(libc::STDIN_FILENO, Target::File(file))])
+            }
+        }
+    }
+
+    /// Starts running `ast` for `<(ast)`, or `>(ast)` if `output` is set, and
+    /// returns the path that the output of it can be read from, or its input
+    /// written to. It is waited for once the command using it is done.
+    pub(crate) fn substitute_process(&mut self, ast: SyntaxTree, output: bool) -> Result<String> {
+        let (reader, writer) = io::pipe()?;
+        let (ours, theirs): (OwnedFd, OwnedFd) = if output {
+            (writer.into(), reader.into())
+        } else {
+            (reader.into(), writer.into())
+        };
+
+        self.writer.flush()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error().into()),
+
+            0 => {
+                // Pipes are only at their end once nothing else has them open
+                drop(ours);
+                self.process_substitutions.clear();
+                jobs::prepare_child(false);
+
+                let fd = if output {
+                    libc::STDIN_FILENO
+                } else {
+                    libc::STDOUT_FILENO
+                };
+                unsafe {
+                    libc::dup2(theirs.as_raw_fd(), fd);
+                }
+                drop(theirs);
+
+                let mut engine = self.subshell(io::stdout());
+                engine.inherit_stdout = true;
+
+                let code = match engine.walk_ast(ast) {
+                    Ok(statuses) => statuses.last().map_or(0, |s| s.code),
+                    Err(e) => {
+                        eprintln!("posh: {e}");
+                        1
+                    }
+                };
+
+                let _ = engine.writer.flush();
+                unsafe { libc::_exit(code) }
+            }
+
+            pid => {
+                drop(theirs);
+
+                // The command using the pipe has to inherit it
+                let fd = match unsafe { libc::fcntl(ours.as_raw_fd(), libc::F_DUPFD, 10) } {
+                    -1 => return Err(io::Error::last_os_error().into()),
+                    fd => unsafe { OwnedFd::from_raw_fd(fd) },
+                };
+
+                let path = format!("/dev/fd/{}", fd.as_raw_fd());
+                self.process_substitutions.push((pid, fd));
+                Ok(path)
+            }
+        }
+    }
+
+    /// Closes the pipes of the process substitutions started after the first
+    /// `count` of them, and waits for their processes to finish.
+    pub(super) fn finish_process_substitutions(&mut self, count: usize) {
+        let pids = self
+            .process_substitutions
+            .split_off(count)
+            .into_iter()
+            .map(|(pid, _)| pid)
+            .collect::<Vec<_>>();
+
+        for pid in pids {
+            // There is nowhere for their statuses to go
+            let _ = jobs::wait_pid(pid, 0);
+        }
+    }
+
+    /// The file descriptor that a redirection applies to, which is `default`
+    /// unless another one was given.
+    fn redirected_fd(&mut self, fd: Option<Word>, default: RawFd) -> Result<RawFd> {
+        match fd {
+            Some(fd) => parse_fd(expand_word_joined(self, &[], fd)?.name),
+            None => Ok(default),
+        }
+    }
+
+    /// Opens `to` for writing, creating it if needed. Unless appending, it is
+    /// truncated, which `noclobber` prevents for regular files unless `clobber`
+    /// is set.
+    fn open_output(&mut self, to: Word, append: bool, clobber: bool) -> Result<OwnedFd> {
+        let to = expand_word_joined(self, &[], to)?.name;
+
+        let file = if append {
+            OpenOptions::new().append(true).create(true).open(&to)
+        } else if self.options.noclobber && !clobber {
+            match fs::metadata(&to) {
+                Ok(metadata) if metadata.is_file() => return Err(Error::Clobber(to)),
+
+                // Things like `/dev/null` can still be written to
+                Ok(_) => OpenOptions::new().write(true).open(&to),
+                Err(_) => OpenOptions::new().write(true).create_new(true).open(&to),
+            }
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&to)
+        }?;
+
+        high_fd(file.into())
+    }
+}
+
+/// Makes `fd` into `target`. Only async-signal-safe functions are called, so
+/// that this can be done between `fork` and `exec`.
+pub(super) fn redirect_fd(fd: RawFd, target: &Target) -> io::Result<()> {
+    let result = unsafe {
+        match target {
+            Target::File(file) => libc::dup2(file.as_raw_fd(), fd),
+
+            // Duplicating a file descriptor onto itself only requires it to be open
+            Target::Duplicate(from) if *from == fd => libc::fcntl(fd, libc::F_GETFD),
+            Target::Duplicate(from) => libc::dup2(*from, fd),
+
+            Target::Close => {
+                libc::close(fd);
+                0
+            }
+        }
+    };
+
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn parse_fd(fd: String) -> Result<RawFd> {
+    match fd.parse() {
+        Ok(n) if n >= 0 => Ok(n),
+        _ => Err(Error::BadFileDescriptor(fd)),
+    }
+}
+
+/// Moves `fd` to a number of at least 10, out of the way of the file
+/// descriptors that redirections usually change.
+fn high_fd(fd: OwnedFd) -> Result<OwnedFd> {
+    match save_fd(fd.as_raw_fd()) {
+        Some(high) => Ok(unsafe { OwnedFd::from_raw_fd(high) }),
+        None => Err(io::Error::last_os_error().into()),
+    }
+}
+
+/// Creates a file that `contents` can be read from, which is how here-documents
+/// and here-strings are fed to commands. It is removed right away, so that it
+/// is gone once nothing has it open anymore. Unlike a pipe, it needs nothing to
+/// keep writing to it, however much is in it.
+fn file_with_contents(contents: String) -> Result<File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("posh-here-{}-{count}", process::id()));
+
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            file => file?,
+        };
+
+        fs::remove_file(&path)?;
+        file.write_all(contents.as_bytes())?;
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(file);
+    }
+}
+
+/// Duplicates `fd` to somewhere that child processes will not see, so that it
+/// can be restored later. Returns `None` if `fd` was not open.
+fn save_fd(fd: RawFd) -> Option<RawFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) } {
+        -1 => None,
+        saved => Some(saved),
+    }
+}
+
+/// Puts back what `save_fd` saved of `fd`.
+fn restore_fd(fd: RawFd, saved: Option<RawFd>) {
+    unsafe {
+        match saved {
+            Some(saved) => {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+            None => {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::mem::ManuallyDrop;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("posh-{name}-{}", process::id()))
+    }
+
+    fn word(name: impl ToString) -> Word {
+        Word {
+            name: name.to_string(),
+            expansions: Vec::new(),
+            quoted: false,
+        }
+    }
+
+    /// Reads all of the file that `fd` is, without closing it.
+    fn read_fd(fd: RawFd) -> String {
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn here_documents() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "a b\n$x\n",
+            engine.output("x=a; cat <<EOF\n$x $(echo b)\nEOF\ncat <<'EOF'\n$x\nEOF")
+        );
+        assert_eq!(
+            "C\na d\n",
+            engine.output("cat <<-EOF | tr a-z A-Z\n\tc\n\tEOF\ncat <<<\"$x d\"")
+        );
+        assert_eq!("e\nf\n", engine.output("(cat; echo f) <<<e"));
+    }
+
+    #[test]
+    fn large_here_strings() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        // More than fits in a pipe, read by a stage that the shell forks
+        assert_eq!(
+            "200001\n",
+            engine.output("x=$(head -c 200000 /dev/zero | tr -c a a); cat <<<\"$x\" | { wc -c; }")
+        );
+        assert_eq!("a\n", engine.output("head -c 1 <<<\"$x\" | { cat; echo; }"));
+    }
+
+    #[test]
+    fn duplicated_file_descriptors() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "A\nB\n",
+            engine.output("(echo a >&2; echo b 3>&1 >&3) 2>&1 | tr a-z A-Z")
+        );
+        assert_eq!("1\n", engine.output("(cat /nonexistent 2>&1 >&-) | wc -l"));
+        assert!(engine.execute_line("echo c >&x").is_err());
+    }
+
+    #[test]
+    fn process_substitutions() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "a\nB\n",
+            engine.output("cat <(echo a) <(echo b | tr a-z A-Z)")
+        );
+        assert_eq!(
+            "c\nd\n",
+            engine.output("f() { cat $1 $2; }; f <(echo c) <(echo d)")
+        );
+        assert_eq!("y\n", engine.output("head -n 1 <(yes)"));
+        assert!(engine.process_substitutions.is_empty());
+    }
+
+    #[test]
+    fn noclobber() {
+        let mut engine = Engine::with_writer(Vec::new());
+        let path = temp_path("noclobber");
+        let path = path.display();
+
+        assert_eq!(
+            "",
+            engine.output(format!("echo longer >{path}; echo a >{path}"))
+        );
+        engine.output("set -o noclobber");
+        assert!(engine.execute_line(format!("echo b >{path}")).is_err());
+        assert_eq!(
+            "a\nc\n",
+            engine.output(format!("echo c >>{path}; echo ok >/dev/null; cat {path}"))
+        );
+        assert_eq!(
+            "d\n",
+            engine.output(format!("echo d >|{path}; cat {path}; rm {path}"))
+        );
+    }
+
+    #[test]
+    fn opening_output() {
+        let mut engine = Engine::with_writer(Vec::new());
+        engine.options.noclobber = true;
+        let path = temp_path("open-output");
+
+        let file = engine
+            .open_output(word(path.display()), false, false)
+            .unwrap();
+        assert!(file.as_raw_fd() >= 10);
+
+        assert!(matches!(
+            engine.open_output(word(path.display()), false, false),
+            Err(Error::Clobber(_))
+        ));
+        assert!(engine
+            .open_output(word(path.display()), true, false)
+            .is_ok());
+        assert!(engine
+            .open_output(word(path.display()), false, true)
+            .is_ok());
+        assert!(engine.open_output(word("/dev/null"), false, false).is_ok());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saving_and_restoring() {
+        let (a, b) = (temp_path("restore-a"), temp_path("restore-b"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+
+        let file = File::open(&a).unwrap();
+        let fd = file.as_raw_fd();
+
+        let saved = save_fd(fd).unwrap();
+        assert!(saved >= 10);
+
+        redirect_fd(fd, &Target::File(File::open(&b).unwrap().into())).unwrap();
+        assert_eq!("b", read_fd(fd));
+
+        // Duplicating onto itself only checks that it is open
+        redirect_fd(fd, &Target::Duplicate(fd)).unwrap();
+        assert_eq!("b", read_fd(fd));
+
+        restore_fd(fd, Some(saved));
+        assert_eq!("a", read_fd(fd));
+        assert_eq!(-1, unsafe { libc::fcntl(saved, libc::F_GETFD) });
+
+        drop(file);
+        fs::remove_file(a).unwrap();
+        fs::remove_file(b).unwrap();
+    }
+
+    #[test]
+    fn file_descriptor_numbers() {
+        assert_eq!(3, parse_fd("3".to_string()).unwrap());
+        assert_eq!(12, parse_fd("12".to_string()).unwrap());
+
+        for fd in ["-1", "x", "", "1x"] {
+            assert!(matches!(
+                parse_fd(fd.to_string()),
+                Err(Error::BadFileDescriptor(s)) if s == fd
+            ));
+        }
+    }
+}
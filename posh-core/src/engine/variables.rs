@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use super::parser::ast::Command;
+use super::{Engine, ExitStatus};
+use crate::{path, Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub value: String,
+
+    /// Whether the variable is passed on to the environment of child processes.
+    pub exported: bool,
+
+    pub readonly: bool,
+}
+
+/// The variables of the shell, which are kept apart from the environment of
+/// the shell process itself. Only the exported ones are given to commands.
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    vars: BTreeMap<String, Variable>,
+
+    /// The names that were exported before being set, which stay out of the
+    /// environment until they are.
+    pending_exports: BTreeSet<String>,
+}
+
+impl Variables {
+    /// Creates a store with every variable of the process environment, exported.
+    pub fn from_env() -> Self {
+        let vars = std::env::vars()
+            .map(|(name, value)| {
+                let var = Variable {
+                    value,
+                    exported: true,
+                    readonly: false,
+                };
+                (name, var)
+            })
+            .collect();
+
+        Self {
+            vars,
+            pending_exports: BTreeSet::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|var| var.value.as_str())
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&Variable> {
+        self.vars.get(name)
+    }
+
+    /// Replaces `name` with `var` even if it is read-only, or removes it if
+    /// `var` is `None`, returning what it was before.
+    pub fn replace(&mut self, name: &str, var: Option<Variable>) -> Option<Variable> {
+        match var {
+            Some(var) => self.vars.insert(name.to_string(), var),
+            None => self.vars.remove(name),
+        }
+    }
+
+    /// Sets the value of `name`, keeping whether it is exported if it already exists.
+    pub fn set(&mut self, name: &str, value: impl ToString) -> Result<()> {
+        match self.vars.get_mut(name) {
+            Some(var) if var.readonly => Err(Error::ReadOnly(name.to_string())),
+
+            Some(var) => {
+                var.value = value.to_string();
+                Ok(())
+            }
+
+            None => {
+                let var = Variable {
+                    value: value.to_string(),
+                    exported: self.pending_exports.remove(name),
+                    readonly: false,
+                };
+                self.vars.insert(name.to_string(), var);
+                Ok(())
+            }
+        }
+    }
+
+    /// Marks `name` as exported, or as to be exported once it is set if it
+    /// is not yet.
+    pub fn export(&mut self, name: &str) {
+        match self.vars.get_mut(name) {
+            Some(var) => var.exported = true,
+            None => {
+                self.pending_exports.insert(name.to_string());
+            }
+        }
+    }
+
+    /// The names that are exported but not set, sorted.
+    pub fn pending_exports(&self) -> impl Iterator<Item = &str> {
+        self.pending_exports.iter().map(String::as_str)
+    }
+
+    /// Marks `name` as read-only, creating it with an empty value if needed.
+    pub fn set_readonly(&mut self, name: &str) {
+        self.vars
+            .entry(name.to_string())
+            .or_insert_with(|| Variable {
+                value: String::new(),
+                exported: false,
+                readonly: false,
+            })
+            .readonly = true;
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<()> {
+        match self.vars.get(name) {
+            Some(var) if var.readonly => Err(Error::ReadOnly(name.to_string())),
+            _ => {
+                self.vars.remove(name);
+                self.pending_exports.remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Every variable, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Variable)> {
+        self.vars.iter().map(|(name, var)| (name.as_str(), var))
+    }
+
+    /// The variables that make up the environment of child processes.
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.iter()
+            .filter(|(_, var)| var.exported)
+            .map(|(name, var)| (name, var.value.as_str()))
+    }
+}
+
+/// Whether `name` can be the name of a variable, i.e. consists of letters,
+/// digits and underscores, and does not start with a digit.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quotes `value` so that it can be read back by the shell, if needed.
+pub fn quote(value: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "_-./:,@%+=~".contains(c);
+
+    if !value.is_empty() && value.chars().all(is_plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+impl<W: Write> Engine<W> {
+    /// Sets the shell variable `name`, which is only passed on to commands
+    /// if it is exported.
+    pub fn set_var(&mut self, name: &str, value: impl ToString) -> Result<()> {
+        self.vars.set(name, value)?;
+
+        if name == "PATH" {
+            self.commands = path::get_cmds_in(self.vars.get("PATH").unwrap_or_default());
+        }
+
+        Ok(())
+    }
+
+    /// Performs the assignments of a command that has no name, which set shell
+    /// variables rather than the environment of a single command.
+    pub(super) fn assign(&mut self, cmd: Command) -> Result<ExitStatus> {
+        for (name, value) in cmd.vars() {
+            if let Err(e) = self.set_var(&name, value) {
+                writeln!(self.writer, "posh: {e}")?;
+                return Ok(ExitStatus::from(1));
+            }
+        }
+
+        Ok(ExitStatus::from(self.substitution_status.unwrap_or(0)))
+    }
+
+    /// Looks up the value of a parameter, which is either a variable or one
+    /// of the special parameters like `$?` and `$1`. `$@` and `$*` are not
+    /// handled here, since they can expand to several fields.
+    pub fn get_param(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(self.pid.to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "#" => Some((self.args.len() - 1).to_string()),
+            "@" | "*" => Some(self.args[1..].join(" ")),
+            _ => match name.parse::<usize>() {
+                Ok(n) => self.args.get(n).cloned(),
+                Err(_) => self.vars.get(name).map(ToString::to_string),
+            },
+        }
+    }
+
+    /// Runs `export` or `readonly`, which both mark variables in some way and
+    /// optionally assign them at the same time.
+    pub(super) fn mark_vars(&mut self, builtin: &str, args: &[String]) -> Result<ExitStatus> {
+        let exporting = builtin == "export";
+
+        if args.is_empty() {
+            let vars = self
+                .vars
+                .iter()
+                .filter(|(_, var)| {
+                    if exporting {
+                        var.exported
+                    } else {
+                        var.readonly
+                    }
+                })
+                .map(|(name, var)| (name, format!("{builtin} {name}={}", quote(&var.value))));
+
+            let mut vars = if exporting {
+                vars.chain(
+                    self.vars
+                        .pending_exports()
+                        .map(|name| (name, format!("{builtin} {name}"))),
+                )
+                .collect::<Vec<_>>()
+            } else {
+                vars.collect()
+            };
+            vars.sort();
+
+            for (_, var) in vars {
+                writeln!(self.writer, "{var}")?;
+            }
+
+            return Ok(ExitStatus::from(0));
+        }
+
+        let mut status = ExitStatus::from(0);
+
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !is_valid_name(name) {
+                writeln!(
+                    self.writer,
+                    "{builtin}: '{name}' is not a valid variable name."
+                )?;
+                status = ExitStatus::from(1);
+                continue;
+            }
+
+            if let Some(value) = value {
+                if let Err(e) = self.set_var(name, value) {
+                    writeln!(self.writer, "{builtin}: {e}")?;
+                    status = ExitStatus::from(1);
+                    continue;
+                }
+            }
+
+            if exporting {
+                self.vars.export(name);
+            } else {
+                self.vars.set_readonly(name);
+            }
+        }
+
+        Ok(status)
+    }
+
+    pub(super) fn unset(&mut self, args: &[String]) -> Result<ExitStatus> {
+        let mut status = ExitStatus::from(0);
+
+        for name in args.iter().filter(|arg| *arg != "-v") {
+            if let Err(e) = self.vars.unset(name) {
+                writeln!(self.writer, "unset: {e}")?;
+                status = ExitStatus::from(1);
+            } else if name == "PATH" {
+                self.commands.clear();
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Lists every variable without any arguments, and otherwise sets or
+    /// unsets the options given with `-o name` or `+o name`, or with flags
+    /// like `-C` or `+C`. Anything after `--`, or arguments that are not
+    /// options, become the positional parameters.
+    pub(super) fn set(&mut self, args: &[String]) -> Result<ExitStatus> {
+        match args {
+            [flag, params @ ..] if flag == "--" => {
+                self.args.truncate(1);
+                self.args.extend_from_slice(params);
+            }
+
+            [first, ..] if !first.starts_with(['-', '+']) => {
+                self.args.truncate(1);
+                self.args.extend_from_slice(args);
+            }
+
+            [] => {
+                let vars = self
+                    .vars
+                    .iter()
+                    .map(|(name, var)| format!("{name}={}", quote(&var.value)))
+                    .collect::<Vec<_>>();
+
+                for var in vars {
+                    writeln!(self.writer, "{var}")?;
+                }
+            }
+
+            [flag] if flag == "-o" || flag == "+o" => {
+                for (name, value) in self.options.iter() {
+                    let value = if value { "on" } else { "off" };
+                    writeln!(self.writer, "{name:<15}{value}")?;
+                }
+            }
+
+            [flag, name] if flag == "-o" || flag == "+o" => {
+                if !self.options.set(name, flag == "-o") {
+                    writeln!(self.writer, "set: '{name}' is not a valid option.")?;
+                    return Ok(ExitStatus::from(1));
+                }
+            }
+
+            [flags, rest @ ..] if flags.len() > 1 && flags.starts_with(['-', '+']) => {
+                let value = flags.starts_with('-');
+                for flag in flags[1..].chars() {
+                    if !self.options.set_flag(flag, value) {
+                        writeln!(self.writer, "set: '{flag}' is not a valid option.")?;
+                        return Ok(ExitStatus::from(1));
+                    }
+                }
+
+                if !rest.is_empty() {
+                    return self.set(rest);
+                }
+            }
+
+            _ => {
+                writeln!(self.writer, "set: invalid arguments")?;
+                return Ok(ExitStatus::from(1));
+            }
+        }
+
+        Ok(ExitStatus::from(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_and_exported_variables() {
+        let mut vars = Variables::default();
+
+        vars.set("FOO", "foo").unwrap();
+        vars.set("BAR", "bar").unwrap();
+        vars.export("BAR");
+        vars.set("BAR", "baz").unwrap();
+
+        assert_eq!(Some("foo"), vars.get("FOO"));
+        assert_eq!(vec![("BAR", "baz")], vars.exported().collect::<Vec<_>>());
+
+        vars.set_readonly("FOO");
+        assert!(vars.set("FOO", "bar").is_err());
+        assert!(vars.unset("FOO").is_err());
+
+        vars.unset("BAR").unwrap();
+        assert_eq!(None, vars.get("BAR"));
+    }
+
+    #[test]
+    fn exporting_unset_variables() {
+        let mut vars = Variables::default();
+
+        vars.export("FOO");
+        assert_eq!(None, vars.get("FOO"));
+        assert_eq!(0, vars.exported().count());
+        assert_eq!(vec!["FOO"], vars.pending_exports().collect::<Vec<_>>());
+
+        vars.set("FOO", "foo").unwrap();
+        assert_eq!(vec![("FOO", "foo")], vars.exported().collect::<Vec<_>>());
+        assert_eq!(0, vars.pending_exports().count());
+
+        vars.export("BAR");
+        vars.unset("BAR").unwrap();
+        vars.set("BAR", "bar").unwrap();
+        assert_eq!(vec![("FOO", "foo")], vars.exported().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shell_variables() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!(
+            "foo bar\n",
+            engine.output("FOO=foo; BAR=bar; echo $FOO $BAR")
+        );
+        assert_eq!("[]\n", engine.output("sh -c 'echo \"[$FOO]\"'"));
+        assert_eq!(
+            "[foo]\n",
+            engine.output("export FOO; sh -c 'echo \"[$FOO]\"'")
+        );
+        assert_eq!("baz\n", engine.output("BAR=baz sh -c 'echo $BAR'"));
+        assert_eq!(
+            "[a  b][c  d]\n",
+            engine.output(r#"export QUX="a  b" QUUX; QUUX='c  d'; sh -c 'echo "[$QUX][$QUUX]"'"#)
+        );
+        assert_eq!("[]\n", engine.output("unset FOO; echo \"[$FOO]\""));
+        assert_eq!(
+            "posh: 'BAR' is read-only\nbar\n",
+            engine.output("readonly BAR; BAR=qux; echo $BAR")
+        );
+    }
+
+    #[test]
+    fn setting_options() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!("ok\n", engine.output("set -e; echo ok"));
+        assert_eq!("2 b\n", engine.output("set -eu -- a b; echo $# $2"));
+
+        engine.output("set -C");
+        assert!(engine.options.noclobber);
+        engine.output("set +C");
+        assert!(!engine.options.noclobber);
+
+        assert_eq!(
+            "set: 'q' is not a valid option.\n1\n",
+            engine.output("set -q; echo $?")
+        );
+    }
+
+    #[test]
+    fn special_parameters() {
+        let mut engine = Engine::with_writer(Vec::new());
+
+        assert_eq!("1\n0\n", engine.output("false; echo $?; echo $?"));
+        assert_eq!("3\n", engine.output("x=$(exit 3) || echo $?"));
+        assert_eq!(
+            "2 posh a\n[a][b  c][a][b][c][a b  c]",
+            engine.output("set -- a 'b  c'; echo $# $0 $1; printf '[%s]' \"$@\" $* \"$*\"")
+        );
+        assert_eq!("[x]", engine.output("set --; printf '[%s]' \"$@\" x"));
+        assert_eq!(
+            "3 3 a b c\n0 x\n",
+            engine.output("set -- a b c; echo ${#@} ${#*} ${@:-x}; set --; echo ${#@} ${*:-x}")
+        );
+
+        assert_eq!(Some("0".to_string()), engine.get_param("#"));
+        assert_eq!(Some(engine.pid.to_string()), engine.get_param("$"));
+        assert_eq!(None, engine.get_param("!"));
+        assert_eq!(None, engine.get_param("1"));
+    }
+
+    #[test]
+    fn variable_names() {
+        assert!(is_valid_name("FOO_bar2"));
+        assert!(is_valid_name("_"));
+        assert!(!is_valid_name("2FOO"));
+        assert!(!is_valid_name("FOO-BAR"));
+        assert!(!is_valid_name(""));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!("foo/bar", quote("foo/bar"));
+        assert_eq!("''", quote(""));
+        assert_eq!("'foo bar'", quote("foo bar"));
+        assert_eq!(r"'it'\''s'", quote("it's"));
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 use std::io;
 use std::path::PathBuf;
 
+use crate::engine::parser::ParseError;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -12,6 +14,14 @@ pub enum Error {
     HistoryOutOfBounds,
     UnknownCommand(String),
     Unimplemented(String),
+    Syntax(ParseError),
+    NoMatch(String),
+    ReadOnly(String),
+    NotSet(String, String),
+    BadAssignment(String),
+    Arithmetic(String),
+    BadFileDescriptor(String),
+    Clobber(String),
 }
 
 impl fmt::Display for Error {
@@ -27,6 +37,14 @@ impl fmt::Display for Error {
                 Self::HistoryOutOfBounds => "Tried to read beyond the history bounds.".to_string(),
                 Self::UnknownCommand(cmd) => format!("Unknown command: {}", cmd),
                 Self::Unimplemented(s) => s.to_string(),
+                Self::Syntax(e) => format!("Syntax error: {}", e),
+                Self::NoMatch(pattern) => format!("No matches for wildcard '{}'", pattern),
+                Self::ReadOnly(name) => format!("'{}' is read-only", name),
+                Self::NotSet(name, message) => format!("{}: {}", name, message),
+                Self::BadAssignment(name) => format!("Cannot assign to '{}'", name),
+                Self::Arithmetic(message) => format!("Arithmetic error: {}", message),
+                Self::BadFileDescriptor(fd) => format!("'{}' is not a valid file descriptor", fd),
+                Self::Clobber(path) => format!("Cannot overwrite existing file '{}'", path),
             }
         )
     }
@@ -39,3 +57,9 @@ impl From<io::Error> for Error {
         Self::Io(e)
     }
 }
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Self::Syntax(e)
+    }
+}
@@ -23,7 +23,12 @@ pub fn history_file() -> PathBuf {
 }
 
 pub fn get_cmds_from_path() -> Vec<String> {
-    let raw_path = env::var("PATH").unwrap();
+    get_cmds_in(&env::var("PATH").unwrap())
+}
+
+/// Lists the files in every directory of `raw_path`, which is separated by
+/// colons like `$PATH`.
+pub fn get_cmds_in(raw_path: &str) -> Vec<String> {
     let raw_path = raw_path.split(':');
 
     let mut cmds = Vec::new();
@@ -70,6 +75,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::useless_format)]
     fn expand_works() {
         let home = home_dir();
 
@@ -15,10 +15,15 @@ impl Colors {
     pub const FLAG: Color = Color::Grey;
     pub const PIPE: Color = Color::DarkMagenta;
     pub const SEMICOLON: Color = Color::Magenta;
+    pub const AND: Color = Color::Magenta;
+    pub const OR: Color = Color::Magenta;
+    pub const AMPERSAND: Color = Color::Magenta;
+    pub const KEYWORD: Color = Color::Magenta;
+    pub const PAREN: Color = Color::Magenta;
     pub const REDIRECT_OUTPUT: Color = Color::Grey;
     pub const REDIRECT_INPUT: Color = Color::Grey;
-    pub const NYI: Color = Color::Red;
     pub const INCOMPLETE: Color = Color::Red;
+    pub const COMMENT: Color = Color::DarkGrey;
 
     pub const NON_ZERO_RC: Color = Color::Red;
     pub const PROMPT: Color = Color::Yellow;
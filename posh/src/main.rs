@@ -4,7 +4,7 @@ mod repl;
 
 use clap::Parser;
 use posh_core::engine::parser::{lex, parse};
-use posh_core::Engine;
+use posh_core::{Engine, Error};
 
 fn main() {
     let args = args::Args::parse();
@@ -18,17 +18,30 @@ fn main() {
 
         std::process::exit(0);
     } else if args.ast {
-        let ast = parse(args.command.unwrap());
+        let cmd = args.command.unwrap();
 
-        println!("{:#?}", ast);
+        match parse(&cmd) {
+            Ok(ast) => println!("{:#?}", ast),
+            Err(e) => {
+                eprintln!("posh: Syntax error: {e}");
+                eprintln!("{}", e.underline(&cmd));
+                std::process::exit(2);
+            }
+        }
 
         std::process::exit(0);
     } else if let Some(cmd) = args.command {
-        let code = match Engine::default().execute_line(cmd) {
+        let code = match Engine::default().execute_line(&cmd) {
             Ok(codes) if codes.is_empty() => 0,
 
             Ok(codes) => codes.last().map(|e| e.code).unwrap(),
 
+            Err(Error::Syntax(e)) => {
+                eprintln!("posh: Syntax error: {e}");
+                eprintln!("{}", e.underline(&cmd));
+                2
+            }
+
             Err(e) => {
                 eprintln!("posh: Could not execute command: {e}");
                 1
@@ -6,7 +6,7 @@ use crossterm::execute;
 use crossterm::queue;
 use crossterm::style;
 use crossterm::terminal;
-use posh_core::engine::parser::lexer::lex;
+use posh_core::engine::parser::lexer::{lex, lex_with_spans};
 use posh_core::engine::parser::Token;
 use posh_core::path::home_dir;
 use posh_core::{Engine, Result};
@@ -38,6 +38,10 @@ struct State {
 
     /// Will be `false` if the user inputs '^ ', which will make abbreviations not expand.
     highlight_abbreviations: bool,
+
+    /// Will be `true` for the lines that continue the first one, like the contents
+    /// of here-documents, which are neither highlighted nor abbreviated.
+    continuation: bool,
 }
 
 impl State {
@@ -46,7 +50,54 @@ impl State {
     }
 }
 
+/// Reads a line of input, along with the lines after it that belong to it, like
+/// the contents of here-documents or the lines after a trailing `\`. Returns an empty string if cancelled.
 pub fn read_line<W: Write>(engine: &mut Engine<W>) -> Result<String> {
+    let Some(mut line) = read_single_line(engine, false)? else {
+        return Ok(String::new());
+    };
+
+    while has_unfinished_here_document(&line) || has_line_continuation(&line) {
+        execute!(
+            engine.writer,
+            style::SetForegroundColor(Colors::PROMPT),
+            style::Print("> "),
+            style::ResetColor,
+        )?;
+
+        let Some(next) = read_single_line(engine, true)? else {
+            return Ok(String::new());
+        };
+
+        line.push('\n');
+        line.push_str(&next);
+    }
+
+    Ok(line)
+}
+
+/// Whether `line` has here-documents that have yet to be ended by their delimiters.
+fn has_unfinished_here_document(line: &str) -> bool {
+    lex(line, false)
+        .iter()
+        .any(|token| matches!(token, Token::HereDocument { body: None, .. }))
+}
+
+/// Whether `line` ends with a `\` that continues it on the next line.
+fn has_line_continuation(line: &str) -> bool {
+    match lex_with_spans(line, false).last() {
+        Some((Token::String(s), span)) if span.end == line.len() => {
+            let backslashes = s.len() - s.trim_end_matches('\\').len();
+            backslashes % 2 == 1
+        }
+        _ => false,
+    }
+}
+
+fn read_single_line<W: Write>(
+    engine: &mut Engine<W>,
+    continuation: bool,
+) -> Result<Option<String>> {
     let _raw = RawMode::init()?;
 
     let mut state = State {
@@ -57,7 +108,8 @@ pub fn read_line<W: Write>(engine: &mut Engine<W>) -> Result<String> {
         about_to_exit: false,
         cancelled: false,
         cleared: false,
-        highlight_abbreviations: true,
+        highlight_abbreviations: !continuation,
+        continuation,
     };
 
     while !state.about_to_exit {
@@ -100,7 +152,9 @@ pub fn read_line<W: Write>(engine: &mut Engine<W>) -> Result<String> {
             }
 
             (KeyCode::Enter, _) => {
-                if let Some((expanded_line, _)) = expand_abbreviation(&state.line, true) {
+                if state.continuation {
+                    // Abbreviations only apply to commands
+                } else if let Some((expanded_line, _)) = expand_abbreviation(&state.line, true) {
                     state.line = expanded_line;
                 }
                 state.about_to_exit = true;
@@ -201,7 +255,7 @@ pub fn read_line<W: Write>(engine: &mut Engine<W>) -> Result<String> {
             (KeyCode::Char(c @ (' ' | '|' | ';')), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
                 let (mut x, y) = state.pos()?;
 
-                if state.line.find(' ').is_none() {
+                if !state.continuation && state.line.find(' ').is_none() {
                     if let Some((expanded_line, diff)) = expand_abbreviation(&state.line, false) {
                         state.line = expanded_line;
 
@@ -304,11 +358,10 @@ pub fn read_line<W: Write>(engine: &mut Engine<W>) -> Result<String> {
         execute!(engine.writer, cursor::MoveTo(0, 0))?;
     }
 
-    // FIXME: should probably return Result<Option<String>> with Ok(None) here?
     if state.cancelled {
-        Ok("".to_string())
+        Ok(None)
     } else {
-        Ok(state.line)
+        Ok(Some(state.line))
     }
 }
 
@@ -317,7 +370,20 @@ fn should_highlight_command(prev_token: Option<&Token>) -> bool {
         return true;
     }
 
-    if let Some(Token::Pipe | Token::Semicolon | Token::RedirectOutput(_, _, _, _)) = prev_token {
+    if let Some(
+        Token::Pipe
+        | Token::Semicolon
+        | Token::Ampersand
+        | Token::Newline
+        | Token::And
+        | Token::Or
+        | Token::Keyword(_)
+        | Token::LParen
+        | Token::RParen
+        | Token::LBrace
+        | Token::RedirectOutput(_, _, _, _, _),
+    ) = prev_token
+    {
         return true;
     }
 
@@ -333,7 +399,19 @@ fn should_highlight_command(prev_token: Option<&Token>) -> bool {
 fn should_highlight_assignment(prev_token: Option<&Token>) -> bool {
     let mut should_highlight_assignment = matches!(
         prev_token,
-        Some(Token::Pipe | Token::Semicolon | Token::RedirectOutput(_, _, _, _)) | None
+        Some(
+            Token::Pipe
+                | Token::Semicolon
+                | Token::Ampersand
+                | Token::Newline
+                | Token::And
+                | Token::Or
+                | Token::Keyword(_)
+                | Token::LParen
+                | Token::RParen
+                | Token::LBrace
+                | Token::RedirectOutput(_, _, _, _, _)
+        ) | None
     );
 
     if let Some(token @ Token::String(_)) = prev_token {
@@ -357,7 +435,11 @@ fn print<W: Write>(engine: &mut Engine<W>, state: &State) -> Result<()> {
     // Perhaps it would be preferable to use the AST to highlight.
     // Using the tokens is kind of hacky (e.g. since it highlights
     // commands based on what the previous token was)
-    let tokens = lex(&state.line, true);
+    let tokens = if state.continuation {
+        Vec::new()
+    } else {
+        lex(&state.line, true)
+    };
 
     let mut prev_non_space_token = None;
 
@@ -370,20 +452,25 @@ fn print<W: Write>(engine: &mut Engine<W>, state: &State) -> Result<()> {
         terminal::Clear(terminal::ClearType::UntilNewLine)
     )?;
 
+    if state.continuation {
+        queue!(engine.writer, style::Print(&state.line))?;
+    }
+
     for token in &tokens {
         match token {
             Token::Space => queue!(engine.writer, style::Print(" "))?,
 
             str_token @ (Token::String(s)
             | Token::SingleQuotedString(s, _)
-            | Token::DoubleQuotedString(s, _)) => {
+            | Token::DoubleQuotedString(s, _)
+            | Token::AnsiCString(s, _)) => {
                 match prev_non_space_token {
                     // If this is the first token, or if it's directly after any
                     // command separator, it should be highlighted as a command.
                     token if should_highlight_command(token) => {
                         let color = if engine.has_builtin(s) {
                             Colors::VALID_BUILTIN
-                        } else if engine.has_command(s) {
+                        } else if engine.has_command(s) || engine.has_function(s) {
                             Colors::VALID_CMD
                         } else if has_abbreviation(s) && state.highlight_abbreviations {
                             Colors::VALID_ABBR
@@ -414,7 +501,8 @@ fn print<W: Write>(engine: &mut Engine<W>, state: &State) -> Result<()> {
                             })
                         )?,
 
-                        Token::SingleQuotedString(_, finished) => queue!(
+                        Token::SingleQuotedString(_, finished)
+                        | Token::AnsiCString(_, finished) => queue!(
                             engine.writer,
                             style::SetForegroundColor(if *finished {
                                 Colors::SINGLE_QUOTED_STRING
@@ -478,6 +566,14 @@ fn print<W: Write>(engine: &mut Engine<W>, state: &State) -> Result<()> {
                         queue!(engine.writer, style::Print(format!("'{s}")))?
                     }
 
+                    Token::AnsiCString(_, true) => {
+                        queue!(engine.writer, style::Print(format!("$'{s}'")))?
+                    }
+
+                    Token::AnsiCString(_, false) => {
+                        queue!(engine.writer, style::Print(format!("$'{s}")))?
+                    }
+
                     Token::DoubleQuotedString(_, false) => {
                         queue!(engine.writer, style::Print(format!("\"{s}")))?
                     }
@@ -498,77 +594,127 @@ fn print<W: Write>(engine: &mut Engine<W>, state: &State) -> Result<()> {
                 style::Print(";")
             )?,
 
-            Token::RedirectOutput(None, to, Some(space), false) => queue!(
+            Token::DoubleSemicolon => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!(">{space}{to}"))
+                style::SetForegroundColor(Colors::SEMICOLON),
+                style::Print(";;")
             )?,
 
-            Token::RedirectOutput(None, to, Some(space), true) => queue!(
+            Token::SemicolonAmpersand => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!(">>{space}{to}"))
+                style::SetForegroundColor(Colors::SEMICOLON),
+                style::Print(";&")
             )?,
 
-            Token::RedirectOutput(None, to, None, false) => queue!(
+            Token::LParen => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!(">{to}"))
+                style::SetForegroundColor(Colors::PAREN),
+                style::Print("(")
             )?,
 
-            Token::RedirectOutput(None, to, None, true) => queue!(
+            Token::RParen => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!(">>{to}"))
+                style::SetForegroundColor(Colors::PAREN),
+                style::Print(")")
             )?,
 
-            Token::RedirectOutput(Some(from), to, None, false) => queue!(
+            Token::LBrace => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!("{from}>{to}"))
+                style::SetForegroundColor(Colors::PAREN),
+                style::Print("{")
             )?,
 
-            Token::RedirectOutput(Some(from), to, None, true) => queue!(
+            Token::RBrace => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!("{from}>>{to}"))
+                style::SetForegroundColor(Colors::PAREN),
+                style::Print("}")
             )?,
 
-            Token::RedirectOutput(Some(from), to, Some(space), false) => queue!(
-                engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!("{from}>{space}{to}"))
-            )?,
+            Token::RedirectOutput(from, to, space, append, clobber) => {
+                let operator = match (append, clobber) {
+                    (true, _) => ">>",
+                    (false, true) => ">|",
+                    (false, false) => ">",
+                };
+                queue!(
+                    engine.writer,
+                    style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
+                    style::Print(format!(
+                        "{}{operator}{}{to}",
+                        from.as_deref().unwrap_or_default(),
+                        space.as_deref().unwrap_or_default()
+                    ))
+                )?
+            }
 
-            Token::RedirectOutput(Some(from), to, Some(space), true) => queue!(
+            Token::RedirectInput(from, to) => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::REDIRECT_OUTPUT),
-                style::Print(format!("{from}>>{space}{to}"))
+                style::SetForegroundColor(Colors::REDIRECT_INPUT),
+                style::Print(format!("{}<{to}", from.as_deref().unwrap_or_default()))
             )?,
 
-            Token::RedirectInput(to) => queue!(
+            Token::HereDocument {
+                delimiter,
+                strip_tabs,
+                ..
+            } => queue!(
                 engine.writer,
                 style::SetForegroundColor(Colors::REDIRECT_INPUT),
-                style::Print(format!("<{to}"))
+                style::Print(format!(
+                    "<<{}{delimiter}",
+                    if *strip_tabs { "-" } else { "" }
+                ))
             )?,
 
+            Token::HereString(word) => {
+                let word = match &**word {
+                    Token::SingleQuotedString(s, true) => format!("'{s}'"),
+                    Token::SingleQuotedString(s, false) => format!("'{s}"),
+                    Token::DoubleQuotedString(s, true) => format!("\"{s}\""),
+                    Token::DoubleQuotedString(s, false) => format!("\"{s}"),
+                    Token::AnsiCString(s, true) => format!("$'{s}'"),
+                    Token::AnsiCString(s, false) => format!("$'{s}"),
+                    Token::String(s) => s.clone(),
+                    _ => String::new(),
+                };
+                queue!(
+                    engine.writer,
+                    style::SetForegroundColor(Colors::REDIRECT_INPUT),
+                    style::Print(format!("<<<{word}"))
+                )?
+            }
+
             Token::And => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::NYI),
+                style::SetForegroundColor(Colors::AND),
                 style::Print("&&")
             )?,
 
             Token::Or => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::NYI),
+                style::SetForegroundColor(Colors::OR),
                 style::Print("||")
             )?,
 
             Token::Ampersand => queue!(
                 engine.writer,
-                style::SetForegroundColor(Colors::NYI),
+                style::SetForegroundColor(Colors::AMPERSAND),
                 style::Print("&")
             )?,
+
+            Token::Keyword(keyword) => queue!(
+                engine.writer,
+                style::SetForegroundColor(Colors::KEYWORD),
+                style::Print(keyword)
+            )?,
+
+            Token::Comment(comment) => queue!(
+                engine.writer,
+                style::SetForegroundColor(Colors::COMMENT),
+                style::Print(format!("#{comment}"))
+            )?,
+
+            Token::Newline => queue!(engine.writer, style::Print("\n"))?,
         }
 
         if !matches!(&token, &Token::Space) {
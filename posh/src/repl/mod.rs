@@ -6,7 +6,7 @@ use std::process;
 
 use crossterm::{execute, style, terminal};
 use posh_core::path::Expand;
-use posh_core::{Engine, ExitStatus, Result};
+use posh_core::{Engine, Error, ExitStatus, Result};
 
 use crate::config::{self, Colors};
 use crate::repl::input::read_line;
@@ -27,11 +27,29 @@ impl Repl {
     fn read_and_execute(&mut self) -> Result<Vec<ExitStatus>> {
         let line = read_line(&mut self.engine)?;
         self.engine.history.append(&line)?;
-        self.engine.execute_line(line)
+
+        match self.engine.execute_line(&line) {
+            Err(Error::Syntax(e)) => {
+                writeln!(self.engine.writer, "posh: Syntax error: {e}")?;
+                writeln!(self.engine.writer, "{}", e.underline(&line))?;
+                Ok(vec![ExitStatus::from(2)])
+            }
+
+            result => result,
+        }
     }
 
     pub fn run(&mut self) -> Result<()> {
+        self.engine.enable_job_control()?;
+
         loop {
+            if let Err(e) = self.engine.notify_jobs() {
+                writeln!(
+                    self.engine.writer,
+                    "posh: Error occurred when checking on jobs: {e}"
+                )?;
+            }
+
             if let Err(e) = self.prompt() {
                 writeln!(
                     self.engine.writer,
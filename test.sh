@@ -24,7 +24,7 @@ run-tests() {
         'echo foo; printf bar'
 
     expect $'foo\nbar' \
-        'echo -e foo\nbar'
+        'echo -e "foo\nbar"'
 
     expect $'foo\nbar' \
         'echo oof | rev; printf bar'
@@ -34,11 +34,59 @@ run-tests() {
     expect_file abc123 \
         'foo'
 
+    expect y \
+        'yes | head -1'
+
+    expect 100000 \
+        'seq 100000 | cat | tail -1'
+
     expect foo \
         'echo oof | rev >file; cat <file'
     expect_file file \
         'foo'
 
+    expect foo \
+        'true && echo foo || echo bar'
+
+    expect bar \
+        'false && echo foo || echo bar'
+
+    expect $'foo\nbar' \
+        'echo foo || echo baz; false || echo bar && false && echo baz'
+
+    expect $'foo\nbar' \
+        'sleep 0.1 && echo bar & echo foo; wait'
+
+    expect 3 \
+        'sh -c "exit 3" & wait %1 || echo 3'
+
+    expect 'foo bar' \
+        'echo $(echo foo; echo bar)'
+
+    expect $'foo\nbar' \
+        'echo "$(echo foo; echo bar)"'
+
+    expect oof \
+        'echo $(echo $(echo foo) | rev)'
+
+    expect 'abc123 file' \
+        'echo *'
+
+    expect 'abc123' \
+        'echo ?b[a-c]*'
+
+    expect '' \
+        'echo *.nope'
+
+    expect 'a.txt b.txt' \
+        'echo {a,b}.txt'
+
+    expect 'x1y x3y x5y' \
+        'echo x{1..5..2}y'
+
+    expect 'ac ad b' \
+        'echo {a{c,d},b}'
+
     expect "$HOME" \
         'echo $HOME'
 
@@ -53,6 +101,146 @@ run-tests() {
 
     expect a/b \
         'foo=a bar=b echo "$foo/$bar"'
+
+    expect bar \
+        'foo=bar; echo $foo; env | grep ^foo='
+
+    expect $'bar\nfoo=bar' \
+        'export foo=bar; echo $foo; env | grep ^foo='
+
+    expect $'0\nfoo=bar' \
+        'export foo; env | grep -c ^foo=; foo=bar; env | grep ^foo='
+
+    expect $'foo=a  b\nbar=c  d' \
+        $'export foo="a  b" bar; bar=\'c  d\'; env | grep -e ^foo= -e ^bar= | sort -r'
+
+    expect '[]' \
+        'foo=bar; unset foo; echo "[$foo]"'
+
+    expect $'1\n0' \
+        'false; echo $?; echo $?'
+
+    expect '2 b c' \
+        'set -- a "b c"; echo $# $2'
+
+    expect '[a][b c]' \
+        'set -- a "b c"; printf "[%s]" "$@"'
+
+    expect 'file.tar gz' \
+        'x=file.tar.gz; echo ${x%.*} ${x##*.}'
+
+    expect 'fallback 0' \
+        'echo ${nope:-fallback} ${#nope}'
+
+    expect 'a  b|b|' \
+        'x="a b"; printf "%s|" "${nope-"a  b"}" "${x#"a "}"'
+
+    expect '7 3' \
+        'x=3; echo $((1 + 2 * x)) "$(( (x > 2) ? x : 0 ))"'
+
+    expect '2.5' \
+        'math 5 / 2'
+
+    expect 'b' \
+        'if false; then echo a; elif true; then echo b; else echo c; fi'
+
+    expect '12' \
+        'i=0; while true; do i=$((i + 1)); [ $i -gt 2 ] && break; x=$x$i; done; echo $x'
+
+    expect 'a-b-c-' \
+        'for x in a $(echo b) "c"; do printf "%s-" $x; done; echo'
+
+    expect 'two' \
+        'case 2 in 1) echo one;; 2|3) echo two;; *) echo many;; esac'
+
+    expect 'hi posh 2' \
+        'x=posh; greet() { local x=$1; echo hi $x $#; }; greet posh 2; [ $x = posh ] || echo leaked'
+
+    expect '/ 1' \
+        'x=1; (cd /; x=2; printf "%s " "$(pwd)"); echo $x'
+
+    expect 'B' \
+        '{ echo a; echo b; } | tail -1 | tr a-z A-Z'
+
+    expect '' \
+        '{ echo a; ls /nonexistent; } 2>/dev/null >group'
+    expect_file group \
+        'a'
+
+    expect $'hello posh\n$x' \
+        $'x=posh; cat <<EOF; cat <<\'EOF\'\nhello $x\nEOF\n$x\nEOF'
+
+    expect 'A B' \
+        'x=b; tr a-z A-Z <<<"a $x"'
+
+    expect '200001' \
+        'x=$(head -c 200000 /dev/zero | tr -c a a); cat <<<"$x" | { wc -c; }'
+
+    expect '1' \
+        'ls /nonexistent 2>&1 | grep -c nonexistent'
+
+    expect '' \
+        'ls . /nonexistent &>both 3>fd3; echo three 3>>fd3 >&3'
+    expect_file fd3 \
+        'three'
+
+    expect 'not closed' \
+        'echo closed >&- || echo not closed'
+
+    expect $'1c1\n< a\n---\n> b' \
+        'diff <(echo a) <(echo b)'
+
+    expect 'POSH' \
+        'echo posh > >(tr a-z A-Z)'
+
+    expect 'a|b|c|' \
+        'files=a\ b; printf "%s|" $files $(echo c)'
+
+    expect 'a|b|a b|' \
+        'files="a b"; printf "%s|" $files "$files"'
+
+    expect 'c|d|c d|' \
+        "files='c d'; printf '%s|' \$files \"\$files\""
+
+    expect 'ab cd|' \
+        'printf "%s|" a"b c"d'
+
+    expect 'a|b c|' \
+        $'IFS=$\'\\n\'; x=a$\'\\n\'"b c"; printf "%s|" $x'
+
+    expect 'a#b' \
+        'echo a#b # c'
+
+    expect $'1\n2' \
+        $'for x in 1 2; do\n\t# a\n\techo\t$x\t# b\ndone'
+
+    expect 'foo bar' \
+        $'echo foo \\\n  b\\\nar'
+
+    expect 'foo bar' \
+        'printf "%s" foo\ bar'
+
+    expect $'./test.sh\nfound' \
+        'cp "$OLDPWD/test.sh" .; find . -name test.sh -exec echo {} \; -exec echo found \;'
+
+    expect '$HOME "q" \z' \
+        'echo \$HOME "\"q\" \z"'
+
+    expect $'a\tb\nc' \
+        "echo \$'a\\tb\\nc'"
+
+    expect '' \
+        'echo longer >clobber; echo new >clobber'
+    expect_file clobber \
+        'new'
+
+    expect 'new' \
+        'set -o noclobber; echo over >clobber; cat clobber'
+
+    expect '' \
+        'set -o noclobber; echo forced >|clobber'
+    expect_file clobber \
+        'forced'
 }
 
 run() {
@@ -90,7 +278,7 @@ expect_file() {
 expect() {
     echo
     printf '$ %s\n' "$2"
-    res="$(run $2)"
+    res="$(run "$2")"
 
     if [ "$1" = "$res" ]; then
         success "$res"